@@ -0,0 +1,72 @@
+use crate::film::LUMINANCE;
+use glam::Vec3;
+use serde_derive::{Deserialize, Serialize};
+
+/// Settings for spending more samples on noisy pixels. Every pixel takes at least
+/// `Params::samples` and at most `max_samples`, stopping in between once its estimated error
+/// falls to `noise_threshold`.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct AdaptiveSampling {
+    /// The relative standard error of a pixel's luminance below which sampling stops.
+    pub noise_threshold: f32,
+    pub max_samples: u32,
+}
+
+/// Luminance below which errors are measured absolutely rather than relative to the pixel's
+/// brightness, so dark pixels aren't sampled indefinitely.
+const MIN_LUMINANCE: f32 = 0.01;
+
+/// Running mean and variance of a pixel's luminance, updated one sample at a time with
+/// Welford's algorithm.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PixelVariance {
+    count: u32,
+    mean: f32,
+    m2: f32,
+}
+
+impl PixelVariance {
+    #[inline]
+    pub fn add(&mut self, rgb: Vec3) {
+        let luminance = rgb.dot(LUMINANCE);
+        self.count += 1;
+        let delta = luminance - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (luminance - self.mean);
+    }
+
+    #[inline]
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// The unbiased sample variance of the luminance.
+    #[inline]
+    pub fn variance(&self) -> f32 {
+        if self.count > 1 {
+            self.m2 / (self.count - 1) as f32
+        } else {
+            0.0
+        }
+    }
+
+    /// The standard error of the mean luminance relative to the mean itself.
+    #[inline]
+    pub fn relative_error(&self) -> f32 {
+        if self.count == 0 {
+            return f32::INFINITY;
+        }
+        let standard_error = (self.variance() / self.count as f32).sqrt();
+        standard_error / self.mean.max(MIN_LUMINANCE)
+    }
+}
+
+impl AdaptiveSampling {
+    /// Returns true if a pixel with `variance` and at least `min_samples` needs no more.
+    #[inline]
+    pub fn is_converged(&self, variance: &PixelVariance, min_samples: u32) -> bool {
+        let count = variance.count();
+        count >= self.max_samples
+            || (count >= min_samples && variance.relative_error() <= self.noise_threshold)
+    }
+}
@@ -0,0 +1,365 @@
+use crate::{
+    collision::{Hitable, Ray, RayHit},
+    material::Material,
+    math::linear_to_srgb,
+};
+use glam::{const_vec3, Vec3};
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr};
+
+/// An auxiliary output, describing the first surface each camera ray hits rather than the
+/// light arriving along it. Pixels where the camera ray missed are zero.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Aov {
+    /// The reflectance of the first hit, see `Material::albedo`.
+    Albedo,
+    /// The world space shading normal of the first hit.
+    Normal,
+    /// The distance from the camera to the first hit.
+    Depth,
+    /// The world space position of the first hit.
+    Position,
+    /// A number identifying the material of the first hit, starting at 1.
+    MaterialId,
+    /// A number identifying the primitive of the first hit, starting at 1.
+    PrimitiveId,
+    /// The number of samples taken for the pixel.
+    Samples,
+}
+
+impl Aov {
+    pub const NAMES: [&'static str; 7] = [
+        "albedo",
+        "normal",
+        "depth",
+        "position",
+        "material_id",
+        "primitive_id",
+        "samples",
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::MaterialId => "material_id",
+            Aov::PrimitiveId => "primitive_id",
+            Aov::Samples => "samples",
+        }
+    }
+
+    /// The names of the channels making up the output, used for EXR layers.
+    pub fn channel_names(self) -> &'static [&'static str] {
+        match self {
+            Aov::Albedo => &["R", "G", "B"],
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::MaterialId | Aov::PrimitiveId => &["id"],
+            Aov::Samples => &["count"],
+        }
+    }
+
+    /// Returns the output's value for `pixel`, outputs with a single channel are repeated in
+    /// all three.
+    pub fn value(self, pixel: &AovPixel) -> (f32, f32, f32) {
+        let splat = |value: f32| (value, value, value);
+        match self {
+            Aov::Albedo => pixel.albedo.into(),
+            Aov::Normal => pixel.normal.into(),
+            Aov::Depth => splat(pixel.depth),
+            Aov::Position => pixel.position.into(),
+            Aov::MaterialId => splat(pixel.material_id as f32),
+            Aov::PrimitiveId => splat(pixel.primitive_id as f32),
+            Aov::Samples => splat(pixel.sample_count as f32),
+        }
+    }
+
+    /// Converts the output to 8-bit values that are easy to inspect by eye. Albedo is sRGB
+    /// encoded, normals are mapped from `[-1, 1]`, depth and position are scaled to the range
+    /// of the whole image, sample counts are shown as a heatmap from the fewest in blue to the
+    /// most in red and ids are given random colours.
+    pub fn to_display(self, pixels: &[AovPixel]) -> Vec<(u8, u8, u8)> {
+        let unorm = |x: f32| (x.clamp(0.0, 1.0) * 255.99) as u8;
+        let unorm3 = |v: Vec3| (unorm(v.x), unorm(v.y), unorm(v.z));
+        match self {
+            Aov::Albedo => pixels
+                .iter()
+                .map(|pixel| linear_to_srgb(pixel.albedo.into()))
+                .collect(),
+            Aov::Normal => pixels
+                .iter()
+                .map(|pixel| unorm3(pixel.normal * 0.5 + Vec3::splat(0.5)))
+                .collect(),
+            Aov::Samples => {
+                let (min, max) = pixels.iter().fold((u32::MAX, 0), |acc, pixel| {
+                    (acc.0.min(pixel.sample_count), acc.1.max(pixel.sample_count))
+                });
+                let scale = if max > min {
+                    1.0 / (max - min) as f32
+                } else {
+                    0.0
+                };
+                pixels
+                    .iter()
+                    .map(|pixel| unorm3(heatmap((pixel.sample_count - min) as f32 * scale)))
+                    .collect()
+            }
+            Aov::Depth => {
+                let max = pixels
+                    .iter()
+                    .map(|pixel| self.value(pixel).0)
+                    .fold(0.0, f32::max);
+                let scale = if max > 0.0 { 1.0 / max } else { 0.0 };
+                pixels
+                    .iter()
+                    .map(|pixel| {
+                        let value = unorm(self.value(pixel).0 * scale);
+                        (value, value, value)
+                    })
+                    .collect()
+            }
+            Aov::Position => {
+                let (min, max) = pixels.iter().filter(|pixel| pixel.depth > 0.0).fold(
+                    (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+                    |acc, pixel| (acc.0.min(pixel.position), acc.1.max(pixel.position)),
+                );
+                let scale = (max - min).max(Vec3::splat(f32::EPSILON)).recip();
+                pixels
+                    .iter()
+                    .map(|pixel| {
+                        if pixel.depth > 0.0 {
+                            unorm3((pixel.position - min) * scale)
+                        } else {
+                            (0, 0, 0)
+                        }
+                    })
+                    .collect()
+            }
+            Aov::MaterialId | Aov::PrimitiveId => pixels
+                .iter()
+                .map(|pixel| id_colour(self.value(pixel).0 as u32))
+                .collect(),
+        }
+    }
+}
+
+impl FromStr for Aov {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "albedo" => Ok(Aov::Albedo),
+            "normal" => Ok(Aov::Normal),
+            "depth" => Ok(Aov::Depth),
+            "position" => Ok(Aov::Position),
+            "material_id" => Ok(Aov::MaterialId),
+            "primitive_id" => Ok(Aov::PrimitiveId),
+            "samples" => Ok(Aov::Samples),
+            _ => Err(format!("unrecognised output '{}'", s)),
+        }
+    }
+}
+
+/// Maps `t` in `[0, 1]` through blue, cyan, green, yellow and red.
+fn heatmap(t: f32) -> Vec3 {
+    const COLOURS: [Vec3; 5] = [
+        const_vec3!([0.0, 0.0, 1.0]),
+        const_vec3!([0.0, 1.0, 1.0]),
+        const_vec3!([0.0, 1.0, 0.0]),
+        const_vec3!([1.0, 1.0, 0.0]),
+        const_vec3!([1.0, 0.0, 0.0]),
+    ];
+    let x = t.clamp(0.0, 1.0) * (COLOURS.len() - 1) as f32;
+    let index = (x as usize).min(COLOURS.len() - 2);
+    COLOURS[index].lerp(COLOURS[index + 1], x - index as f32)
+}
+
+/// Returns a colour for `id` that is distinct from its neighbours, black for no id.
+fn id_colour(id: u32) -> (u8, u8, u8) {
+    if id == 0 {
+        return (0, 0, 0);
+    }
+    // scramble the bits so consecutive ids get unrelated colours
+    let mut hash = id.wrapping_mul(0x9e37_79b9);
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    (hash as u8, (hash >> 8) as u8, (hash >> 16) as u8)
+}
+
+/// The auxiliary outputs for a single pixel. Values that can be filtered are averaged over the
+/// pixel's samples, ids are taken from the first sample that hit something.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AovPixel {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub position: Vec3,
+    pub depth: f32,
+    pub material_id: u32,
+    pub primitive_id: u32,
+    pub sample_count: u32,
+}
+
+impl AovPixel {
+    /// Accumulates the first hit of a camera ray. Ids are only set by the first sample that
+    /// hits something.
+    pub fn add_hit(
+        &mut self,
+        ids: &SceneIds,
+        ray: &Ray,
+        ray_hit: &RayHit,
+        material: &Material,
+        primitive: &Hitable,
+    ) {
+        self.albedo += material.albedo(ray_hit);
+        self.normal += ray_hit.normal;
+        self.position += ray_hit.point;
+        self.depth += ray_hit.t * ray.direction.length();
+        if self.primitive_id == 0 {
+            self.material_id = ids.material(material);
+            self.primitive_id = ids.primitive(primitive);
+        }
+    }
+
+    /// Divides the values accumulated by `add_hit` by the number of samples taken.
+    pub fn average(&mut self, samples: u32) {
+        let inv_ns = 1.0 / samples.max(1) as f32;
+        self.albedo *= inv_ns;
+        self.normal *= inv_ns;
+        self.position *= inv_ns;
+        self.depth *= inv_ns;
+        self.sample_count = samples;
+    }
+
+    /// Mixes in the averaged outputs of a later frame the same way as the colour buffer.
+    /// Sample counts add up and ids keep their first value.
+    pub fn blend(&mut self, new: &AovPixel, mix_prev: f32) {
+        let mix_new = 1.0 - mix_prev;
+        self.albedo = self.albedo * mix_prev + new.albedo * mix_new;
+        self.normal = self.normal * mix_prev + new.normal * mix_new;
+        self.position = self.position * mix_prev + new.position * mix_new;
+        self.depth = self.depth * mix_prev + new.depth * mix_new;
+        if self.primitive_id == 0 {
+            self.material_id = new.material_id;
+            self.primitive_id = new.primitive_id;
+        }
+        self.sample_count += new.sample_count;
+    }
+}
+
+/// Auxiliary outputs for a whole image along with the ids of the scene being rendered.
+pub struct AovBuffer {
+    pub ids: SceneIds,
+    pub pixels: Vec<AovPixel>,
+}
+
+impl AovBuffer {
+    pub fn new(world: &Hitable, width: u32, height: u32) -> AovBuffer {
+        AovBuffer {
+            ids: SceneIds::new(world),
+            pixels: vec![AovPixel::default(); (width * height) as usize],
+        }
+    }
+}
+
+/// Numbers the materials and primitives of a scene in the order they are found walking its
+/// hitables.
+#[derive(Debug, Default)]
+pub struct SceneIds {
+    materials: HashMap<usize, u32>,
+    primitives: HashMap<(usize, u32), u32>,
+}
+
+impl SceneIds {
+    pub fn new(world: &Hitable) -> SceneIds {
+        let mut ids = SceneIds::default();
+        ids.collect(world, true);
+        ids
+    }
+
+    fn add_material(&mut self, material: &Material) {
+        let next_id = self.materials.len() as u32 + 1;
+        self.materials
+            .entry(material as *const Material as usize)
+            .or_insert(next_id);
+    }
+
+    /// Adds the materials of `hitable` and its descendants, and the primitives if `primitives`
+    /// is set. Everything inside an instance or medium belongs to a single primitive.
+    fn collect(&mut self, hitable: &Hitable, primitives: bool) {
+        match *hitable {
+            Hitable::BVHNode(node) => {
+                let (lhs, rhs) = node.children();
+                self.collect(&lhs, primitives);
+                self.collect(&rhs, primitives);
+                return;
+            }
+            Hitable::LinearBVH(bvh) => {
+                for hitable in bvh.primitives() {
+                    self.collect(hitable, primitives);
+                }
+                return;
+            }
+            Hitable::WideBVH(bvh) => {
+                for hitable in bvh.primitives() {
+                    self.collect(hitable, primitives);
+                }
+                return;
+            }
+            Hitable::Tlas(tlas) => {
+                for hitable in tlas.hitables() {
+                    self.collect(&hitable, primitives);
+                }
+                return;
+            }
+            Hitable::List(list) => {
+                for hitable in list.hitables() {
+                    self.collect(hitable, primitives);
+                }
+                return;
+            }
+            Hitable::Spheres(spheres) => {
+                for hitable in spheres.hitables() {
+                    self.collect(hitable, primitives);
+                }
+                return;
+            }
+            Hitable::Instance(instance) => self.collect(&instance.hitable(), false),
+            Hitable::ConstantMedium(medium) => self.add_material(medium.phase_function()),
+            Hitable::TriangleMesh(mesh) | Hitable::MeshFace(mesh, _) => {
+                for material in mesh.materials() {
+                    self.add_material(material);
+                }
+            }
+            Hitable::Rect(_, material)
+            | Hitable::Cuboid(_, material)
+            | Hitable::MovingSphere(_, material)
+            | Hitable::Sphere(_, material)
+            | Hitable::Triangle(_, material) => self.add_material(material),
+        }
+        if primitives {
+            let next_id = self.primitives.len() as u32 + 1;
+            self.primitives.entry(hitable.address()).or_insert(next_id);
+        }
+    }
+
+    /// Returns the id of `material`, or 0 if it isn't part of the scene.
+    #[inline]
+    pub fn material(&self, material: &Material) -> u32 {
+        self.materials
+            .get(&(material as *const Material as usize))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Returns the id of a primitive returned by `Hitable::ray_pick`, or 0 if it isn't part of
+    /// the scene.
+    #[inline]
+    pub fn primitive(&self, primitive: &Hitable) -> u32 {
+        self.primitives
+            .get(&primitive.address())
+            .copied()
+            .unwrap_or(0)
+    }
+}
@@ -1,6 +1,14 @@
+use crate::{
+    collision::BVHBuilder,
+    film::{DisplayTransform, ToneMap},
+    params::{Accel, Params},
+    sampler::SamplerKind,
+    scene::Integrator,
+    tiles::TileOrder,
+};
+#[cfg(feature = "bench")]
 use crate::{
     collision::{Hitable, Ray},
-    params::Params,
     presets,
     storage::Storage,
 };
@@ -9,11 +17,23 @@ pub const PARAMS: Params = Params {
     width: 200,
     height: 100,
     samples: 10,
-    max_depth: 10,
-    random_seed: false,
-    use_bvh: false,
+    adaptive: None,
+    min_depth: 10,
+    seed: 0,
+    accel: Accel::List,
+    bvh_builder: BVHBuilder::Sah,
+    integrator: Integrator::Naive,
+    sampler: SamplerKind::Independent,
+    tile_size: 32,
+    tile_order: TileOrder::Scanline,
+    crop: None,
+    display: DisplayTransform {
+        exposure: 0.0,
+        tone_map: ToneMap::Clamp,
+    },
 };
 
+#[cfg(feature = "bench")]
 pub fn hitables_bench<F>(f: F)
 where
     F: FnOnce(&Ray, Vec<Hitable>),
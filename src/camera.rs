@@ -1,9 +1,24 @@
-use crate::{collision::Ray, math::random_in_unit_disk};
+use crate::{collision::Ray, math::sample_unit_disk, sampler::Sampler};
 use glam::Vec3;
-use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
 use std::f32;
 
-// #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+/// The parameters a `Camera` is constructed from, minus the aspect ratio which comes from the
+/// output image dimensions.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct CameraDesc {
+    pub lookfrom: Vec3,
+    pub lookat: Vec3,
+    pub vup: Vec3,
+    pub vfov: f32,
+    pub aperture: f32,
+    pub focus_dist: f32,
+    #[serde(default)]
+    pub time0: f32,
+    #[serde(default)]
+    pub time1: f32,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Camera {
     origin: Vec3,
@@ -16,6 +31,7 @@ pub struct Camera {
     time0: f32,
     time1: f32,
     lens_radius: f32,
+    desc: CameraDesc,
 }
 
 impl Camera {
@@ -50,13 +66,48 @@ impl Camera {
             time0,
             time1,
             lens_radius: aperture * 0.5,
+            desc: CameraDesc {
+                lookfrom,
+                lookat,
+                vup,
+                vfov,
+                aperture,
+                focus_dist,
+                time0,
+                time1,
+            },
         }
     }
 
-    pub fn get_ray<T: Rng>(&self, s: f32, t: f32, rng: &mut T) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk(rng);
+    pub fn from_desc(desc: &CameraDesc, aspect: f32) -> Camera {
+        Camera::new(
+            desc.lookfrom,
+            desc.lookat,
+            desc.vup,
+            desc.vfov,
+            aspect,
+            desc.aperture,
+            desc.focus_dist,
+            desc.time0,
+            desc.time1,
+        )
+    }
+
+    /// The interval ray times are sampled from.
+    #[inline]
+    pub fn shutter(&self) -> (f32, f32) {
+        (self.time0, self.time1)
+    }
+
+    #[inline]
+    pub fn desc(&self) -> &CameraDesc {
+        &self.desc
+    }
+
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Ray {
+        let rd = self.lens_radius * sample_unit_disk(sampler.next_2d());
         let offset = self.u * rd.x + self.v * rd.y;
-        let time = self.time0 + rng.gen::<f32>() * (self.time1 - self.time0);
+        let time = self.time0 + sampler.next_1d() * (self.time1 - self.time0);
         Ray::new(
             self.origin + offset,
             (self.lower_left_corner + s * self.horizontal + t * self.vertical
@@ -0,0 +1,219 @@
+use crate::{
+    adaptive::PixelVariance,
+    aov::AovPixel,
+    offline::{OutputOptions, StopConditions},
+    params::Params,
+    source::SceneSource,
+    tiles::Tile,
+};
+use glam::Vec3;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    error, fmt,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Identifies checkpoint files, the last byte is the format version.
+const MAGIC: [u8; 8] = *b"PTCKPT\0\x03";
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Encoding {
+        path: PathBuf,
+        source: bincode::Error,
+    },
+    /// The file doesn't start with `MAGIC`, it isn't a checkpoint or was written by a different
+    /// version.
+    UnrecognisedFormat {
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckpointError::Io { path, source } => {
+                write!(f, "failed to access '{}': {}", path.display(), source)
+            }
+            CheckpointError::Encoding { path, source } => {
+                write!(f, "{}: {}", path.display(), source)
+            }
+            CheckpointError::UnrecognisedFormat { path } => write!(
+                f,
+                "{}: not a checkpoint or written by an incompatible version",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl error::Error for CheckpointError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CheckpointError::Io { source, .. } => Some(source),
+            CheckpointError::Encoding { source, .. } => Some(source.as_ref()),
+            CheckpointError::UnrecognisedFormat { .. } => None,
+        }
+    }
+}
+
+/// The state of an offline render, written periodically so an interrupted render can be
+/// resumed. Tiles are only recorded once they are finished, resuming renders the rest of the
+/// current pass and carries on until `stop` is met.
+///
+/// The scene is reloaded from `source` when resuming so it must not have changed, the render
+/// is only the same as an uninterrupted one if it is.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub source: SceneSource,
+    pub params: Params,
+    pub output: OutputOptions,
+    pub stop: StopConditions,
+    /// The number of finished passes over the whole image.
+    pub passes: u32,
+    /// The tiles of `params.tiles()` that have been rendered in the current pass.
+    pub finished_tiles: Vec<Tile>,
+    /// Radiance averaged over the passes each pixel has been rendered in, black before the
+    /// first.
+    pub rgb: Vec<(f32, f32, f32)>,
+    /// Samples taken for each pixel over all passes.
+    pub sample_counts: Vec<u32>,
+    /// The variation of each pixel's luminance between passes.
+    pub variance: Vec<PixelVariance>,
+    /// Auxiliary outputs of the finished tiles, gathered if any are written or the render is
+    /// denoised.
+    pub aov_pixels: Option<Vec<AovPixel>>,
+    /// Time spent rendering the finished tiles, summed over every run that resumed the render.
+    pub elapsed_secs: f64,
+}
+
+impl Checkpoint {
+    /// Starts a render with no finished tiles.
+    pub fn new(
+        source: SceneSource,
+        params: Params,
+        output: OutputOptions,
+        stop: StopConditions,
+    ) -> Checkpoint {
+        let num_pixels = (params.width * params.height) as usize;
+        let aov_pixels = if output.aovs.is_empty() && !output.denoise {
+            None
+        } else {
+            Some(vec![AovPixel::default(); num_pixels])
+        };
+        Checkpoint {
+            source,
+            params,
+            output,
+            stop,
+            passes: 0,
+            finished_tiles: Vec::new(),
+            rgb: vec![(0.0, 0.0, 0.0); num_pixels],
+            sample_counts: vec![0; num_pixels],
+            variance: vec![PixelVariance::default(); num_pixels],
+            aov_pixels,
+            elapsed_secs: 0.0,
+        }
+    }
+
+    /// The tiles still to be rendered in the current pass, in the order they are started.
+    pub fn unfinished_tiles(&self) -> Vec<Tile> {
+        self.params
+            .tiles()
+            .into_iter()
+            .filter(|tile| !self.finished_tiles.contains(tile))
+            .collect()
+    }
+
+    /// Records a finished tile, with its pixels, sample counts and auxiliary outputs stored row
+    /// by row as reported by `TileEvent::Finished`. The pixels are blended with the previous
+    /// passes.
+    pub fn add_tile(
+        &mut self,
+        tile: Tile,
+        pixels: &[(f32, f32, f32)],
+        sample_counts: &[u32],
+        aovs: Option<&[AovPixel]>,
+    ) {
+        let passes = self.passes as f32;
+        for (n, i) in tile.pixel_indices(self.params.width).enumerate() {
+            // recover this pass's value from the running average to measure its variation
+            let pass = Vec3::from(pixels[n]) * (passes + 1.0) - Vec3::from(self.rgb[i]) * passes;
+            self.variance[i].add(pass);
+            self.rgb[i] = pixels[n];
+            self.sample_counts[i] += sample_counts[n];
+            if let (Some(aov_pixels), Some(aovs)) = (&mut self.aov_pixels, aovs) {
+                aov_pixels[i] = aovs[n];
+            }
+        }
+        self.finished_tiles.push(tile);
+    }
+
+    /// Starts the next pass, all the tiles of the current one must be finished.
+    pub fn finish_pass(&mut self) {
+        debug_assert!(self.unfinished_tiles().is_empty());
+        self.passes += 1;
+        self.finished_tiles.clear();
+    }
+
+    /// The mean over the rendered pixels of the standard error of their luminance relative to the
+    /// luminance itself, estimated from the variation between passes. This is only meaningful
+    /// after at least two passes.
+    pub fn noise(&self) -> f32 {
+        let region = self.params.region();
+        let total: f64 = region
+            .pixel_indices(self.params.width)
+            .map(|i| f64::from(self.variance[i].relative_error()))
+            .sum();
+        (total / (region.width * region.height) as f64) as f32
+    }
+
+    pub fn load(path: &Path) -> Result<Checkpoint, CheckpointError> {
+        let io_error = |source| CheckpointError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+        let mut reader = BufReader::new(File::open(path).map_err(io_error)?);
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic).map_err(io_error)?;
+        if magic != MAGIC {
+            return Err(CheckpointError::UnrecognisedFormat {
+                path: path.to_path_buf(),
+            });
+        }
+        bincode::deserialize_from(reader).map_err(|source| CheckpointError::Encoding {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Writes the checkpoint next to `path` and then renames it, so the previous checkpoint
+    /// survives if writing is interrupted.
+    pub fn save(&self, path: &Path) -> Result<(), CheckpointError> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let io_error = |source| CheckpointError::Io {
+            path: temp_path.clone(),
+            source,
+        };
+        let mut writer = BufWriter::new(File::create(&temp_path).map_err(io_error)?);
+        writer.write_all(&MAGIC).map_err(io_error)?;
+        bincode::serialize_into(&mut writer, self).map_err(|source| CheckpointError::Encoding {
+            path: temp_path.clone(),
+            source,
+        })?;
+        writer.flush().map_err(io_error)?;
+        drop(writer);
+        fs::rename(&temp_path, path).map_err(|source| CheckpointError::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
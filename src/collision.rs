@@ -5,21 +5,31 @@ mod cuboid;
 mod hitable;
 mod hitable_list;
 mod instance;
+mod linear_bvh;
 mod moving_sphere;
 mod ray;
+mod ray_packet;
 mod rect;
 mod sphere;
 mod spheres_soa;
+mod tlas;
+mod triangle;
+mod wide_bvh;
 
 pub use aabb::AABB;
-pub use bvh::BVHNode;
+pub use bvh::{BVHBuilder, BVHNode};
 pub use constant_medium::ConstantMedium;
 pub use cuboid::Cuboid;
 pub use hitable::Hitable;
 pub use hitable_list::HitableList;
 pub use instance::Instance;
+pub use linear_bvh::LinearBVH;
 pub use moving_sphere::MovingSphere;
 pub use ray::{Ray, RayHit};
+pub use ray_packet::{lanes, PacketHits, RayPacket, MAX_PACKET_SIZE};
 pub use rect::Rect;
 pub use sphere::Sphere;
 pub use spheres_soa::SpheresSoA;
+pub use tlas::{Tlas, TlasUpdate};
+pub use triangle::{Triangle, TriangleMesh};
+pub use wide_bvh::WideBVH;
@@ -1,5 +1,5 @@
 #![allow(dead_code)]
-use crate::collision::Ray;
+use crate::collision::{Ray, RayPacket, MAX_PACKET_SIZE};
 use glam::{Affine3A, Vec3, Vec3A};
 use std::f32;
 
@@ -50,11 +50,105 @@ impl AABB {
         let rcp_direction = Vec3A::from(r.rcp_direction);
         let min_delta = (min - origin) * rcp_direction;
         let max_delta = (max - origin) * rcp_direction;
-        let t0 = min_delta.min(max_delta);
-        let t1 = min_delta.max(max_delta);
-        let tmin = t0.max(Vec3A::splat(tmin));
-        let tmax = t1.min(Vec3A::splat(tmax));
-        tmax.cmpgt(tmin).all()
+        // the ray is inside the box between entering the last slab and leaving the first
+        let t0 = min_delta.min(max_delta).max_element().max(tmin);
+        let t1 = min_delta.max(max_delta).min_element().min(tmax);
+        t1 > t0
+    }
+
+    /// Tests the rays of `packet` in `mask`, each up to its own `t_max`, and returns the mask
+    /// of those that hit. Agrees with `ray_hit` for every ray.
+    #[inline]
+    pub fn ray_hit_packet(
+        &self,
+        packet: &RayPacket,
+        t_min: f32,
+        t_max: &[f32; MAX_PACKET_SIZE],
+        mask: u32,
+    ) -> u32 {
+        // packets are only as wide as the CPU supports
+        let hit = match packet.size() {
+            MAX_PACKET_SIZE => unsafe { self.ray_hit_packet_avx2(packet, t_min, t_max) },
+            _ => unsafe { self.ray_hit_packet_sse4_1(packet, t_min, t_max) },
+        };
+        hit & mask
+    }
+
+    #[cfg_attr(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature(enable = "sse4.1")
+    )]
+    unsafe fn ray_hit_packet_sse4_1(
+        &self,
+        packet: &RayPacket,
+        t_min: f32,
+        t_max: &[f32; MAX_PACKET_SIZE],
+    ) -> u32 {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::*;
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::*;
+        let mut t0 = _mm_set1_ps(t_min);
+        let mut t1 = _mm_loadu_ps(t_max.as_ptr());
+        for axis in 0..3 {
+            let origin = _mm_loadu_ps(packet.origin[axis].as_ptr());
+            let rcp_direction = _mm_loadu_ps(packet.rcp_direction[axis].as_ptr());
+            let min_delta = _mm_mul_ps(
+                _mm_sub_ps(_mm_set1_ps(self.min[axis]), origin),
+                rcp_direction,
+            );
+            let max_delta = _mm_mul_ps(
+                _mm_sub_ps(_mm_set1_ps(self.max[axis]), origin),
+                rcp_direction,
+            );
+            t0 = _mm_max_ps(_mm_min_ps(min_delta, max_delta), t0);
+            t1 = _mm_min_ps(_mm_max_ps(min_delta, max_delta), t1);
+        }
+        _mm_movemask_ps(_mm_cmpgt_ps(t1, t0)) as u32
+    }
+
+    #[cfg_attr(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature(enable = "avx2")
+    )]
+    unsafe fn ray_hit_packet_avx2(
+        &self,
+        packet: &RayPacket,
+        t_min: f32,
+        t_max: &[f32; MAX_PACKET_SIZE],
+    ) -> u32 {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::*;
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::*;
+        let mut t0 = _mm256_set1_ps(t_min);
+        let mut t1 = _mm256_loadu_ps(t_max.as_ptr());
+        for axis in 0..3 {
+            let origin = _mm256_loadu_ps(packet.origin[axis].as_ptr());
+            let rcp_direction = _mm256_loadu_ps(packet.rcp_direction[axis].as_ptr());
+            let min_delta = _mm256_mul_ps(
+                _mm256_sub_ps(_mm256_set1_ps(self.min[axis]), origin),
+                rcp_direction,
+            );
+            let max_delta = _mm256_mul_ps(
+                _mm256_sub_ps(_mm256_set1_ps(self.max[axis]), origin),
+                rcp_direction,
+            );
+            t0 = _mm256_max_ps(_mm256_min_ps(min_delta, max_delta), t0);
+            t1 = _mm256_min_ps(_mm256_max_ps(min_delta, max_delta), t1);
+        }
+        _mm256_movemask_ps(_mm256_cmp_ps(t1, t0, _CMP_GT_OQ)) as u32
+    }
+
+    #[inline]
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    #[inline]
+    pub fn surface_area(&self) -> f32 {
+        let extent = self.max - self.min;
+        2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
     }
 
     #[inline]
@@ -73,25 +167,24 @@ impl AABB {
 
     #[inline]
     pub fn transform(&self, m: &Affine3A) -> Self {
-        let min = m.w_axis;
-        let max = min;
-
-        let mut min_out = min;
-        let mut max_out = max;
-
-        let x_axis = Vec3A::from(m.x_axis);
-        let x_mask = x_axis.cmpgt(Vec3A::ZERO);
-        let y_axis = Vec3A::from(m.y_axis);
-        let y_mask = y_axis.cmpgt(Vec3A::ZERO);
-        let z_axis = Vec3A::from(m.z_axis);
-        let z_mask = z_axis.cmpgt(Vec3A::ZERO);
-
-        min_out += x_axis * Vec3A::select(x_mask, min, max);
-        max_out += x_axis * Vec3A::select(x_mask, max, min);
-        min_out += y_axis * Vec3A::select(y_mask, min, max);
-        max_out += y_axis * Vec3A::select(y_mask, max, min);
-        min_out += z_axis * Vec3A::select(z_mask, min, max);
-        max_out += z_axis * Vec3A::select(z_mask, max, min);
+        // Arvo's method, each axis of the transform contributes whichever of the min or max
+        // extent produces the smallest (or largest) value
+        let min = Vec3A::from(self.min);
+        let max = Vec3A::from(self.max);
+
+        let mut min_out = m.translation;
+        let mut max_out = min_out;
+
+        for (axis, axis_min, axis_max) in [
+            (m.x_axis, min.x, max.x),
+            (m.y_axis, min.y, max.y),
+            (m.z_axis, min.z, max.z),
+        ] {
+            let a = axis * axis_min;
+            let b = axis * axis_max;
+            min_out += a.min(b);
+            max_out += a.max(b);
+        }
 
         AABB {
             min: Vec3::from(min_out),
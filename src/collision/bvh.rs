@@ -1,23 +1,89 @@
 use crate::{
-    collision::{Hitable, Ray, RayHit, AABB},
+    collision::{Hitable, PacketHits, Ray, RayHit, RayPacket, SpheresSoA, AABB},
     material::Material,
+    sampler::Sampler,
+    storage::Storage,
 };
+use glam::Vec3;
 use rand::Rng;
 use rand_xoshiro::Xoshiro256Plus;
+use serde_derive::{Deserialize, Serialize};
+use std::{slice, str::FromStr};
 use typed_arena::Arena;
 
 const MISS_OR_HIT: [&str; 2] = ["Miss", "Hit"];
 
+// relative costs of visiting a node and intersecting a primitive used by the SAH
+pub(super) const TRAVERSAL_COST: f32 = 0.125;
+pub(super) const INTERSECTION_COST: f32 = 1.0;
+
+// the SAH builder will create leaves with up to this many primitives
+const MAX_LEAF_SIZE: usize = 4;
+const SAH_BINS: usize = 12;
+
+// the last leaf size histogram bucket counts all leaves of this size or larger
+const LEAF_SIZE_BUCKETS: usize = 8;
+
+/// How a BVH picks the split of each node.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BVHBuilder {
+    /// Sort on a random axis and split at the median, one primitive per leaf.
+    Random,
+    /// Split at the centroid median of the longest axis, one primitive per leaf.
+    Median,
+    /// Binned surface area heuristic, leaves may hold several primitives.
+    Sah,
+}
+
+impl BVHBuilder {
+    pub const NAMES: [&'static str; 3] = ["sah", "median", "random"];
+}
+
+impl FromStr for BVHBuilder {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(BVHBuilder::Random),
+            "median" => Ok(BVHBuilder::Median),
+            "sah" => Ok(BVHBuilder::Sah),
+            _ => Err(format!("unrecognised BVH builder '{}'", s)),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Default)]
 pub struct BVHStats {
     num_nodes: u64,
     max_depth: u64,
     num_spheres: u64,
+    // leaves' spheres packed into a SpheresSoA
+    num_sphere_clusters: u64,
     num_moving_spheres: u64,
     num_rects: u64,
     num_boxes: u64,
+    num_triangles: u64,
+    num_meshes: u64,
     num_instances: u64,
     num_constant_mediums: u64,
+    num_leaves: u64,
+    // leaf_sizes[n] counts leaves holding n + 1 primitives
+    leaf_sizes: [u64; LEAF_SIZE_BUCKETS],
+    sah_cost: f32,
+}
+
+/// A primitive being built into a BVH with its bounds, `item` is a hitable or whatever else
+/// the builder's tree references.
+#[derive(Copy, Clone)]
+pub(super) struct BuildPrimitive<T> {
+    pub item: T,
+    pub aabb: AABB,
+    pub centroid: Vec3,
+}
+
+#[derive(Copy, Clone)]
+struct SahBin {
+    aabb: AABB,
+    count: usize,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -33,17 +99,22 @@ impl<'a> BVHNode<'a> {
         self.aabb
     }
 
+    #[inline]
+    pub fn children(&self) -> (Hitable<'a>, Hitable<'a>) {
+        (self.lhs, self.rhs)
+    }
+
     #[inline]
     pub fn ray_hit(
         &self,
         ray: &Ray,
         t_min: f32,
         t_max: f32,
-        rng: &mut Xoshiro256Plus,
+        sampler: &mut dyn Sampler,
     ) -> Option<(RayHit, &Material)> {
         if self.aabb.ray_hit(ray, t_min, t_max) {
-            let hit_lhs = self.lhs.ray_hit(ray, t_min, t_max, rng);
-            let hit_rhs = self.rhs.ray_hit(ray, t_min, t_max, rng);
+            let hit_lhs = self.lhs.ray_hit(ray, t_min, t_max, sampler);
+            let hit_rhs = self.rhs.ray_hit(ray, t_min, t_max, sampler);
             match (hit_lhs, hit_rhs) {
                 (Some(hit_lhs), Some(hit_rhs)) => {
                     if hit_lhs.0.t < hit_rhs.0.t {
@@ -61,13 +132,62 @@ impl<'a> BVHNode<'a> {
         }
     }
 
+    pub fn ray_pick(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(RayHit, &Material<'_>, Hitable<'a>)> {
+        if self.aabb.ray_hit(ray, t_min, t_max) {
+            let hit_lhs = self.lhs.ray_pick(ray, t_min, t_max, sampler);
+            let hit_rhs = self.rhs.ray_pick(ray, t_min, t_max, sampler);
+            match (hit_lhs, hit_rhs) {
+                (Some(hit_lhs), Some(hit_rhs)) => {
+                    if hit_lhs.0.t < hit_rhs.0.t {
+                        Some(hit_lhs)
+                    } else {
+                        Some(hit_rhs)
+                    }
+                }
+                (Some(hit_lhs), None) => Some(hit_lhs),
+                (None, Some(hit_rhs)) => Some(hit_rhs),
+                (None, None) => None,
+            }
+        } else {
+            None
+        }
+    }
+
+    pub fn ray_pick_packet<'s>(
+        &'s self,
+        packet: &RayPacket,
+        t_min: f32,
+        mask: u32,
+        hits: &mut PacketHits<'s, 'a>,
+        samplers: &mut [Box<dyn Sampler>],
+    ) {
+        let mask = self.aabb.ray_hit_packet(packet, t_min, &hits.t_max, mask);
+        if mask != 0 {
+            self.lhs
+                .ray_pick_packet(packet, t_min, mask, hits, samplers);
+            // single hitable nodes store it as both children
+            if !self.lhs.ptr_eq(&self.rhs) {
+                self.rhs
+                    .ray_pick_packet(packet, t_min, mask, hits, samplers);
+            }
+        }
+    }
+
+    /// Builds a BVH over `hitables` whose bounds enclose them over the shutter interval
+    /// `t0` to `t1`.
     pub fn new(
         rng: &mut Xoshiro256Plus,
         hitables: &mut [Hitable<'a>],
+        t0: f32,
+        t1: f32,
         arena: &'a Arena<BVHNode<'a>>,
     ) -> Option<&'a BVHNode<'a>> {
-        let t0 = 0.0;
-        let t1 = 0.0;
         match hitables.len() {
             0 => None,
             1 => {
@@ -93,10 +213,10 @@ impl<'a> BVHNode<'a> {
         }
     }
 
-    pub fn print_ray_hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut Xoshiro256Plus) {
+    pub fn print_ray_hit(&self, ray: &Ray, t_min: f32, t_max: f32, sampler: &mut dyn Sampler) {
         let mut stats = BVHStats::default();
         println!("Starting ray trace {:?}", ray);
-        let ray_hit = self.print_ray_hit_node(0, &mut stats, ray, t_min, t_max, rng);
+        let ray_hit = self.print_ray_hit_node(0, &mut stats, ray, t_min, t_max, sampler);
         println!("Result: {:?}", ray_hit);
         println!("Visit status: {:?}", stats);
     }
@@ -108,7 +228,7 @@ impl<'a> BVHNode<'a> {
         ray: &Ray,
         t_min: f32,
         t_max: f32,
-        rng: &mut Xoshiro256Plus,
+        sampler: &mut dyn Sampler,
     ) -> Option<(RayHit, &Material)> {
         stats.num_nodes += 1;
         let hit = self.aabb.ray_hit(ray, t_min, t_max);
@@ -117,8 +237,10 @@ impl<'a> BVHNode<'a> {
             "", stats.num_nodes, depth, MISS_OR_HIT[hit as usize], self.aabb.min, self.aabb.max
         );
         if hit {
-            let hit_lhs = self.print_ray_hit_child(depth, stats, &self.lhs, ray, t_min, t_max, rng);
-            let hit_rhs = self.print_ray_hit_child(depth, stats, &self.rhs, ray, t_min, t_max, rng);
+            let hit_lhs =
+                self.print_ray_hit_child(depth, stats, &self.lhs, ray, t_min, t_max, sampler);
+            let hit_rhs =
+                self.print_ray_hit_child(depth, stats, &self.rhs, ray, t_min, t_max, sampler);
             match (hit_lhs, hit_rhs) {
                 (Some(hit_lhs), Some(hit_rhs)) => {
                     if hit_lhs.0.t < hit_rhs.0.t {
@@ -144,11 +266,11 @@ impl<'a> BVHNode<'a> {
         ray: &Ray,
         t_min: f32,
         t_max: f32,
-        rng: &mut Xoshiro256Plus,
+        sampler: &mut dyn Sampler,
     ) -> Option<(RayHit, &Material)> {
         match hitable {
             Hitable::BVHNode(node) => {
-                return node.print_ray_hit_node(depth + 1, stats, ray, t_min, t_max, rng);
+                return node.print_ray_hit_node(depth + 1, stats, ray, t_min, t_max, sampler);
             }
             Hitable::MovingSphere(sphere, material) => {
                 stats.num_moving_spheres += 1;
@@ -182,6 +304,18 @@ impl<'a> BVHNode<'a> {
                     return Some((ray_hit, material));
                 }
             }
+            Hitable::Spheres(spheres) => {
+                stats.num_spheres += spheres.num_spheres() as u64;
+                let ray_hit = spheres.ray_hit(ray, t_min, t_max);
+                println!(
+                    " {:+1$}SpheresSoA spheres: {2} hit: {3:?}",
+                    "",
+                    depth,
+                    spheres.num_spheres(),
+                    ray_hit.map(|(ray_hit, _)| ray_hit)
+                );
+                return ray_hit;
+            }
             Hitable::Rect(rect, material) => {
                 stats.num_rects += 1;
                 let ray_hit = rect.ray_hit(ray, t_min, t_max);
@@ -210,22 +344,127 @@ impl<'a> BVHNode<'a> {
                     return Some((ray_hit, material));
                 }
             }
+            Hitable::Triangle(triangle, material) => {
+                stats.num_triangles += 1;
+                let ray_hit = triangle.ray_hit(ray, t_min, t_max);
+                println!(
+                    " {:+2$}Triangle {1} vertices: {4:?} hit: {3:?}",
+                    "",
+                    stats.num_triangles,
+                    depth,
+                    ray_hit,
+                    triangle.vertices()
+                );
+                if let Some(ray_hit) = ray_hit {
+                    return Some((ray_hit, material));
+                }
+            }
+            Hitable::MeshFace(mesh, face) => {
+                stats.num_triangles += 1;
+                let ray_hit = mesh.face_ray_hit(*face, ray, t_min, t_max);
+                println!(
+                    " {:+2$}MeshFace {1} face: {4} hit: {3:?}",
+                    "",
+                    stats.num_triangles,
+                    depth,
+                    ray_hit.map(|(ray_hit, _)| ray_hit),
+                    face
+                );
+                return ray_hit;
+            }
+            Hitable::TriangleMesh(mesh) => {
+                stats.num_meshes += 1;
+                let ray_hit = mesh.ray_hit(ray, t_min, t_max);
+                println!(
+                    " {:+2$}TriangleMesh {1} faces: {4} {3}!",
+                    "",
+                    stats.num_meshes,
+                    depth,
+                    MISS_OR_HIT[ray_hit.is_some() as usize],
+                    mesh.num_faces()
+                );
+                return ray_hit;
+            }
             Hitable::ConstantMedium(constant_medium) => {
                 stats.num_constant_mediums += 1;
-                return constant_medium.ray_hit(ray, t_min, t_max, rng);
+                return constant_medium.ray_hit(ray, t_min, t_max, sampler);
             }
             Hitable::Instance(instance) => {
                 stats.num_instances += 1;
-                return instance.ray_hit(ray, t_min, t_max, rng);
+                return instance.ray_hit(ray, t_min, t_max, sampler);
+            }
+            Hitable::LinearBVH(bvh) => {
+                let ray_hit = bvh.ray_hit(ray, t_min, t_max, sampler);
+                println!(
+                    " {:+1$}LinearBVH nodes: {2} motion: {3} hit: {4:?}",
+                    "",
+                    depth,
+                    bvh.num_nodes(),
+                    bvh.is_motion(),
+                    ray_hit.map(|(ray_hit, _)| ray_hit)
+                );
+                return ray_hit;
+            }
+            Hitable::WideBVH(bvh) => {
+                let ray_hit = bvh.ray_hit(ray, t_min, t_max, sampler);
+                println!(
+                    " {:+1$}WideBVH width: {2} nodes: {3} hit: {4:?}",
+                    "",
+                    depth,
+                    bvh.width(),
+                    bvh.num_nodes(),
+                    ray_hit.map(|(ray_hit, _)| ray_hit)
+                );
+                return ray_hit;
+            }
+            Hitable::Tlas(tlas) => {
+                let ray_hit = tlas.ray_hit(ray, t_min, t_max, sampler);
+                println!(
+                    " {:+1$}Tlas nodes: {2} instances: {3} blases: {4} hit: {5:?}",
+                    "",
+                    depth,
+                    tlas.num_nodes(),
+                    tlas.num_instances(),
+                    tlas.blases().count(),
+                    ray_hit.map(|(ray_hit, _)| ray_hit)
+                );
+                return ray_hit;
+            }
+            Hitable::List(list) => {
+                println!(
+                    " {:+1$}List hitables: {2}",
+                    "",
+                    depth,
+                    list.hitables().len()
+                );
+                let mut result = None;
+                let mut closest_so_far = t_max;
+                for hitable in list.hitables() {
+                    if let Some(ray_hit) = self.print_ray_hit_child(
+                        depth + 1,
+                        stats,
+                        hitable,
+                        ray,
+                        t_min,
+                        closest_so_far,
+                        sampler,
+                    ) {
+                        closest_so_far = ray_hit.0.t;
+                        result = Some(ray_hit);
+                    }
+                }
+                return result;
             }
-            Hitable::List(_) => unimplemented!(),
         }
         None
     }
 
-    pub fn get_stats(&self) -> BVHStats {
+    /// Stats of the tree, with its SAH cost over the bounds swept from `t0` to `t1` that it was
+    /// built with.
+    pub fn get_stats(&self, t0: f32, t1: f32) -> BVHStats {
         let mut stats = BVHStats::default();
         stats.max_depth = self.get_node_stats(0, &mut stats);
+        stats.sah_cost = self.sah_cost(t0, t1);
         stats
     }
 
@@ -237,33 +476,95 @@ impl<'a> BVHNode<'a> {
     }
 
     pub fn get_child_stats(&self, depth: u64, hitable: &Hitable, stats: &mut BVHStats) -> u64 {
-        match hitable {
+        let leaf = match hitable {
             Hitable::BVHNode(node) => {
                 return node.get_node_stats(depth + 1, stats);
             }
-            Hitable::Sphere(_, _) => {
-                stats.num_spheres += 1;
-            }
-            Hitable::MovingSphere(_, _) => {
-                stats.num_moving_spheres += 1;
-            }
-            Hitable::Rect(_, _) => {
-                stats.num_rects += 1;
-            }
-            Hitable::Cuboid(_, _) => {
-                stats.num_boxes += 1;
-            }
-            Hitable::ConstantMedium(_) => {
-                stats.num_constant_mediums += 1;
-            }
-            Hitable::Instance(_) => {
-                stats.num_instances += 1;
+            Hitable::List(list) => list.hitables(),
+            _ => slice::from_ref(hitable),
+        };
+        // packed spheres count individually towards the leaf size
+        let leaf_size: usize = leaf
+            .iter()
+            .map(|hitable| match hitable {
+                Hitable::Spheres(spheres) => spheres.num_spheres(),
+                _ => 1,
+            })
+            .sum();
+        stats.num_leaves += 1;
+        stats.leaf_sizes[leaf_size.clamp(1, LEAF_SIZE_BUCKETS) - 1] += 1;
+        for hitable in leaf {
+            match hitable {
+                Hitable::Sphere(_, _) => {
+                    stats.num_spheres += 1;
+                }
+                Hitable::Spheres(spheres) => {
+                    stats.num_spheres += spheres.num_spheres() as u64;
+                    stats.num_sphere_clusters += 1;
+                }
+                Hitable::MovingSphere(_, _) => {
+                    stats.num_moving_spheres += 1;
+                }
+                Hitable::Rect(_, _) => {
+                    stats.num_rects += 1;
+                }
+                Hitable::Cuboid(_, _) => {
+                    stats.num_boxes += 1;
+                }
+                Hitable::Triangle(_, _) | Hitable::MeshFace(_, _) => {
+                    stats.num_triangles += 1;
+                }
+                Hitable::TriangleMesh(_) => {
+                    stats.num_meshes += 1;
+                }
+                Hitable::ConstantMedium(_) => {
+                    stats.num_constant_mediums += 1;
+                }
+                Hitable::Instance(_) => {
+                    stats.num_instances += 1;
+                }
+                Hitable::BVHNode(_)
+                | Hitable::LinearBVH(_)
+                | Hitable::WideBVH(_)
+                | Hitable::Tlas(_)
+                | Hitable::List(_) => {}
             }
-            Hitable::List(_) => unimplemented!(),
         }
         depth
     }
 
+    /// Expected cost of tracing a ray that hits the root node, the sum over all nodes of the
+    /// probability of visiting them times their traversal and intersection costs. Leaves are
+    /// bounded over `t0` to `t1`, which should match the build's.
+    pub fn sah_cost(&self, t0: f32, t1: f32) -> f32 {
+        let area = self.aabb.surface_area();
+        TRAVERSAL_COST
+            + BVHNode::child_sah_cost(&self.lhs, area, t0, t1)
+            + BVHNode::child_sah_cost(&self.rhs, area, t0, t1)
+    }
+
+    fn child_sah_cost(hitable: &Hitable, parent_area: f32, t0: f32, t1: f32) -> f32 {
+        let (area, cost) = match hitable {
+            Hitable::BVHNode(node) => (node.aabb.surface_area(), node.sah_cost(t0, t1)),
+            Hitable::List(list) => (
+                list.bounding_box(t0, t1)
+                    .map_or(0.0, |aabb| aabb.surface_area()),
+                INTERSECTION_COST * list.hitables().len() as f32,
+            ),
+            _ => (
+                hitable
+                    .bounding_box(t0, t1)
+                    .map_or(0.0, |aabb| aabb.surface_area()),
+                INTERSECTION_COST,
+            ),
+        };
+        if parent_area > 0.0 {
+            area / parent_area * cost
+        } else {
+            cost
+        }
+    }
+
     #[inline]
     fn sort_by_axis(rng: &mut Xoshiro256Plus, hitables: &mut [Hitable<'a>], t0: f32, t1: f32) {
         let axis = rng.gen_range(0..3);
@@ -345,6 +646,199 @@ impl<'a> BVHNode<'a> {
             rhs: rhs,
         })
     }
+
+    /// Builds a BVH over `hitables` using the given split strategy. Unlike `new` this may
+    /// produce `Hitable::List` leaves holding several primitives, with any spheres among them
+    /// packed into a `SpheresSoA`.
+    pub fn build(
+        builder: BVHBuilder,
+        rng: &mut Xoshiro256Plus,
+        hitables: &mut [Hitable<'a>],
+        t0: f32,
+        t1: f32,
+        storage: &'a Storage<'a>,
+    ) -> Option<&'a BVHNode<'a>> {
+        if builder == BVHBuilder::Random || hitables.len() < 2 {
+            return BVHNode::new(rng, hitables, t0, t1, &storage.bvhnode_arena);
+        }
+        let mut primitives: Vec<BuildPrimitive<Hitable>> = hitables
+            .iter()
+            .map(|&hitable| {
+                let aabb = hitable.bounding_box(t0, t1).unwrap();
+                BuildPrimitive {
+                    item: hitable,
+                    aabb,
+                    centroid: aabb.centroid(),
+                }
+            })
+            .collect();
+        match BVHNode::build_node(builder, &mut primitives, storage) {
+            Hitable::BVHNode(node) => Some(node),
+            leaf => {
+                // the SAH preferred a single leaf, wrap it so the root is always a node
+                let aabb = leaf.bounding_box(t0, t1).unwrap();
+                Some(BVHNode::alloc_bvhnode(
+                    &storage.bvhnode_arena,
+                    leaf,
+                    leaf,
+                    aabb,
+                ))
+            }
+        }
+    }
+
+    fn build_node(
+        builder: BVHBuilder,
+        primitives: &mut [BuildPrimitive<Hitable<'a>>],
+        storage: &'a Storage<'a>,
+    ) -> Hitable<'a> {
+        if primitives.len() == 1 {
+            return primitives[0].item;
+        }
+        let mut aabb = AABB::invalid();
+        let mut centroid_bounds = AABB::invalid();
+        for primitive in primitives.iter() {
+            aabb.add_assign(&primitive.aabb);
+            centroid_bounds.add_assign(&AABB {
+                min: primitive.centroid,
+                max: primitive.centroid,
+            });
+        }
+        let pivot = match builder {
+            BVHBuilder::Sah => BVHNode::sah_split(primitives, &aabb, &centroid_bounds),
+            _ => Some(BVHNode::median_split(primitives, &centroid_bounds)),
+        };
+        match pivot {
+            Some(pivot) => {
+                let (lhs, rhs) = primitives.split_at_mut(pivot);
+                let lhs = BVHNode::build_node(builder, lhs, storage);
+                let rhs = BVHNode::build_node(builder, rhs, storage);
+                Hitable::BVHNode(BVHNode::alloc_bvhnode(
+                    &storage.bvhnode_arena,
+                    lhs,
+                    rhs,
+                    aabb,
+                ))
+            }
+            None => {
+                let hitables: Vec<Hitable> =
+                    primitives.iter().map(|primitive| primitive.item).collect();
+                // test the leaf's spheres together with SIMD
+                let mut hitables = SpheresSoA::pack(&hitables, storage);
+                if hitables.len() == 1 {
+                    hitables.pop().unwrap()
+                } else {
+                    Hitable::List(storage.alloc_hitables(hitables))
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn longest_axis(aabb: &AABB) -> usize {
+        let extent = aabb.max - aabb.min;
+        if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Partitions around the centroid median of the longest axis and returns the pivot.
+    pub(super) fn median_split<T>(
+        primitives: &mut [BuildPrimitive<T>],
+        centroid_bounds: &AABB,
+    ) -> usize {
+        let axis = BVHNode::longest_axis(centroid_bounds);
+        let pivot = primitives.len() / 2;
+        primitives.select_nth_unstable_by(pivot, |lhs, rhs| {
+            lhs.centroid[axis].partial_cmp(&rhs.centroid[axis]).unwrap()
+        });
+        pivot
+    }
+
+    /// Finds the cheapest binned SAH split over all three axes and partitions around it.
+    /// Returns `None` if a leaf is cheaper than any split.
+    pub(super) fn sah_split<T>(
+        primitives: &mut [BuildPrimitive<T>],
+        aabb: &AABB,
+        centroid_bounds: &AABB,
+    ) -> Option<usize> {
+        let count = primitives.len();
+        let leaf_cost = INTERSECTION_COST * count as f32;
+        let area = aabb.surface_area();
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let bin_index = |centroid: Vec3, axis: usize| {
+            let offset = (centroid[axis] - centroid_bounds.min[axis]) / extent[axis];
+            ((offset * SAH_BINS as f32) as usize).min(SAH_BINS - 1)
+        };
+
+        let mut best: Option<(f32, usize, usize)> = None;
+        for axis in 0..3 {
+            if extent[axis] <= 0.0 {
+                continue;
+            }
+            let mut bins = [SahBin {
+                aabb: AABB::invalid(),
+                count: 0,
+            }; SAH_BINS];
+            for primitive in primitives.iter() {
+                let bin = &mut bins[bin_index(primitive.centroid, axis)];
+                bin.aabb.add_assign(&primitive.aabb);
+                bin.count += 1;
+            }
+
+            // sweep from the right to get the area and count of everything after each split
+            let mut right_area = [0.0; SAH_BINS];
+            let mut right_count = [0; SAH_BINS];
+            let mut right_aabb = AABB::invalid();
+            let mut accumulated = 0;
+            for split in (1..SAH_BINS).rev() {
+                right_aabb.add_assign(&bins[split].aabb);
+                accumulated += bins[split].count;
+                right_area[split] = right_aabb.surface_area();
+                right_count[split] = accumulated;
+            }
+
+            let mut left_aabb = AABB::invalid();
+            let mut left_count = 0;
+            for split in 1..SAH_BINS {
+                left_aabb.add_assign(&bins[split - 1].aabb);
+                left_count += bins[split - 1].count;
+                if left_count == 0 || right_count[split] == 0 {
+                    continue;
+                }
+                let cost = TRAVERSAL_COST
+                    + INTERSECTION_COST
+                        * (left_aabb.surface_area() * left_count as f32
+                            + right_area[split] * right_count[split] as f32)
+                        / area;
+                if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                    best = Some((cost, axis, split));
+                }
+            }
+        }
+
+        match best {
+            Some((cost, _, _)) if count <= MAX_LEAF_SIZE && leaf_cost <= cost => None,
+            Some((_, axis, split)) => {
+                // partition so everything in a bin left of the split comes first
+                let mut pivot = 0;
+                for index in 0..count {
+                    if bin_index(primitives[index].centroid, axis) < split {
+                        primitives.swap(index, pivot);
+                        pivot += 1;
+                    }
+                }
+                Some(pivot)
+            }
+            // all centroids coincide so no split can separate them
+            None if count <= MAX_LEAF_SIZE => None,
+            None => Some(BVHNode::median_split(primitives, centroid_bounds)),
+        }
+    }
 }
 
 #[cfg(all(feature = "bench", test))]
@@ -364,7 +858,9 @@ mod bench {
         let storage = Storage::new(&mut rng);
         let (mut hitables, camera, _) = presets::random_spheres(&PARAMS, &mut rng, &storage);
         let ray = camera.get_ray(0.5, 0.5, &mut rng);
-        let bvh_root = BVHNode::new(&mut rng, &mut hitables, &storage.bvhnode_arena).unwrap();
+        let (t0, t1) = camera.shutter();
+        let bvh_root =
+            BVHNode::new(&mut rng, &mut hitables, t0, t1, &storage.bvhnode_arena).unwrap();
         b.iter(|| bvh_root.ray_hit(&ray, MIN_T, MAX_T, &mut rng));
     }
 
@@ -374,7 +870,9 @@ mod bench {
         let storage = Storage::new(&mut rng);
         let (mut hitables, camera, _) = presets::random(&PARAMS, &mut rng, &storage);
         let ray = camera.get_ray(0.5, 0.5, &mut rng);
-        let bvh_root = BVHNode::new(&mut rng, &mut hitables, &storage.bvhnode_arena).unwrap();
+        let (t0, t1) = camera.shutter();
+        let bvh_root =
+            BVHNode::new(&mut rng, &mut hitables, t0, t1, &storage.bvhnode_arena).unwrap();
         b.iter(|| bvh_root.ray_hit(&ray, MIN_T, MAX_T, &mut rng));
     }
 }
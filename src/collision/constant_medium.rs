@@ -1,11 +1,10 @@
 use crate::{
     collision::{Hitable, Ray, RayHit, AABB},
     material::{isotropic, Material},
+    sampler::Sampler,
     texture::Texture,
 };
 use glam::Vec3;
-use rand::Rng;
-use rand_xoshiro::Xoshiro256Plus;
 use std::f32;
 
 #[derive(Copy, Clone, Debug)]
@@ -25,6 +24,21 @@ impl<'a> ConstantMedium<'a> {
         }
     }
 
+    #[inline]
+    pub fn hitable(&self) -> Hitable<'a> {
+        self.hitable
+    }
+
+    #[inline]
+    pub fn density(&self) -> f32 {
+        self.density
+    }
+
+    #[inline]
+    pub fn phase_function(&self) -> &Material<'a> {
+        &self.phase_function
+    }
+
     pub fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
         self.hitable.bounding_box(t0, t1)
     }
@@ -34,12 +48,12 @@ impl<'a> ConstantMedium<'a> {
         ray: &Ray,
         t_min: f32,
         t_max: f32,
-        rng: &mut Xoshiro256Plus,
+        sampler: &mut dyn Sampler,
     ) -> Option<(RayHit, &Material)> {
-        if let Some((ray_hit1, _)) = self.hitable.ray_hit(ray, -f32::MAX, f32::MAX, rng) {
+        if let Some((ray_hit1, _)) = self.hitable.ray_hit(ray, -f32::MAX, f32::MAX, sampler) {
             if let Some((ray_hit2, _)) =
                 self.hitable
-                    .ray_hit(ray, ray_hit1.t + 0.0001, f32::MAX, rng)
+                    .ray_hit(ray, ray_hit1.t + 0.0001, f32::MAX, sampler)
             {
                 let mut t1 = ray_hit1.t;
                 let mut t2 = ray_hit2.t;
@@ -57,7 +71,7 @@ impl<'a> ConstantMedium<'a> {
                 }
                 let ray_length = ray.direction.length();
                 let distance_inside_boundary = (t2 - t1) * ray_length;
-                let hit_distance = -(1.0 / self.density) * rng.gen::<f32>().ln();
+                let hit_distance = -(1.0 / self.density) * (1.0 - sampler.next_1d()).ln();
                 if hit_distance < distance_inside_boundary {
                     let t = t1 + hit_distance / ray_length;
                     return Some((
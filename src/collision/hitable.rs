@@ -1,35 +1,79 @@
 #![allow(dead_code)]
 use crate::{
     collision::{
-        BVHNode, ConstantMedium, Cuboid, HitableList, Instance, MovingSphere, Ray, RayHit, Rect,
-        Sphere, AABB,
+        lanes, BVHNode, ConstantMedium, Cuboid, HitableList, Instance, LinearBVH, MovingSphere,
+        PacketHits, Ray, RayHit, RayPacket, Rect, Sphere, SpheresSoA, Tlas, Triangle, TriangleMesh,
+        WideBVH, AABB,
     },
     material::Material,
+    sampler::Sampler,
 };
-use rand_xoshiro::Xoshiro256Plus;
 
 #[derive(Copy, Clone, Debug)]
 pub enum Hitable<'a> {
     BVHNode(&'a BVHNode<'a>),
+    LinearBVH(&'a LinearBVH<'a>),
+    WideBVH(&'a WideBVH<'a>),
+    Tlas(&'a Tlas<'a>),
     Instance(&'a Instance<'a>),
     Rect(&'a Rect, &'a Material<'a>),
     Cuboid(&'a Cuboid, &'a Material<'a>),
     MovingSphere(&'a MovingSphere, &'a Material<'a>),
     Sphere(&'a Sphere, &'a Material<'a>),
+    Spheres(&'a SpheresSoA<'a>),
+    Triangle(&'a Triangle, &'a Material<'a>),
+    TriangleMesh(&'a TriangleMesh<'a>),
+    MeshFace(&'a TriangleMesh<'a>, u32),
     ConstantMedium(&'a ConstantMedium<'a>),
     List(&'a HitableList<'a>),
 }
 
 impl<'a> Hitable<'a> {
+    /// Returns true if both hitables refer to the same arena allocated object.
+    #[inline]
+    pub fn ptr_eq(&self, other: &Hitable) -> bool {
+        self.address() == other.address()
+    }
+
+    /// Returns the address of the arena allocated object, and the face index for mesh faces,
+    /// which identifies the hitable for as long as the arena lives.
+    #[inline]
+    pub fn address(&self) -> (usize, u32) {
+        match *self {
+            Hitable::BVHNode(node) => (node as *const BVHNode as usize, 0),
+            Hitable::LinearBVH(bvh) => (bvh as *const LinearBVH as usize, 0),
+            Hitable::WideBVH(bvh) => (bvh as *const WideBVH as usize, 0),
+            Hitable::Tlas(tlas) => (tlas as *const Tlas as usize, 0),
+            Hitable::Instance(instance) => (instance as *const Instance as usize, 0),
+            Hitable::Rect(rect, _) => (rect as *const Rect as usize, 0),
+            Hitable::Cuboid(cuboid, _) => (cuboid as *const Cuboid as usize, 0),
+            Hitable::MovingSphere(sphere, _) => (sphere as *const MovingSphere as usize, 0),
+            Hitable::Sphere(sphere, _) => (sphere as *const Sphere as usize, 0),
+            Hitable::Spheres(spheres) => (spheres as *const SpheresSoA as usize, 0),
+            Hitable::Triangle(triangle, _) => (triangle as *const Triangle as usize, 0),
+            Hitable::TriangleMesh(mesh) => (mesh as *const TriangleMesh as usize, 0),
+            Hitable::MeshFace(mesh, face) => (mesh as *const TriangleMesh as usize, face),
+            Hitable::ConstantMedium(medium) => (medium as *const ConstantMedium as usize, 0),
+            Hitable::List(list) => (list as *const HitableList as usize, 0),
+        }
+    }
+
     #[inline]
     pub fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
         match self {
             Hitable::BVHNode(node) => Some(node.bounding_box()),
+            Hitable::LinearBVH(bvh) => Some(bvh.bounding_box()),
+            Hitable::WideBVH(bvh) => Some(bvh.bounding_box()),
+            Hitable::Tlas(tlas) => Some(tlas.bounding_box()),
             Hitable::Instance(instance) => instance.bounding_box(t0, t1),
             Hitable::Rect(rect, _) => Some(rect.bounding_box()),
             Hitable::Cuboid(cuboid, _) => Some(cuboid.bounding_box()),
             Hitable::Sphere(sphere, _) => Some(sphere.bounding_box()),
+            Hitable::Spheres(spheres) => Some(spheres.bounding_box()),
             Hitable::MovingSphere(sphere, _) => Some(sphere.bounding_box(t0, t1)),
+            Hitable::Triangle(triangle, _) => Some(triangle.bounding_box()),
+            Hitable::TriangleMesh(mesh) => Some(mesh.bounding_box()),
+            Hitable::MeshFace(mesh, face) => Some(mesh.face_bounding_box(*face)),
             Hitable::ConstantMedium(constant_medium) => constant_medium.bounding_box(t0, t1),
             Hitable::List(list) => list.bounding_box(t0, t1),
         }
@@ -41,21 +85,30 @@ impl<'a> Hitable<'a> {
         ray: &Ray,
         t_min: f32,
         t_max: f32,
-        rng: &mut Xoshiro256Plus,
+        sampler: &mut dyn Sampler,
     ) -> Option<(RayHit, &Material)> {
         let (ray_hit, material) = match self {
-            Hitable::BVHNode(node) => return node.ray_hit(ray, t_min, t_max, rng),
-            Hitable::Instance(instance) => return instance.ray_hit(ray, t_min, t_max, rng),
+            Hitable::BVHNode(node) => return node.ray_hit(ray, t_min, t_max, sampler),
+            Hitable::LinearBVH(bvh) => return bvh.ray_hit(ray, t_min, t_max, sampler),
+            Hitable::WideBVH(bvh) => return bvh.ray_hit(ray, t_min, t_max, sampler),
+            Hitable::Tlas(tlas) => return tlas.ray_hit(ray, t_min, t_max, sampler),
+            Hitable::Instance(instance) => return instance.ray_hit(ray, t_min, t_max, sampler),
             Hitable::Rect(rect, material) => (rect.ray_hit(ray, t_min, t_max), material),
             Hitable::Cuboid(cuboid, material) => (cuboid.ray_hit(ray, t_min, t_max), material),
             Hitable::Sphere(sphere, material) => (sphere.ray_hit(ray, t_min, t_max), material),
+            Hitable::Spheres(spheres) => return spheres.ray_hit(ray, t_min, t_max),
             Hitable::MovingSphere(sphere, material) => {
                 (sphere.ray_hit(ray, t_min, t_max), material)
             }
+            Hitable::Triangle(triangle, material) => {
+                (triangle.ray_hit(ray, t_min, t_max), material)
+            }
+            Hitable::TriangleMesh(mesh) => return mesh.ray_hit(ray, t_min, t_max),
+            Hitable::MeshFace(mesh, face) => return mesh.face_ray_hit(*face, ray, t_min, t_max),
             Hitable::ConstantMedium(constant_medium) => {
-                return constant_medium.ray_hit(ray, t_min, t_max, rng)
+                return constant_medium.ray_hit(ray, t_min, t_max, sampler)
             }
-            Hitable::List(list) => return list.ray_hit(ray, t_min, t_max, rng),
+            Hitable::List(list) => return list.ray_hit(ray, t_min, t_max, sampler),
         };
         if let Some(ray_hit) = ray_hit {
             Some((ray_hit, material))
@@ -63,4 +116,66 @@ impl<'a> Hitable<'a> {
             None
         }
     }
+
+    /// Like `ray_hit` but also returns the primitive that was hit, for identifying it in
+    /// auxiliary outputs. Instances, including those in a `Tlas`, constant media and unsplit
+    /// triangle meshes count as a single primitive, packed spheres each count as one.
+    pub fn ray_pick(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(RayHit, &Material<'_>, Hitable<'a>)> {
+        match self {
+            Hitable::BVHNode(node) => node.ray_pick(ray, t_min, t_max, sampler),
+            Hitable::LinearBVH(bvh) => bvh.ray_pick(ray, t_min, t_max, sampler),
+            Hitable::WideBVH(bvh) => bvh.ray_pick(ray, t_min, t_max, sampler),
+            Hitable::Tlas(tlas) => tlas.ray_pick(ray, t_min, t_max, sampler),
+            Hitable::List(list) => list.ray_pick(ray, t_min, t_max, sampler),
+            Hitable::Spheres(spheres) => spheres.ray_pick(ray, t_min, t_max),
+            _ => self
+                .ray_hit(ray, t_min, t_max, sampler)
+                .map(|(ray_hit, material)| (ray_hit, material, *self)),
+        }
+    }
+
+    /// Like `ray_pick` for the rays of `packet` in `mask`, recording hits in `hits` if they are
+    /// closer than those already found. `samplers` has one sampler per lane.
+    ///
+    /// BVHs, lists and packed spheres are traced as a packet, anything else, and any packet
+    /// that has diverged down to a single ray, is traced one ray at a time.
+    pub fn ray_pick_packet<'s>(
+        &'s self,
+        packet: &RayPacket,
+        t_min: f32,
+        mask: u32,
+        hits: &mut PacketHits<'s, 'a>,
+        samplers: &mut [Box<dyn Sampler>],
+    ) {
+        if mask.count_ones() > 1 {
+            match *self {
+                Hitable::BVHNode(node) => {
+                    return node.ray_pick_packet(packet, t_min, mask, hits, samplers)
+                }
+                Hitable::LinearBVH(bvh) if !bvh.is_motion() => {
+                    return bvh.ray_pick_packet(packet, t_min, mask, hits, samplers)
+                }
+                Hitable::List(list) => {
+                    return list.ray_pick_packet(packet, t_min, mask, hits, samplers)
+                }
+                Hitable::Spheres(spheres) => {
+                    return spheres.ray_pick_packet(packet, t_min, mask, hits)
+                }
+                _ => (),
+            }
+        }
+        for lane in lanes(mask) {
+            let ray = &packet.rays[lane];
+            if let Some(hit) = self.ray_pick(ray, t_min, hits.t_max[lane], samplers[lane].as_mut())
+            {
+                hits.record(lane, hit);
+            }
+        }
+    }
 }
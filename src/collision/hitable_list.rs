@@ -1,9 +1,9 @@
 #![allow(dead_code)]
 use crate::{
-    collision::{Hitable, Ray, RayHit, AABB},
+    collision::{Hitable, PacketHits, Ray, RayHit, RayPacket, AABB},
     material::Material,
+    sampler::Sampler,
 };
-use rand_xoshiro::Xoshiro256Plus;
 
 #[derive(Debug)]
 pub struct HitableList<'a> {
@@ -15,6 +15,11 @@ impl<'a> HitableList<'a> {
         HitableList { hitables }
     }
 
+    #[inline]
+    pub fn hitables(&self) -> &[Hitable<'a>] {
+        &self.hitables
+    }
+
     pub fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
         if self.hitables.is_empty() {
             return None;
@@ -42,18 +47,50 @@ impl<'a> HitableList<'a> {
         ray: &Ray,
         t_min: f32,
         t_max: f32,
-        rng: &mut Xoshiro256Plus,
+        sampler: &mut dyn Sampler,
     ) -> Option<(RayHit, &Material)> {
         let mut result = None;
         let mut closest_so_far = t_max;
         for hitable in &self.hitables {
-            if let Some((ray_hit, material)) = hitable.ray_hit(ray, t_min, closest_so_far, rng) {
+            if let Some((ray_hit, material)) = hitable.ray_hit(ray, t_min, closest_so_far, sampler)
+            {
                 result = Some((ray_hit, material));
                 closest_so_far = ray_hit.t;
             }
         }
         result
     }
+
+    pub fn ray_pick(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(RayHit, &Material<'_>, Hitable<'a>)> {
+        let mut result = None;
+        let mut closest_so_far = t_max;
+        for hitable in &self.hitables {
+            if let Some(hit) = hitable.ray_pick(ray, t_min, closest_so_far, sampler) {
+                closest_so_far = hit.0.t;
+                result = Some(hit);
+            }
+        }
+        result
+    }
+
+    pub fn ray_pick_packet<'s>(
+        &'s self,
+        packet: &RayPacket,
+        t_min: f32,
+        mask: u32,
+        hits: &mut PacketHits<'s, 'a>,
+        samplers: &mut [Box<dyn Sampler>],
+    ) {
+        for hitable in &self.hitables {
+            hitable.ray_pick_packet(packet, t_min, mask, hits, samplers);
+        }
+    }
 }
 
 #[cfg(all(feature = "bench", test))]
@@ -1,15 +1,16 @@
 use crate::{
     collision::{Hitable, Ray, RayHit, AABB},
     material::Material,
+    sampler::Sampler,
 };
-use glam::Affine3A;
-use rand_xoshiro::Xoshiro256Plus;
+use glam::{Affine3A, Mat3A};
 
 #[derive(Copy, Clone, Debug)]
 pub struct Instance<'a> {
     hitable: Hitable<'a>,
     transform: Affine3A,
     inv_transform: Affine3A,
+    normal_transform: Mat3A,
 }
 
 impl<'a> Instance<'a> {
@@ -18,9 +19,20 @@ impl<'a> Instance<'a> {
             hitable,
             transform,
             inv_transform: transform.inverse(),
+            normal_transform: transform.matrix3.inverse().transpose(),
         }
     }
 
+    #[inline]
+    pub fn hitable(&self) -> Hitable<'a> {
+        self.hitable
+    }
+
+    #[inline]
+    pub fn transform(&self) -> &Affine3A {
+        &self.transform
+    }
+
     pub fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
         if let Some(aabb) = self.hitable.bounding_box(t0, t1) {
             Some(aabb.transform(&self.transform))
@@ -34,13 +46,28 @@ impl<'a> Instance<'a> {
         ray: &Ray,
         t_min: f32,
         t_max: f32,
-        rng: &mut Xoshiro256Plus,
+        sampler: &mut dyn Sampler,
     ) -> Option<(RayHit, &Material)> {
+        self.ray_hit_blas(&self.hitable, ray, t_min, t_max, sampler)
+    }
+
+    /// Like `ray_hit` but traces `blas` in place of the instanced hitable, for a `Tlas` which
+    /// builds an acceleration structure over it that all its instances share.
+    pub fn ray_hit_blas<'h>(
+        &self,
+        blas: &'h Hitable,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(RayHit, &'h Material<'h>)> {
         if let Some((ray_hit, material)) =
-            self.hitable
-                .ray_hit(&ray.transform(&self.inv_transform), t_min, t_max, rng)
+            blas.ray_hit(&ray.transform(&self.inv_transform), t_min, t_max, sampler)
         {
-            Some((ray_hit.transform(&self.transform), material))
+            Some((
+                ray_hit.transform(&self.transform, &self.normal_transform),
+                material,
+            ))
         } else {
             None
         }
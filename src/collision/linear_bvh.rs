@@ -0,0 +1,466 @@
+use crate::{
+    collision::{BVHNode, Hitable, PacketHits, Ray, RayHit, RayPacket, AABB},
+    material::Material,
+    sampler::Sampler,
+};
+
+// deep enough for all but badly skewed trees, which traverse with a heap allocated stack
+const MAX_STACK_DEPTH: usize = 64;
+
+/// A BVH node packed into 32 bytes so two nodes share a cache line.
+///
+/// Interior nodes have `count == 0`, their first child immediately follows them in the node
+/// array and `offset` is the index of the second child. Leaf nodes reference `count`
+/// primitives starting at `offset`.
+#[derive(Copy, Clone, Debug)]
+#[repr(C, align(32))]
+struct LinearBVHNode {
+    aabb: AABB,
+    offset: u32,
+    count: u16,
+    axis: u8,
+}
+
+/// A depth first, flattened copy of a `BVHNode` tree with an iterative traversal.
+///
+/// A motion BVH additionally stores the bounds of every node at the end of the shutter
+/// interval, the bounds tested against a ray are interpolated between the start and end bounds
+/// by the ray's time. This is much tighter than bounds swept over the whole interval when
+/// objects move a long way.
+#[derive(Debug)]
+pub struct LinearBVH<'a> {
+    nodes: Vec<LinearBVHNode>,
+    end_aabbs: Vec<AABB>,
+    time0: f32,
+    inv_time_delta: f32,
+    primitives: Vec<Hitable<'a>>,
+    /// The depth of the deepest leaf, which bounds the size of the traversal stack.
+    depth: usize,
+}
+
+impl<'a> LinearBVH<'a> {
+    /// Flattens `root` with node bounds swept over the shutter interval `t0` to `t1`.
+    pub fn new(root: &'a BVHNode<'a>, t0: f32, t1: f32) -> LinearBVH<'a> {
+        LinearBVH::flatten_root(root, t0, t1, false)
+    }
+
+    /// Flattens `root` with node bounds at `t0` and `t1` which are interpolated by ray time.
+    /// Falls back to swept bounds if the shutter interval is empty.
+    pub fn with_motion(root: &'a BVHNode<'a>, t0: f32, t1: f32) -> LinearBVH<'a> {
+        LinearBVH::flatten_root(root, t0, t1, t1 > t0)
+    }
+
+    fn flatten_root(root: &'a BVHNode<'a>, t0: f32, t1: f32, motion: bool) -> LinearBVH<'a> {
+        let mut bvh = LinearBVH {
+            nodes: Vec::new(),
+            end_aabbs: Vec::new(),
+            time0: t0,
+            inv_time_delta: if motion { 1.0 / (t1 - t0) } else { 0.0 },
+            primitives: Vec::new(),
+            depth: 0,
+        };
+        let (depth, _, _) = bvh.flatten(&Hitable::BVHNode(root), 0, t0, t1, motion);
+        bvh.depth = depth;
+        bvh
+    }
+
+    #[inline]
+    pub fn bounding_box(&self) -> AABB {
+        match self.end_aabbs.first() {
+            Some(end_aabb) => self.nodes[0].aabb.add(end_aabb),
+            None => self.nodes[0].aabb,
+        }
+    }
+
+    #[inline]
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    #[inline]
+    pub fn primitives(&self) -> &[Hitable<'a>] {
+        &self.primitives
+    }
+
+    #[inline]
+    pub fn is_motion(&self) -> bool {
+        !self.end_aabbs.is_empty()
+    }
+
+    /// Appends `hitable` and its descendants to the node array. Returns the subtree depth and
+    /// its bounds at `t0` and `t1`, or its swept bounds twice if `motion` is false.
+    fn flatten(
+        &mut self,
+        hitable: &Hitable<'a>,
+        depth: usize,
+        t0: f32,
+        t1: f32,
+        motion: bool,
+    ) -> (usize, AABB, AABB) {
+        let index = self.nodes.len();
+        match hitable {
+            Hitable::BVHNode(node) => {
+                let (lhs, rhs) = node.children();
+                if lhs.ptr_eq(&rhs) {
+                    // single hitable nodes store it as both children
+                    return self.flatten(&lhs, depth, t0, t1, motion);
+                }
+                // order children by the axis their centres are furthest apart on so the
+                // traversal can visit the nearest first
+                let lhs_aabb = lhs.bounding_box(t0, t1).unwrap();
+                let rhs_aabb = rhs.bounding_box(t0, t1).unwrap();
+                let delta = (rhs_aabb.centroid() - lhs_aabb.centroid()).abs();
+                let axis = if delta.x >= delta.y && delta.x >= delta.z {
+                    0
+                } else if delta.y >= delta.z {
+                    1
+                } else {
+                    2
+                };
+                // the first child is always the one on the low side of the axis
+                let (lhs, rhs) = if rhs_aabb.centroid()[axis] < lhs_aabb.centroid()[axis] {
+                    (rhs, lhs)
+                } else {
+                    (lhs, rhs)
+                };
+                self.nodes.push(LinearBVHNode {
+                    aabb: node.bounding_box(),
+                    offset: 0,
+                    count: 0,
+                    axis: axis as u8,
+                });
+                if motion {
+                    self.end_aabbs.push(AABB::invalid());
+                }
+                let (lhs_depth, lhs_start, lhs_end) = self.flatten(&lhs, depth + 1, t0, t1, motion);
+                self.nodes[index].offset = self.nodes.len() as u32;
+                let (rhs_depth, rhs_start, rhs_end) = self.flatten(&rhs, depth + 1, t0, t1, motion);
+                if motion {
+                    self.nodes[index].aabb = lhs_start.add(&rhs_start);
+                    self.end_aabbs[index] = lhs_end.add(&rhs_end);
+                }
+                (
+                    lhs_depth.max(rhs_depth),
+                    self.nodes[index].aabb,
+                    if motion {
+                        self.end_aabbs[index]
+                    } else {
+                        self.nodes[index].aabb
+                    },
+                )
+            }
+            _ => {
+                let leaf = match hitable {
+                    Hitable::List(list) => list.hitables(),
+                    _ => std::slice::from_ref(hitable),
+                };
+                let (start, end) = if motion {
+                    (
+                        hitable.bounding_box(t0, t0).unwrap(),
+                        hitable.bounding_box(t1, t1).unwrap(),
+                    )
+                } else {
+                    let aabb = hitable.bounding_box(t0, t1).unwrap();
+                    (aabb, aabb)
+                };
+                self.push_leaf(start, leaf);
+                if motion {
+                    self.end_aabbs.push(end);
+                }
+                (depth, start, end)
+            }
+        }
+    }
+
+    fn push_leaf(&mut self, aabb: AABB, hitables: &[Hitable<'a>]) {
+        assert!(hitables.len() <= u16::MAX as usize);
+        self.nodes.push(LinearBVHNode {
+            aabb,
+            offset: self.primitives.len() as u32,
+            count: hitables.len() as u16,
+            axis: 0,
+        });
+        self.primitives.extend_from_slice(hitables);
+    }
+
+    #[inline]
+    fn node_aabb(&self, index: usize, time: f32) -> AABB {
+        let start = &self.nodes[index].aabb;
+        match self.end_aabbs.get(index) {
+            Some(end) => AABB {
+                min: start.min.lerp(end.min, time),
+                max: start.max.lerp(end.max, time),
+            },
+            None => *start,
+        }
+    }
+
+    pub fn ray_hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(RayHit, &Material<'_>)> {
+        self.traverse(0, ray, t_min, t_max, |hitable, closest_so_far| {
+            let hit = hitable.ray_hit(ray, t_min, closest_so_far, sampler)?;
+            Some((hit, hit.0.t))
+        })
+    }
+
+    pub fn ray_pick(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(RayHit, &Material<'_>, Hitable<'a>)> {
+        self.traverse(0, ray, t_min, t_max, |hitable, closest_so_far| {
+            let hit = hitable.ray_pick(ray, t_min, closest_so_far, sampler)?;
+            Some((hit, hit.0.t))
+        })
+    }
+
+    /// Like `ray_pick` for the rays of `packet` in `mask`, which are traced down the tree
+    /// together until only one of them is left in a subtree. Not for motion BVHs, whose bounds
+    /// depend on each ray's time.
+    pub fn ray_pick_packet<'s>(
+        &'s self,
+        packet: &RayPacket,
+        t_min: f32,
+        mask: u32,
+        hits: &mut PacketHits<'s, 'a>,
+        samplers: &mut [Box<dyn Sampler>],
+    ) {
+        debug_assert!(!self.is_motion());
+        // the children are ordered by the first ray's direction, the packet is coherent
+        // enough that it is usually the nearest for the others too
+        let first = &packet.rays[mask.trailing_zeros() as usize];
+        let dir_is_neg = [
+            first.direction.x < 0.0,
+            first.direction.y < 0.0,
+            first.direction.z < 0.0,
+        ];
+        let mut fixed_stack = [(0u32, 0u32); MAX_STACK_DEPTH];
+        let mut deep_stack;
+        let stack: &mut [(u32, u32)] = if self.depth < MAX_STACK_DEPTH {
+            &mut fixed_stack
+        } else {
+            deep_stack = vec![(0u32, 0u32); self.depth + 1];
+            &mut deep_stack
+        };
+        let mut stack_len = 0;
+        let mut index = 0;
+        let mut mask = mask;
+        loop {
+            let node = &self.nodes[index];
+            if node.count > 0 {
+                let start = node.offset as usize;
+                let end = start + node.count as usize;
+                for hitable in &self.primitives[start..end] {
+                    hitable.ray_pick_packet(packet, t_min, mask, hits, samplers);
+                }
+            } else {
+                let hit_mask = node.aabb.ray_hit_packet(packet, t_min, &hits.t_max, mask);
+                if hit_mask.count_ones() > 1 {
+                    let (near, far) = if dir_is_neg[node.axis as usize] {
+                        (node.offset, index as u32 + 1)
+                    } else {
+                        (index as u32 + 1, node.offset)
+                    };
+                    stack[stack_len] = (far, hit_mask);
+                    stack_len += 1;
+                    index = near as usize;
+                    mask = hit_mask;
+                    continue;
+                } else if hit_mask != 0 {
+                    // the packet has diverged, finish the subtree with the one ray left
+                    let lane = hit_mask.trailing_zeros() as usize;
+                    let ray = &packet.rays[lane];
+                    let sampler = samplers[lane].as_mut();
+                    let hit = self.traverse(
+                        index,
+                        ray,
+                        t_min,
+                        hits.t_max[lane],
+                        |hitable, closest_so_far| {
+                            let hit = hitable.ray_pick(ray, t_min, closest_so_far, sampler)?;
+                            Some((hit, hit.0.t))
+                        },
+                    );
+                    if let Some(hit) = hit {
+                        hits.record(lane, hit);
+                    }
+                }
+            }
+            if stack_len == 0 {
+                break;
+            }
+            stack_len -= 1;
+            let (next, next_mask) = stack[stack_len];
+            index = next as usize;
+            mask = next_mask;
+        }
+    }
+
+    /// Visits the leaves below node `root` that `ray` may hit nearest first, `leaf_hit` is
+    /// called for each of their primitives with the closest hit distance so far and returns a
+    /// hit and its distance.
+    #[inline]
+    fn traverse<'s, T, F>(
+        &'s self,
+        root: usize,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        mut leaf_hit: F,
+    ) -> Option<T>
+    where
+        F: FnMut(&'s Hitable<'a>, f32) -> Option<(T, f32)>,
+    {
+        let dir_is_neg = [
+            ray.direction.x < 0.0,
+            ray.direction.y < 0.0,
+            ray.direction.z < 0.0,
+        ];
+        // how far through the shutter interval the ray is, for interpolating motion bounds
+        let time = (ray.time - self.time0) * self.inv_time_delta;
+        let mut fixed_stack = [0u32; MAX_STACK_DEPTH];
+        let mut deep_stack;
+        let stack: &mut [u32] = if self.depth < MAX_STACK_DEPTH {
+            &mut fixed_stack
+        } else {
+            deep_stack = vec![0u32; self.depth + 1];
+            &mut deep_stack
+        };
+        let mut stack_len = 0;
+        let mut index = root;
+        let mut result = None;
+        let mut closest_so_far = t_max;
+        loop {
+            let node = &self.nodes[index];
+            if node.count > 0 {
+                // leaf bounds were already covered by the parent's test, testing them again
+                // can reject hits on flat primitives such as rects
+                let start = node.offset as usize;
+                let end = start + node.count as usize;
+                for hitable in &self.primitives[start..end] {
+                    if let Some((hit, t)) = leaf_hit(hitable, closest_so_far) {
+                        closest_so_far = t;
+                        result = Some(hit);
+                    }
+                }
+            } else if self
+                .node_aabb(index, time)
+                .ray_hit(ray, t_min, closest_so_far)
+            {
+                // visit the child nearest the ray origin first, the far one is only
+                // tested against whatever t remains after it
+                let (near, far) = if dir_is_neg[node.axis as usize] {
+                    (node.offset, index as u32 + 1)
+                } else {
+                    (index as u32 + 1, node.offset)
+                };
+                stack[stack_len] = far;
+                stack_len += 1;
+                index = near as usize;
+                continue;
+            }
+            if stack_len == 0 {
+                break;
+            }
+            stack_len -= 1;
+            index = stack[stack_len] as usize;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LinearBVH, MAX_STACK_DEPTH};
+    use crate::{
+        collision::{BVHNode, Hitable, Ray, Sphere},
+        material,
+        scene::{MAX_T, MIN_T},
+        storage::Storage,
+        texture,
+    };
+    use glam::Vec3;
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256Plus;
+
+    #[test]
+    fn deeper_than_fixed_stack() {
+        // nesting each BVH in the next, like a BVH over meshes' BVHs, makes a row of spheres
+        // into a tree much deeper than the fixed traversal stack
+        let mut rng = Xoshiro256Plus::seed_from_u64(0);
+        let storage = Storage::new(&mut rng);
+        let material = storage.alloc_material(material::lambertian(
+            storage.alloc_texture(texture::constant(Vec3::ONE)),
+        ));
+        let sphere = |x| {
+            Hitable::Sphere(
+                storage.alloc_sphere(Sphere::new(Vec3::new(x, 0.0, 0.0), 0.1)),
+                material,
+            )
+        };
+        let mut root = BVHNode::new(
+            &mut rng,
+            &mut [sphere(0.0)],
+            0.0,
+            0.0,
+            &storage.bvhnode_arena,
+        )
+        .unwrap();
+        for i in 1..100 {
+            let mut hitables = [Hitable::BVHNode(root), sphere(i as f32)];
+            root = BVHNode::new(&mut rng, &mut hitables, 0.0, 0.0, &storage.bvhnode_arena).unwrap();
+        }
+        let bvh = LinearBVH::new(root, 0.0, 0.0);
+        assert!(bvh.depth >= MAX_STACK_DEPTH, "depth {}", bvh.depth);
+
+        // the nearest sphere is the deepest leaf, every level above it pushes its far child
+        let ray = Ray::new(Vec3::new(-10.0, 0.0, 0.0), Vec3::X, 0.0);
+        let (ray_hit, _) = bvh.ray_hit(&ray, MIN_T, MAX_T, &mut rng).unwrap();
+        assert!((ray_hit.t - 9.9).abs() < 1e-4, "{}", ray_hit.t);
+    }
+}
+
+#[cfg(all(feature = "bench", test))]
+mod bench {
+    use crate::{
+        bench::PARAMS,
+        collision::{BVHNode, LinearBVH},
+        presets,
+        scene::{MAX_T, MIN_T},
+        storage::Storage,
+    };
+    use test::Bencher;
+
+    #[bench]
+    fn random_spheres_ray_hit(b: &mut Bencher) {
+        let mut rng = PARAMS.new_rng();
+        let storage = Storage::new(&mut rng);
+        let (mut hitables, camera, _) = presets::random_spheres(&PARAMS, &mut rng, &storage);
+        let ray = camera.get_ray(0.5, 0.5, &mut rng);
+        let (t0, t1) = camera.shutter();
+        let bvh_root =
+            BVHNode::new(&mut rng, &mut hitables, t0, t1, &storage.bvhnode_arena).unwrap();
+        let linear_bvh = LinearBVH::new(bvh_root, t0, t1);
+        b.iter(|| linear_bvh.ray_hit(&ray, MIN_T, MAX_T, &mut rng));
+    }
+
+    #[bench]
+    fn ray_hit(b: &mut Bencher) {
+        let mut rng = PARAMS.new_rng();
+        let storage = Storage::new(&mut rng);
+        let (mut hitables, camera, _) = presets::random(&PARAMS, &mut rng, &storage);
+        let ray = camera.get_ray(0.5, 0.5, &mut rng);
+        let (t0, t1) = camera.shutter();
+        let bvh_root =
+            BVHNode::new(&mut rng, &mut hitables, t0, t1, &storage.bvhnode_arena).unwrap();
+        let linear_bvh = LinearBVH::new(bvh_root, t0, t1);
+        b.iter(|| linear_bvh.ray_hit(&ray, MIN_T, MAX_T, &mut rng));
+    }
+}
@@ -2,7 +2,6 @@ use crate::collision::{Ray, RayHit, AABB};
 use glam::Vec3;
 use std::f32;
 
-// #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 #[derive(Clone, Copy, Debug)]
 pub struct MovingSphere {
     centre_start: Vec3,
@@ -29,6 +28,11 @@ impl MovingSphere {
         self.centre_start + ((time - self.time_start) * self.inv_time_delta) * self.centre_delta
     }
 
+    #[inline]
+    pub fn time_range(&self) -> (f32, f32) {
+        (self.time_start, self.time_start + 1.0 / self.inv_time_delta)
+    }
+
     #[inline]
     pub fn radius(&self) -> f32 {
         self.radius
@@ -1,4 +1,4 @@
-use glam::{Affine3A, Vec3};
+use glam::{Affine3A, Mat3A, Vec3, Vec3A};
 
 #[derive(Clone, Copy, Debug)]
 pub struct Ray {
@@ -50,10 +50,12 @@ pub struct RayHit {
 }
 
 impl RayHit {
+    /// Transforms the hit by `m`, whose inverse transpose `normal_m` transforms the normal so
+    /// it stays perpendicular to the surface under non-uniform scales.
     #[inline]
-    pub fn transform(&self, m: &Affine3A) -> Self {
+    pub fn transform(&self, m: &Affine3A, normal_m: &Mat3A) -> Self {
         let point = m.transform_point3(self.point);
-        let normal = m.transform_vector3(self.normal);
+        let normal = (*normal_m * Vec3A::from(self.normal)).normalize().into();
         RayHit {
             point,
             normal,
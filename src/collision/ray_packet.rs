@@ -0,0 +1,105 @@
+use crate::{
+    collision::{Hitable, Ray, RayHit},
+    material::Material,
+    simd::TargetFeature,
+};
+use glam::Vec3;
+
+/// The most rays in a packet, as many as fit in an AVX2 register.
+pub const MAX_PACKET_SIZE: usize = 8;
+
+/// Rays traced through the scene together. Their origins and directions are also stored as a
+/// structure of arrays so a box or sphere can be tested against all of them at once with SIMD.
+///
+/// Which rays are traced is given by a bit mask of lanes alongside the packet, the other lanes
+/// are ignored.
+#[derive(Copy, Clone, Debug)]
+pub struct RayPacket {
+    pub rays: [Ray; MAX_PACKET_SIZE],
+    pub origin: [[f32; MAX_PACKET_SIZE]; 3],
+    pub direction: [[f32; MAX_PACKET_SIZE]; 3],
+    pub rcp_direction: [[f32; MAX_PACKET_SIZE]; 3],
+    size: usize,
+    unit_directions: bool,
+}
+
+impl RayPacket {
+    /// The packet size matching the widest SIMD registers the CPU supports, 8 with AVX2, 4
+    /// with SSE4.1 and 1 otherwise, which isn't worth tracing as a packet.
+    pub fn detect_size() -> usize {
+        TargetFeature::detect().get_bits() / 32
+    }
+
+    /// A packet of `size` lanes, which must be 4 or 8 and no wider than the CPU's SIMD
+    /// registers as packets are tested with SSE4.1 or AVX2 by their size.
+    pub fn new(size: usize) -> RayPacket {
+        assert!(size == 4 || size == MAX_PACKET_SIZE);
+        assert!(size <= RayPacket::detect_size());
+        let mut packet = RayPacket {
+            rays: [Ray::new(Vec3::ZERO, Vec3::X, 0.0); MAX_PACKET_SIZE],
+            origin: [[0.0; MAX_PACKET_SIZE]; 3],
+            direction: [[0.0; MAX_PACKET_SIZE]; 3],
+            rcp_direction: [[0.0; MAX_PACKET_SIZE]; 3],
+            size,
+            unit_directions: true,
+        };
+        // unused lanes hold a valid ray so SIMD tests don't see garbage
+        for lane in 0..MAX_PACKET_SIZE {
+            packet.set(lane, packet.rays[lane]);
+        }
+        packet
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether every ray's direction is unit length, `SpheresSoA` falls back to testing the
+    /// rays one at a time otherwise.
+    #[inline]
+    pub fn unit_directions(&self) -> bool {
+        self.unit_directions
+    }
+
+    #[inline]
+    pub fn set(&mut self, lane: usize, ray: Ray) {
+        self.rays[lane] = ray;
+        for axis in 0..3 {
+            self.origin[axis][lane] = ray.origin[axis];
+            self.direction[axis][lane] = ray.direction[axis];
+            self.rcp_direction[axis][lane] = ray.rcp_direction[axis];
+        }
+        self.unit_directions &= (ray.direction.length_squared() - 1.0).abs() <= 1e-4;
+    }
+}
+
+/// Iterates the lanes set in `mask`.
+#[inline]
+pub fn lanes(mask: u32) -> impl Iterator<Item = usize> {
+    (0..MAX_PACKET_SIZE).filter(move |lane| mask & (1 << lane) != 0)
+}
+
+/// The closest hit found so far for each ray of a packet, with the primitive hit as returned
+/// by `Hitable::ray_pick`.
+pub struct PacketHits<'s, 'a> {
+    /// The distance of each ray's closest hit, hits further away are ignored.
+    pub t_max: [f32; MAX_PACKET_SIZE],
+    pub hits: [Option<(RayHit, &'s Material<'s>, Hitable<'a>)>; MAX_PACKET_SIZE],
+}
+
+impl<'s, 'a> PacketHits<'s, 'a> {
+    pub fn new(t_max: f32) -> PacketHits<'s, 'a> {
+        PacketHits {
+            t_max: [t_max; MAX_PACKET_SIZE],
+            hits: [None; MAX_PACKET_SIZE],
+        }
+    }
+
+    /// Records a hit closer than any found for `lane` so far.
+    #[inline]
+    pub fn record(&mut self, lane: usize, hit: (RayHit, &'s Material<'s>, Hitable<'a>)) {
+        self.t_max[lane] = hit.0.t;
+        self.hits[lane] = Some(hit);
+    }
+}
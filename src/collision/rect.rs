@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 use crate::collision::{Ray, RayHit, AABB};
-use glam::vec3;
+use glam::{vec3, Vec3};
 
 #[derive(Copy, Clone, Debug)]
 pub enum Rect {
@@ -69,6 +69,32 @@ impl Rect {
         }
     }
 
+    #[inline]
+    pub fn area(&self) -> f32 {
+        match *self {
+            Rect::XY { x0, x1, y0, y1, .. } => (x1 - x0) * (y1 - y0),
+            Rect::XZ { x0, x1, z0, z1, .. } => (x1 - x0) * (z1 - z0),
+            Rect::YZ { y0, y1, z0, z1, .. } => (y1 - y0) * (z1 - z0),
+        }
+    }
+
+    /// Maps a uniformly distributed sample in the unit square to a uniformly distributed point
+    /// on the rect.
+    #[inline]
+    pub fn sample_point(&self, (a, b): (f32, f32)) -> Vec3 {
+        match *self {
+            Rect::XY {
+                x0, x1, y0, y1, k, ..
+            } => vec3(x0 + a * (x1 - x0), y0 + b * (y1 - y0), k),
+            Rect::XZ {
+                x0, x1, z0, z1, k, ..
+            } => vec3(x0 + a * (x1 - x0), k, z0 + b * (z1 - z0)),
+            Rect::YZ {
+                y0, y1, z0, z1, k, ..
+            } => vec3(k, y0 + a * (y1 - y0), z0 + b * (z1 - z0)),
+        }
+    }
+
     #[inline]
     fn xy_ray_hit(
         ray: &Ray,
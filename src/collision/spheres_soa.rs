@@ -1,13 +1,18 @@
 #![allow(dead_code)]
 use crate::{
-    collision::{Hitable, Ray, RayHit, AABB},
+    collision::{lanes, Hitable, PacketHits, Ray, RayHit, RayPacket, AABB, MAX_PACKET_SIZE},
     material::Material,
     math::align_to,
     simd::*,
+    storage::Storage,
 };
 use glam::{vec3, Vec3, Vec3A};
 use std::f32;
 
+/// Spheres stored as a structure of arrays so a ray can be tested against several of them at
+/// once with SSE4.1 or AVX2, whichever the CPU supports.
+///
+/// The arrays are padded to a multiple of the SIMD width with spheres no ray can hit.
 #[derive(Debug)]
 pub struct SpheresSoA<'a> {
     bounds: AABB,
@@ -18,6 +23,8 @@ pub struct SpheresSoA<'a> {
     radius_sq: Vec<f32>,
     radius_inv: Vec<f32>,
     material: Vec<Option<&'a Material<'a>>>,
+    /// The packed `Hitable::Sphere`s, returned by `ray_pick`.
+    hitables: Vec<Hitable<'a>>,
     len: usize,
     num_spheres: usize,
 }
@@ -25,10 +32,8 @@ pub struct SpheresSoA<'a> {
 impl<'a> SpheresSoA<'a> {
     pub fn new(hitables: &[Hitable<'a>]) -> SpheresSoA<'a> {
         let feature = TargetFeature::detect();
-        feature.print_version();
-
         let mut bounds = AABB::invalid();
-        let chunk_size = TargetFeature::detect().get_bits() / 32;
+        let chunk_size = feature.get_bits() / 32;
         let num_spheres = hitables.len();
         let len = align_to(num_spheres, chunk_size);
         let mut centre_x = Vec::with_capacity(len);
@@ -68,16 +73,49 @@ impl<'a> SpheresSoA<'a> {
             radius_sq,
             radius_inv,
             material,
+            hitables: hitables.to_vec(),
             len,
             num_spheres,
         }
     }
 
+    /// Packs the spheres in `hitables` into a `SpheresSoA` and returns it followed by the other
+    /// hitables. Fewer than two spheres aren't worth packing and are returned unchanged.
+    pub fn pack(hitables: &[Hitable<'a>], storage: &'a Storage<'a>) -> Vec<Hitable<'a>> {
+        let (spheres, mut others): (Vec<Hitable>, Vec<Hitable>) = hitables
+            .iter()
+            .partition(|hitable| matches!(hitable, Hitable::Sphere(_, _)));
+        if spheres.len() < 2 {
+            return hitables.to_vec();
+        }
+        others.insert(
+            0,
+            Hitable::Spheres(storage.alloc_spheres_soa(SpheresSoA::new(&spheres))),
+        );
+        others
+    }
+
+    #[inline]
+    pub fn bounding_box(&self) -> AABB {
+        self.bounds
+    }
+
     #[inline]
     pub fn in_bounds(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
         self.bounds.ray_hit(ray, t_min, t_max)
     }
 
+    /// The packed spheres, in the order they were given to `new`.
+    #[inline]
+    pub fn hitables(&self) -> &[Hitable<'a>] {
+        &self.hitables
+    }
+
+    #[inline]
+    pub fn num_spheres(&self) -> usize {
+        self.num_spheres
+    }
+
     pub fn centre(&self, index: u32) -> Vec3 {
         let index = index as usize;
         assert!(index < self.len);
@@ -94,7 +132,72 @@ impl<'a> SpheresSoA<'a> {
         self.radius_sq[index as usize]
     }
 
-    pub fn ray_hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(RayHit, &Material)> {
+    pub fn ray_hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(RayHit, &Material<'_>)> {
+        self.nearest(ray, t_min, t_max)
+            .map(|(index, t)| self.hit_at(ray, index, t))
+    }
+
+    /// Like `ray_hit` but also returns the `Hitable::Sphere` that was hit.
+    pub fn ray_pick(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(RayHit, &Material<'_>, Hitable<'a>)> {
+        self.nearest(ray, t_min, t_max).map(|(index, t)| {
+            let (ray_hit, material) = self.hit_at(ray, index, t);
+            (ray_hit, material, self.hitables[index])
+        })
+    }
+
+    /// Like `ray_pick` for the rays of `packet` in `mask`, each sphere is tested against all of
+    /// them at once. Hits are recorded in `hits` if they are closer than those already found.
+    pub fn ray_pick_packet<'s>(
+        &'s self,
+        packet: &RayPacket,
+        t_min: f32,
+        mask: u32,
+        hits: &mut PacketHits<'s, 'a>,
+    ) {
+        if !packet.unit_directions() {
+            for lane in lanes(mask) {
+                if let Some(hit) = self.ray_pick(&packet.rays[lane], t_min, hits.t_max[lane]) {
+                    hits.record(lane, hit);
+                }
+            }
+            return;
+        }
+        let mut hit_t = hits.t_max;
+        let mut hit_index = [-1; MAX_PACKET_SIZE];
+        // packets are only as wide as the CPU supports
+        match packet.size() {
+            MAX_PACKET_SIZE => unsafe {
+                self.hit_packet_avx2(packet, t_min, &mut hit_t, &mut hit_index)
+            },
+            _ => unsafe { self.hit_packet_sse4_1(packet, t_min, &mut hit_t, &mut hit_index) },
+        }
+        for lane in lanes(mask) {
+            if hit_index[lane] >= 0 {
+                let index = hit_index[lane] as usize;
+                let (ray_hit, material) = self.hit_at(&packet.rays[lane], index, hit_t[lane]);
+                hits.record(lane, (ray_hit, material, self.hitables[index]));
+            }
+        }
+    }
+
+    /// Returns the index and distance of the nearest sphere hit by `ray`.
+    #[inline]
+    fn nearest(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(usize, f32)> {
+        // the intersection tests assume a unit length direction, which rays transformed into
+        // a scaled instance's space don't have
+        let length_sq = ray.direction.length_squared();
+        if (length_sq - 1.0).abs() > 1e-4 {
+            let length = length_sq.sqrt();
+            let unit_ray = Ray::new(ray.origin, ray.direction / length, ray.time);
+            return self
+                .nearest(&unit_ray, t_min * length, t_max * length)
+                .map(|(index, t)| (index, t / length));
+        }
         match self.feature {
             TargetFeature::AVX2 => unsafe { self.hit_avx2(ray, t_min, t_max) },
             TargetFeature::SSE4_1 => unsafe { self.hit_sse4_1(ray, t_min, t_max) },
@@ -102,7 +205,29 @@ impl<'a> SpheresSoA<'a> {
         }
     }
 
-    pub fn hit_scalar(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(RayHit, &Material)> {
+    fn hit_at(&self, ray: &Ray, index: usize, t: f32) -> (RayHit, &Material<'_>) {
+        let point = ray.point_at_parameter(t);
+        let centre = vec3(
+            self.centre_x[index],
+            self.centre_y[index],
+            self.centre_z[index],
+        );
+        let normal = (point - centre) * self.radius_inv[index];
+        let material = self.material[index].unwrap();
+        let (u, v) = material.get_sphere_uv(normal);
+        (
+            RayHit {
+                point,
+                normal,
+                t,
+                u,
+                v,
+            },
+            material,
+        )
+    }
+
+    pub fn hit_scalar(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(usize, f32)> {
         let mut hit_t = t_max;
         let mut hit_index = self.len;
         for ((((index, centre_x), centre_y), centre_z), radius_sq) in self
@@ -130,40 +255,134 @@ impl<'a> SpheresSoA<'a> {
             }
         }
         if hit_index < self.len {
-            let point = ray.point_at_parameter(hit_t);
-            let centre = vec3(
-                self.centre_x[hit_index],
-                self.centre_y[hit_index],
-                self.centre_z[hit_index],
-            );
-            let normal = (point - centre) * self.radius_inv[hit_index];
-            let material = self.material[hit_index].unwrap();
-            let (u, v) = material.get_sphere_uv(normal);
-            Some((
-                RayHit {
-                    point,
-                    normal,
-                    t: hit_t,
-                    u,
-                    v,
-                },
-                material,
-            ))
+            Some((hit_index, hit_t))
         } else {
             None
         }
     }
 
+    /// Tests each sphere against the first 4 rays of `packet`, updating `hit_t` and `hit_index`
+    /// for the rays that hit it closer.
     #[cfg_attr(
         any(target_arch = "x86", target_arch = "x86_64"),
         target_feature(enable = "sse4.1")
     )]
-    pub unsafe fn hit_sse4_1(
+    unsafe fn hit_packet_sse4_1(
         &self,
-        ray: &Ray,
+        packet: &RayPacket,
         t_min: f32,
-        t_max: f32,
-    ) -> Option<(RayHit, &Material)> {
+        hit_t_out: &mut [f32; MAX_PACKET_SIZE],
+        hit_index_out: &mut [i32; MAX_PACKET_SIZE],
+    ) {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::*;
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::*;
+        let t_min = _mm_set_ps1(t_min);
+        let mut hit_t = _mm_loadu_ps(hit_t_out.as_ptr());
+        let mut hit_index = _mm_set1_epi32(-1);
+        let ro_x = _mm_loadu_ps(packet.origin[0].as_ptr());
+        let ro_y = _mm_loadu_ps(packet.origin[1].as_ptr());
+        let ro_z = _mm_loadu_ps(packet.origin[2].as_ptr());
+        let rd_x = _mm_loadu_ps(packet.direction[0].as_ptr());
+        let rd_y = _mm_loadu_ps(packet.direction[1].as_ptr());
+        let rd_z = _mm_loadu_ps(packet.direction[2].as_ptr());
+        // the same sums as `hit_sse4_1` so a ray hits the same spheres in or out of a packet
+        for index in 0..self.num_spheres {
+            let co_x = _mm_sub_ps(_mm_set1_ps(*self.centre_x.get_unchecked(index)), ro_x);
+            let co_y = _mm_sub_ps(_mm_set1_ps(*self.centre_y.get_unchecked(index)), ro_y);
+            let co_z = _mm_sub_ps(_mm_set1_ps(*self.centre_z.get_unchecked(index)), ro_z);
+            let nb = dot3_sse2(co_x, rd_x, co_y, rd_y, co_z, rd_z);
+            let r_sq = _mm_set1_ps(*self.radius_sq.get_unchecked(index));
+            let c = _mm_sub_ps(dot3_sse2(co_x, co_x, co_y, co_y, co_z, co_z), r_sq);
+            let discr = _mm_sub_ps(_mm_mul_ps(nb, nb), c);
+            let pos_discr = _mm_cmpgt_ps(discr, _mm_set_ps1(0.0));
+            if _mm_movemask_ps(pos_discr) != 0 {
+                let discr_sqrt = _mm_sqrt_ps(discr);
+                let t0 = _mm_sub_ps(nb, discr_sqrt);
+                let t1 = _mm_add_ps(nb, discr_sqrt);
+                let t = _mm_blendv_ps(t1, t0, _mm_cmpgt_ps(t0, t_min));
+                let mask = _mm_and_ps(
+                    pos_discr,
+                    _mm_and_ps(_mm_cmpgt_ps(t, t_min), _mm_cmplt_ps(t, hit_t)),
+                );
+                hit_index = _mm_blendv_epi8(
+                    hit_index,
+                    _mm_set1_epi32(index as i32),
+                    _mm_castps_si128(mask),
+                );
+                hit_t = _mm_blendv_ps(hit_t, t, mask);
+            }
+        }
+        _mm_storeu_ps(hit_t_out.as_mut_ptr(), hit_t);
+        _mm_storeu_si128(hit_index_out.as_mut_ptr() as *mut __m128i, hit_index);
+    }
+
+    /// Tests each sphere against all 8 rays of `packet`, updating `hit_t` and `hit_index` for
+    /// the rays that hit it closer.
+    #[cfg_attr(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature(enable = "avx2")
+    )]
+    unsafe fn hit_packet_avx2(
+        &self,
+        packet: &RayPacket,
+        t_min: f32,
+        hit_t_out: &mut [f32; MAX_PACKET_SIZE],
+        hit_index_out: &mut [i32; MAX_PACKET_SIZE],
+    ) {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::*;
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::*;
+        let t_min = _mm256_set1_ps(t_min);
+        let mut hit_t = _mm256_loadu_ps(hit_t_out.as_ptr());
+        let mut hit_index = _mm256_set1_epi32(-1);
+        let ro_x = _mm256_loadu_ps(packet.origin[0].as_ptr());
+        let ro_y = _mm256_loadu_ps(packet.origin[1].as_ptr());
+        let ro_z = _mm256_loadu_ps(packet.origin[2].as_ptr());
+        let rd_x = _mm256_loadu_ps(packet.direction[0].as_ptr());
+        let rd_y = _mm256_loadu_ps(packet.direction[1].as_ptr());
+        let rd_z = _mm256_loadu_ps(packet.direction[2].as_ptr());
+        // the same sums as `hit_avx2` so a ray hits the same spheres in or out of a packet
+        for index in 0..self.num_spheres {
+            let co_x = _mm256_sub_ps(_mm256_set1_ps(*self.centre_x.get_unchecked(index)), ro_x);
+            let co_y = _mm256_sub_ps(_mm256_set1_ps(*self.centre_y.get_unchecked(index)), ro_y);
+            let co_z = _mm256_sub_ps(_mm256_set1_ps(*self.centre_z.get_unchecked(index)), ro_z);
+            let nb = dot3_avx2(co_x, rd_x, co_y, rd_y, co_z, rd_z);
+            let r_sq = _mm256_set1_ps(*self.radius_sq.get_unchecked(index));
+            let c = _mm256_sub_ps(dot3_avx2(co_x, co_x, co_y, co_y, co_z, co_z), r_sq);
+            let discr = _mm256_sub_ps(_mm256_mul_ps(nb, nb), c);
+            let pos_discr = _mm256_cmp_ps(discr, _mm256_set1_ps(0.0), _CMP_GT_OQ);
+            if _mm256_movemask_ps(pos_discr) != 0 {
+                let discr_sqrt = _mm256_sqrt_ps(discr);
+                let t0 = _mm256_sub_ps(nb, discr_sqrt);
+                let t1 = _mm256_add_ps(nb, discr_sqrt);
+                let t = _mm256_blendv_ps(t1, t0, _mm256_cmp_ps(t0, t_min, _CMP_GT_OQ));
+                let mask = _mm256_and_ps(
+                    pos_discr,
+                    _mm256_and_ps(
+                        _mm256_cmp_ps(t, t_min, _CMP_GT_OQ),
+                        _mm256_cmp_ps(t, hit_t, _CMP_LT_OQ),
+                    ),
+                );
+                hit_index = _mm256_blendv_epi8(
+                    hit_index,
+                    _mm256_set1_epi32(index as i32),
+                    _mm256_castps_si256(mask),
+                );
+                hit_t = _mm256_blendv_ps(hit_t, t, mask);
+            }
+        }
+        _mm256_storeu_ps(hit_t_out.as_mut_ptr(), hit_t);
+        _mm256_storeu_si256(hit_index_out.as_mut_ptr() as *mut __m256i, hit_index);
+    }
+
+    #[cfg_attr(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature(enable = "sse4.1")
+    )]
+    pub unsafe fn hit_sse4_1(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(usize, f32)> {
         #[cfg(target_arch = "x86")]
         use std::arch::x86::*;
         #[cfg(target_arch = "x86_64")]
@@ -243,25 +462,7 @@ impl<'a> SpheresSoA<'a> {
                 debug_assert!(hit_index_scalar < self.len);
                 let hit_t_scalar = *hit_t_array.get_unchecked(hit_t_lane);
 
-                let point = ray.point_at_parameter(hit_t_scalar);
-                let centre = vec3(
-                    *self.centre_x.get_unchecked(hit_index_scalar),
-                    *self.centre_y.get_unchecked(hit_index_scalar),
-                    *self.centre_z.get_unchecked(hit_index_scalar),
-                );
-                let normal = (point - centre) * *self.radius_inv.get_unchecked(hit_index_scalar);
-                let material = self.material.get_unchecked(hit_index_scalar).unwrap();
-                let (u, v) = material.get_sphere_uv(normal);
-                return Some((
-                    RayHit {
-                        point,
-                        normal,
-                        t: hit_t_scalar,
-                        u,
-                        v,
-                    },
-                    material,
-                ));
+                return Some((hit_index_scalar, hit_t_scalar));
             }
         }
         None
@@ -271,12 +472,7 @@ impl<'a> SpheresSoA<'a> {
         any(target_arch = "x86", target_arch = "x86_64"),
         target_feature(enable = "avx2")
     )]
-    pub unsafe fn hit_avx2(
-        &self,
-        ray: &Ray,
-        t_min: f32,
-        t_max: f32,
-    ) -> Option<(RayHit, &Material)> {
+    pub unsafe fn hit_avx2(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(usize, f32)> {
         #[cfg(target_arch = "x86")]
         use std::arch::x86::*;
         #[cfg(target_arch = "x86_64")]
@@ -366,25 +562,7 @@ impl<'a> SpheresSoA<'a> {
                 debug_assert!(hit_index_scalar < self.len);
                 let hit_t_scalar = *hit_t_array.get_unchecked(hit_t_lane);
 
-                let point = ray.point_at_parameter(hit_t_scalar);
-                let centre = vec3(
-                    *self.centre_x.get_unchecked(hit_index_scalar),
-                    *self.centre_y.get_unchecked(hit_index_scalar),
-                    *self.centre_z.get_unchecked(hit_index_scalar),
-                );
-                let normal = (point - centre) * *self.radius_inv.get_unchecked(hit_index_scalar);
-                let material = self.material.get_unchecked(hit_index_scalar).unwrap();
-                let (u, v) = material.get_sphere_uv(normal);
-                return Some((
-                    RayHit {
-                        point,
-                        normal,
-                        t: hit_t_scalar,
-                        u,
-                        v,
-                    },
-                    material,
-                ));
+                return Some((hit_index_scalar, hit_t_scalar));
             }
         }
         None
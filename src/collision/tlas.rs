@@ -0,0 +1,484 @@
+use super::bvh::{BuildPrimitive, INTERSECTION_COST, TRAVERSAL_COST};
+use crate::{
+    collision::{BVHBuilder, BVHNode, Hitable, Instance, Ray, RayHit, AABB},
+    material::Material,
+    sampler::Sampler,
+    storage::Storage,
+};
+use glam::Affine3A;
+use rand_xoshiro::Xoshiro256Plus;
+use std::collections::HashMap;
+
+// deep enough for all but badly skewed trees, which traverse with a heap allocated stack
+const MAX_STACK_DEPTH: usize = 64;
+
+// how many levels down a BLAS's BVH the node bounds are transformed to bound an instance
+const INSTANCE_BOUNDS_DEPTH: u32 = 3;
+
+// how much refitting may raise the SAH cost over that of the last build before `update`
+// rebuilds the top level instead
+const REBUILD_THRESHOLD: f32 = 1.5;
+
+/// A top level BVH node, laid out like a `LinearBVH` node. Leaves reference `count` instances
+/// starting at `offset`.
+#[derive(Copy, Clone, Debug)]
+#[repr(C, align(32))]
+struct TlasNode {
+    aabb: AABB,
+    offset: u32,
+    count: u16,
+    axis: u8,
+}
+
+/// A leaf of the top level, the BLAS at index `blas` placed in the scene by an instance.
+#[derive(Copy, Clone, Debug)]
+struct TlasInstance<'a> {
+    /// The scene's instance, hits are identified by it.
+    instance: &'a Instance<'a>,
+    /// A copy of `instance` with its current transform that hits are traced through.
+    placed: Instance<'a>,
+    blas: u32,
+    /// The index of the instance in the order they were given to `Tlas::new`.
+    index: u32,
+    aabb: AABB,
+}
+
+/// What `Tlas::update` did to the top level.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TlasUpdate {
+    Refitted,
+    Rebuilt,
+}
+
+/// A two level acceleration structure for scenes with instances.
+///
+/// Each unique instanced geometry has a single bottom level acceleration structure (BLAS),
+/// usually a BVH, that every instance of it shares. The top level is a BVH over the bounds of
+/// the instances and only stores a copy of each instance and the index of its BLAS, so
+/// thousands of copies of a mesh cost little more memory than one. The geometry that isn't
+/// instanced is gathered into one more BLAS which isn't transformed and is traced before the
+/// top level rather than being part of it, so large static geometry like a ground plane
+/// doesn't hide how well the top level fits the instances.
+///
+/// Instances can be moved with `set_transform`, after which `update` refits or rebuilds the
+/// top level. The BLASes are never changed.
+#[derive(Debug)]
+pub struct Tlas<'a> {
+    nodes: Vec<TlasNode>,
+    instances: Vec<TlasInstance<'a>>,
+    blases: Vec<Hitable<'a>>,
+    /// The BLAS of the hitables that aren't instanced.
+    stationary: Option<Hitable<'a>>,
+    /// The position in `instances` of each instance by its index.
+    slots: Vec<u32>,
+    builder: BVHBuilder,
+    /// The SAH cost of the top level when it was last built.
+    built_cost: f32,
+    /// The depth of the deepest top level leaf, which bounds the size of the traversal stack.
+    depth: usize,
+    time0: f32,
+    time1: f32,
+}
+
+impl<'a> Tlas<'a> {
+    /// Builds a TLAS over `hitables` with bounds swept over the shutter interval `t0` to `t1`.
+    ///
+    /// `build_blas` is called once for each unique geometry instanced by the `Instance`s in
+    /// `hitables`, and once for the rest of `hitables` together, to build its BLAS.
+    pub fn new<F>(
+        builder: BVHBuilder,
+        rng: &mut Xoshiro256Plus,
+        hitables: Vec<Hitable<'a>>,
+        t0: f32,
+        t1: f32,
+        storage: &'a Storage<'a>,
+        mut build_blas: F,
+    ) -> Tlas<'a>
+    where
+        F: FnMut(&mut Xoshiro256Plus, Hitable<'a>) -> Hitable<'a>,
+    {
+        let mut tlas = Tlas {
+            nodes: Vec::new(),
+            instances: Vec::new(),
+            blases: Vec::new(),
+            stationary: None,
+            slots: Vec::new(),
+            builder,
+            built_cost: 0.0,
+            depth: 0,
+            time0: t0,
+            time1: t1,
+        };
+        let mut blas_indices = HashMap::new();
+        let mut rest = Vec::new();
+        for hitable in hitables {
+            if let Hitable::Instance(instance) = hitable {
+                let geometry = instance.hitable();
+                let blases = &mut tlas.blases;
+                let blas = *blas_indices.entry(geometry.address()).or_insert_with(|| {
+                    blases.push(build_blas(rng, geometry));
+                    blases.len() as u32 - 1
+                });
+                let index = tlas.slots.len() as u32;
+                tlas.slots.push(index);
+                tlas.instances.push(TlasInstance {
+                    instance,
+                    placed: *instance,
+                    blas,
+                    index,
+                    aabb: tlas.instance_bounds(instance),
+                });
+            } else {
+                rest.push(hitable);
+            }
+        }
+        // the rest is passed as a list even if it's a single hitable so it's always built into
+        // a BVH
+        if !rest.is_empty() {
+            let blas = build_blas(rng, Hitable::List(storage.alloc_hitables(rest)));
+            tlas.stationary = Some(blas);
+        }
+        assert!(!tlas.instances.is_empty(), "empty TLAS");
+        tlas.rebuild();
+        tlas
+    }
+
+    #[inline]
+    pub fn bounding_box(&self) -> AABB {
+        match &self.stationary {
+            Some(stationary) => self.nodes[0]
+                .aabb
+                .add(&stationary.bounding_box(self.time0, self.time1).unwrap()),
+            None => self.nodes[0].aabb,
+        }
+    }
+
+    #[inline]
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    #[inline]
+    pub fn num_instances(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// The instanced geometry's BLASes followed by that of the hitables that aren't instanced.
+    pub fn blases(&self) -> impl Iterator<Item = Hitable<'a>> + '_ {
+        self.blases.iter().copied().chain(self.stationary)
+    }
+
+    /// The scene's instance at `index`, its transform is the one it was built with rather
+    /// than any set since.
+    #[inline]
+    pub fn instance(&self, index: usize) -> &'a Instance<'a> {
+        self.instances[self.slots[index] as usize].instance
+    }
+
+    /// Each instance with its original geometry followed by the BLAS of the geometry that
+    /// isn't instanced.
+    pub fn hitables(&self) -> impl Iterator<Item = Hitable<'a>> + '_ {
+        self.instances
+            .iter()
+            .map(|leaf| Hitable::Instance(leaf.instance))
+            .chain(self.stationary)
+    }
+
+    /// Moves the instance at `index`. The top level's bounds aren't updated until `update`,
+    /// `refit` or `rebuild` is called.
+    pub fn set_transform(&mut self, index: usize, transform: Affine3A) {
+        let slot = self.slots[index] as usize;
+        let placed = Instance::new(self.instances[slot].instance.hitable(), transform);
+        self.instances[slot].aabb = self.instance_bounds(&placed);
+        self.instances[slot].placed = placed;
+    }
+
+    /// Refits the top level to the instances' current bounds, or rebuilds it if that would
+    /// raise its SAH cost by more than `REBUILD_THRESHOLD` times its cost when last built.
+    pub fn update(&mut self) -> TlasUpdate {
+        self.refit();
+        if self.sah_cost() > self.built_cost * REBUILD_THRESHOLD {
+            self.rebuild();
+            TlasUpdate::Rebuilt
+        } else {
+            TlasUpdate::Refitted
+        }
+    }
+
+    /// Updates the bounds of every node bottom up, keeping the tree as it is. This is fast but
+    /// the tree gets worse as the instances move away from where it was built.
+    pub fn refit(&mut self) {
+        // children always follow their parent so a reverse pass visits them first
+        for index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[index];
+            self.nodes[index].aabb = if node.count > 0 {
+                let start = node.offset as usize;
+                let end = start + node.count as usize;
+                self.instances[start..end]
+                    .iter()
+                    .fold(AABB::invalid(), |aabb, leaf| aabb.add(&leaf.aabb))
+            } else {
+                self.nodes[index + 1]
+                    .aabb
+                    .add(&self.nodes[node.offset as usize].aabb)
+            };
+        }
+    }
+
+    /// Builds the top level from scratch over the instances' current bounds.
+    pub fn rebuild(&mut self) {
+        let mut primitives: Vec<BuildPrimitive<TlasInstance<'a>>> = self
+            .instances
+            .drain(..)
+            .map(|leaf| BuildPrimitive {
+                item: leaf,
+                aabb: leaf.aabb,
+                centroid: leaf.aabb.centroid(),
+            })
+            .collect();
+        self.nodes.clear();
+        self.depth = self.build_node(&mut primitives, 0);
+        for (slot, leaf) in self.instances.iter().enumerate() {
+            self.slots[leaf.index as usize] = slot as u32;
+        }
+        self.built_cost = self.sah_cost();
+    }
+
+    /// Appends a node over `primitives` and its descendants to the node array. Returns the
+    /// subtree depth.
+    fn build_node(
+        &mut self,
+        primitives: &mut [BuildPrimitive<TlasInstance<'a>>],
+        depth: usize,
+    ) -> usize {
+        let index = self.nodes.len();
+        let mut aabb = AABB::invalid();
+        let mut centroid_bounds = AABB::invalid();
+        for primitive in primitives.iter() {
+            aabb.add_assign(&primitive.aabb);
+            centroid_bounds.add_assign(&AABB::new(primitive.centroid, primitive.centroid));
+        }
+        let pivot = match self.builder {
+            _ if primitives.len() == 1 => None,
+            BVHBuilder::Sah => BVHNode::sah_split(primitives, &aabb, &centroid_bounds),
+            // there is nothing to gain from random splits of the instances
+            _ => Some(BVHNode::median_split(primitives, &centroid_bounds)),
+        };
+        let pivot = match pivot {
+            Some(pivot) => pivot,
+            None => {
+                assert!(primitives.len() <= u16::MAX as usize);
+                self.nodes.push(TlasNode {
+                    aabb,
+                    offset: self.instances.len() as u32,
+                    count: primitives.len() as u16,
+                    axis: 0,
+                });
+                self.instances
+                    .extend(primitives.iter().map(|primitive| primitive.item));
+                return depth;
+            }
+        };
+
+        // order children by the axis their centres are furthest apart on so the traversal
+        // can visit the nearest first, the first child is the one on the low side of it
+        let (lhs, rhs) = primitives.split_at_mut(pivot);
+        let bounds = |primitives: &[BuildPrimitive<TlasInstance>]| {
+            primitives
+                .iter()
+                .fold(AABB::invalid(), |aabb, primitive| aabb.add(&primitive.aabb))
+        };
+        let lhs_centroid = bounds(lhs).centroid();
+        let rhs_centroid = bounds(rhs).centroid();
+        let delta = (rhs_centroid - lhs_centroid).abs();
+        let axis = if delta.x >= delta.y && delta.x >= delta.z {
+            0
+        } else if delta.y >= delta.z {
+            1
+        } else {
+            2
+        };
+        let (lhs, rhs) = if rhs_centroid[axis] < lhs_centroid[axis] {
+            (rhs, lhs)
+        } else {
+            (lhs, rhs)
+        };
+        self.nodes.push(TlasNode {
+            aabb,
+            offset: 0,
+            count: 0,
+            axis: axis as u8,
+        });
+        let lhs_depth = self.build_node(lhs, depth + 1);
+        self.nodes[index].offset = self.nodes.len() as u32;
+        let rhs_depth = self.build_node(rhs, depth + 1);
+        lhs_depth.max(rhs_depth)
+    }
+
+    /// Expected cost of tracing a ray that hits the top level's root node, the sum over all nodes of the
+    /// probability of visiting them times their traversal and intersection costs.
+    pub fn sah_cost(&self) -> f32 {
+        let root_area = self.nodes[0].aabb.surface_area();
+        self.nodes
+            .iter()
+            .map(|node| {
+                let cost = if node.count > 0 {
+                    INTERSECTION_COST * node.count as f32
+                } else {
+                    TRAVERSAL_COST
+                };
+                if root_area > 0.0 {
+                    node.aabb.surface_area() / root_area * cost
+                } else {
+                    cost
+                }
+            })
+            .sum()
+    }
+
+    /// The bounds of `instance`. The bounds of its geometry's BVH nodes a few levels down are
+    /// transformed rather than its root's, which can be far larger than the geometry when the
+    /// transform rotates it.
+    fn instance_bounds(&self, instance: &Instance) -> AABB {
+        Tlas::transformed_bounds(
+            &instance.hitable(),
+            instance.transform(),
+            INSTANCE_BOUNDS_DEPTH,
+            self.time0,
+            self.time1,
+        )
+    }
+
+    fn transformed_bounds(
+        geometry: &Hitable,
+        transform: &Affine3A,
+        depth: u32,
+        t0: f32,
+        t1: f32,
+    ) -> AABB {
+        match geometry {
+            Hitable::BVHNode(node) if depth > 0 => {
+                let (lhs, rhs) = node.children();
+                let aabb = Tlas::transformed_bounds(&lhs, transform, depth - 1, t0, t1);
+                if lhs.ptr_eq(&rhs) {
+                    aabb
+                } else {
+                    aabb.add(&Tlas::transformed_bounds(
+                        &rhs,
+                        transform,
+                        depth - 1,
+                        t0,
+                        t1,
+                    ))
+                }
+            }
+            _ => geometry.bounding_box(t0, t1).unwrap().transform(transform),
+        }
+    }
+
+    pub fn ray_hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(RayHit, &Material<'_>)> {
+        let stationary_hit = self
+            .stationary
+            .as_ref()
+            .and_then(|blas| blas.ray_hit(ray, t_min, t_max, sampler));
+        let t_max = stationary_hit.map_or(t_max, |(ray_hit, _)| ray_hit.t);
+        self.traverse(ray, t_min, t_max, |leaf, closest_so_far| {
+            let blas = &self.blases[leaf.blas as usize];
+            let hit = leaf
+                .placed
+                .ray_hit_blas(blas, ray, t_min, closest_so_far, sampler)?;
+            Some((hit, hit.0.t))
+        })
+        .or(stationary_hit)
+    }
+
+    /// Like `ray_hit` but also returns the primitive that was hit, instanced geometry returns
+    /// its instance so each copy has its own id.
+    pub fn ray_pick(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(RayHit, &Material<'_>, Hitable<'a>)> {
+        let stationary_hit = self
+            .stationary
+            .as_ref()
+            .and_then(|blas| blas.ray_pick(ray, t_min, t_max, sampler));
+        let t_max = stationary_hit.map_or(t_max, |(ray_hit, _, _)| ray_hit.t);
+        self.traverse(ray, t_min, t_max, |leaf, closest_so_far| {
+            let blas = &self.blases[leaf.blas as usize];
+            let (ray_hit, material) =
+                leaf.placed
+                    .ray_hit_blas(blas, ray, t_min, closest_so_far, sampler)?;
+            Some((
+                (ray_hit, material, Hitable::Instance(leaf.instance)),
+                ray_hit.t,
+            ))
+        })
+        .or(stationary_hit)
+    }
+
+    /// Visits the instances `ray` may hit nearest first, `leaf_hit` is called for each of them
+    /// with the closest hit distance so far and returns a hit and its distance.
+    #[inline]
+    fn traverse<'s, T, F>(&'s self, ray: &Ray, t_min: f32, t_max: f32, mut leaf_hit: F) -> Option<T>
+    where
+        F: FnMut(&'s TlasInstance<'a>, f32) -> Option<(T, f32)>,
+    {
+        let dir_is_neg = [
+            ray.direction.x < 0.0,
+            ray.direction.y < 0.0,
+            ray.direction.z < 0.0,
+        ];
+        let mut fixed_stack = [0u32; MAX_STACK_DEPTH];
+        let mut deep_stack;
+        let stack: &mut [u32] = if self.depth < MAX_STACK_DEPTH {
+            &mut fixed_stack
+        } else {
+            deep_stack = vec![0u32; self.depth + 1];
+            &mut deep_stack
+        };
+        let mut stack_len = 0;
+        let mut index = 0;
+        let mut result = None;
+        let mut closest_so_far = t_max;
+        loop {
+            let node = &self.nodes[index];
+            if node.aabb.ray_hit(ray, t_min, closest_so_far) {
+                if node.count > 0 {
+                    let start = node.offset as usize;
+                    let end = start + node.count as usize;
+                    for leaf in &self.instances[start..end] {
+                        if let Some((hit, t)) = leaf_hit(leaf, closest_so_far) {
+                            closest_so_far = t;
+                            result = Some(hit);
+                        }
+                    }
+                } else {
+                    let (near, far) = if dir_is_neg[node.axis as usize] {
+                        (node.offset, index as u32 + 1)
+                    } else {
+                        (index as u32 + 1, node.offset)
+                    };
+                    stack[stack_len] = far;
+                    stack_len += 1;
+                    index = near as usize;
+                    continue;
+                }
+            }
+            if stack_len == 0 {
+                break;
+            }
+            stack_len -= 1;
+            index = stack[stack_len] as usize;
+        }
+        result
+    }
+}
@@ -0,0 +1,292 @@
+use crate::{
+    collision::{Hitable, Ray, RayHit, AABB},
+    material::Material,
+};
+use glam::{Vec2, Vec3};
+use std::f32;
+
+const EPSILON: f32 = 1e-8;
+
+/// Möller–Trumbore ray/triangle test returning `(t, b1, b2)` where `b1` and `b2` are the
+/// barycentric weights of the second and third vertex.
+#[inline]
+fn ray_hit_triangle(
+    ray: &Ray,
+    t_min: f32,
+    t_max: f32,
+    v0: Vec3,
+    edge1: Vec3,
+    edge2: Vec3,
+) -> Option<(f32, f32, f32)> {
+    let pvec = ray.direction.cross(edge2);
+    let det = edge1.dot(pvec);
+    if det.abs() < EPSILON {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let tvec = ray.origin - v0;
+    let b1 = tvec.dot(pvec) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let qvec = tvec.cross(edge1);
+    let b2 = ray.direction.dot(qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+    let t = edge2.dot(qvec) * inv_det;
+    if t < t_max && t > t_min {
+        Some((t, b1, b2))
+    } else {
+        None
+    }
+}
+
+#[inline]
+fn triangle_bounding_box(v0: Vec3, v1: Vec3, v2: Vec3) -> AABB {
+    // pad the box so axis aligned triangles don't produce a degenerate AABB
+    let padding = Vec3::splat(0.0001);
+    AABB {
+        min: v0.min(v1).min(v2) - padding,
+        max: v0.max(v1).max(v2) + padding,
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Triangle {
+    v0: Vec3,
+    edge1: Vec3,
+    edge2: Vec3,
+    normal: Vec3,
+}
+
+impl Triangle {
+    #[inline]
+    pub fn new(v0: Vec3, v1: Vec3, v2: Vec3) -> Triangle {
+        let edge1 = v1 - v0;
+        let edge2 = v2 - v0;
+        Triangle {
+            v0,
+            edge1,
+            edge2,
+            normal: edge1.cross(edge2).normalize(),
+        }
+    }
+
+    #[inline]
+    pub fn vertices(&self) -> [Vec3; 3] {
+        [self.v0, self.v0 + self.edge1, self.v0 + self.edge2]
+    }
+
+    #[inline]
+    pub fn ray_hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<RayHit> {
+        ray_hit_triangle(ray, t_min, t_max, self.v0, self.edge1, self.edge2).map(|(t, b1, b2)| {
+            RayHit {
+                point: ray.point_at_parameter(t),
+                normal: self.normal,
+                t,
+                u: b1,
+                v: b2,
+            }
+        })
+    }
+
+    #[inline]
+    pub fn bounding_box(&self) -> AABB {
+        let [v0, v1, v2] = self.vertices();
+        triangle_bounding_box(v0, v1, v2)
+    }
+}
+
+/// An indexed triangle mesh with shared vertex attributes.
+///
+/// `normals` and `uvs` are optional, when present they must be the same length as `positions`
+/// and are indexed by the same face indices. Each face has its own material.
+#[derive(Debug)]
+pub struct TriangleMesh<'a> {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<Vec2>,
+    indices: Vec<[u32; 3]>,
+    materials: Vec<&'a Material<'a>>,
+    aabb: AABB,
+}
+
+impl<'a> TriangleMesh<'a> {
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<Vec2>,
+        indices: Vec<[u32; 3]>,
+        materials: Vec<&'a Material<'a>>,
+    ) -> TriangleMesh<'a> {
+        assert!(normals.is_empty() || normals.len() == positions.len());
+        assert!(uvs.is_empty() || uvs.len() == positions.len());
+        assert_eq!(indices.len(), materials.len());
+        let mut aabb = AABB::invalid();
+        for face in &indices {
+            for &index in face {
+                let p = positions[index as usize];
+                aabb.min = aabb.min.min(p);
+                aabb.max = aabb.max.max(p);
+            }
+        }
+        let padding = Vec3::splat(0.0001);
+        aabb.min -= padding;
+        aabb.max += padding;
+        TriangleMesh {
+            positions,
+            normals,
+            uvs,
+            indices,
+            materials,
+            aabb,
+        }
+    }
+
+    /// Creates a mesh where every face shares the same material.
+    pub fn with_material(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<Vec2>,
+        indices: Vec<[u32; 3]>,
+        material: &'a Material<'a>,
+    ) -> TriangleMesh<'a> {
+        let materials = vec![material; indices.len()];
+        TriangleMesh::new(positions, normals, uvs, indices, materials)
+    }
+
+    #[inline]
+    pub fn num_faces(&self) -> usize {
+        self.indices.len()
+    }
+
+    #[inline]
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+
+    #[inline]
+    pub fn normals(&self) -> &[Vec3] {
+        &self.normals
+    }
+
+    #[inline]
+    pub fn uvs(&self) -> &[Vec2] {
+        &self.uvs
+    }
+
+    #[inline]
+    pub fn indices(&self) -> &[[u32; 3]] {
+        &self.indices
+    }
+
+    #[inline]
+    pub fn materials(&self) -> &[&'a Material<'a>] {
+        &self.materials
+    }
+
+    /// Returns one `Hitable::MeshFace` per face so the faces can be inserted into a BVH
+    /// individually instead of treating the mesh as a single leaf.
+    pub fn faces(&'a self) -> Vec<Hitable<'a>> {
+        (0..self.indices.len() as u32)
+            .map(|face| Hitable::MeshFace(self, face))
+            .collect()
+    }
+
+    #[inline]
+    fn face_vertices(&self, face: u32) -> (Vec3, Vec3, Vec3) {
+        let [i0, i1, i2] = self.indices[face as usize];
+        (
+            self.positions[i0 as usize],
+            self.positions[i1 as usize],
+            self.positions[i2 as usize],
+        )
+    }
+
+    #[inline]
+    pub fn face_bounding_box(&self, face: u32) -> AABB {
+        let (v0, v1, v2) = self.face_vertices(face);
+        triangle_bounding_box(v0, v1, v2)
+    }
+
+    #[inline]
+    pub fn bounding_box(&self) -> AABB {
+        self.aabb
+    }
+
+    #[inline]
+    fn face_ray_hit_t(
+        &self,
+        face: u32,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, f32, f32)> {
+        let (v0, v1, v2) = self.face_vertices(face);
+        ray_hit_triangle(ray, t_min, t_max, v0, v1 - v0, v2 - v0)
+    }
+
+    fn face_ray_hit_result(&self, face: u32, ray: &Ray, t: f32, b1: f32, b2: f32) -> RayHit {
+        let [i0, i1, i2] = self.indices[face as usize];
+        let (i0, i1, i2) = (i0 as usize, i1 as usize, i2 as usize);
+        let b0 = 1.0 - b1 - b2;
+        let normal = if self.normals.is_empty() {
+            let (v0, v1, v2) = self.face_vertices(face);
+            (v1 - v0).cross(v2 - v0).normalize()
+        } else {
+            (b0 * self.normals[i0] + b1 * self.normals[i1] + b2 * self.normals[i2]).normalize()
+        };
+        let (u, v) = if self.uvs.is_empty() {
+            (b1, b2)
+        } else {
+            (b0 * self.uvs[i0] + b1 * self.uvs[i1] + b2 * self.uvs[i2]).into()
+        };
+        RayHit {
+            point: ray.point_at_parameter(t),
+            normal,
+            t,
+            u,
+            v,
+        }
+    }
+
+    #[inline]
+    pub fn face_ray_hit(
+        &self,
+        face: u32,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(RayHit, &Material<'_>)> {
+        self.face_ray_hit_t(face, ray, t_min, t_max)
+            .map(|(t, b1, b2)| {
+                (
+                    self.face_ray_hit_result(face, ray, t, b1, b2),
+                    self.materials[face as usize],
+                )
+            })
+    }
+
+    /// Brute force test against every face, prefer building a BVH over `faces()` for anything
+    /// but small meshes.
+    pub fn ray_hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(RayHit, &Material<'_>)> {
+        if !self.aabb.ray_hit(ray, t_min, t_max) {
+            return None;
+        }
+        let mut result = None;
+        let mut closest_so_far = t_max;
+        for face in 0..self.indices.len() as u32 {
+            if let Some((t, b1, b2)) = self.face_ray_hit_t(face, ray, t_min, closest_so_far) {
+                result = Some((face, t, b1, b2));
+                closest_so_far = t;
+            }
+        }
+        result.map(|(face, t, b1, b2)| {
+            (
+                self.face_ray_hit_result(face, ray, t, b1, b2),
+                self.materials[face as usize],
+            )
+        })
+    }
+}
@@ -0,0 +1,485 @@
+use crate::{
+    collision::{BVHNode, Hitable, Ray, RayHit, AABB},
+    material::Material,
+    sampler::Sampler,
+    simd::TargetFeature,
+};
+use std::f32;
+
+// deep enough for all but badly skewed trees, which traverse with a heap allocated stack
+const MAX_STACK_DEPTH: usize = 64;
+const MAX_WIDTH: usize = 8;
+
+/// A child of a wide node. Interior children have `count == 0` and `offset` is the index of
+/// their node, leaf children reference `count` primitives starting at `offset`.
+#[derive(Copy, Clone, Debug, Default)]
+struct WideChild {
+    offset: u32,
+    count: u32,
+}
+
+/// A BVH with 4 or 8 children per node, collapsed from a binary `BVHNode` tree.
+///
+/// The bounds of each node's children are stored as a structure of arrays, `width` floats for
+/// each of min x, y, z and max x, y, z, so a ray is tested against all of them at once with
+/// SSE4.1 or AVX2, whichever the CPU supports. Nodes with fewer children than `width` mask
+/// out the unused lanes.
+#[derive(Debug)]
+pub struct WideBVH<'a> {
+    width: usize,
+    feature: TargetFeature,
+    aabb: AABB,
+    bounds: Vec<f32>,
+    children: Vec<WideChild>,
+    num_children: Vec<u8>,
+    primitives: Vec<Hitable<'a>>,
+    /// The depth of the deepest wide node, which bounds the size of the traversal stack.
+    depth: usize,
+}
+
+impl<'a> WideBVH<'a> {
+    /// Collapses `root` into nodes of `width` children, which must be 4 or 8, with bounds
+    /// swept over the shutter interval `t0` to `t1`.
+    pub fn new(root: &'a BVHNode<'a>, width: usize, t0: f32, t1: f32) -> WideBVH<'a> {
+        assert!(width == 4 || width == MAX_WIDTH);
+        let mut bvh = WideBVH {
+            width,
+            feature: TargetFeature::detect(),
+            aabb: root.bounding_box(),
+            bounds: Vec::new(),
+            children: Vec::new(),
+            num_children: Vec::new(),
+            primitives: Vec::new(),
+            depth: 0,
+        };
+        bvh.depth = bvh.collapse(root, 0, t0, t1);
+        bvh
+    }
+
+    #[inline]
+    pub fn bounding_box(&self) -> AABB {
+        self.aabb
+    }
+
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    #[inline]
+    pub fn num_nodes(&self) -> usize {
+        self.num_children.len()
+    }
+
+    #[inline]
+    pub fn primitives(&self) -> &[Hitable<'a>] {
+        &self.primitives
+    }
+
+    /// Appends a node for `node` and its descendants, pulling grandchildren up until it has
+    /// `width` children. Returns the depth of the subtree.
+    fn collapse(&mut self, node: &'a BVHNode<'a>, depth: usize, t0: f32, t1: f32) -> usize {
+        let mut children = Vec::with_capacity(self.width);
+        WideBVH::push_children(node, &mut children);
+        // open the child with the largest surface area, it is the most likely to be hit. This
+        // adds at most one child so never overfills the node
+        while children.len() < self.width {
+            let largest = children
+                .iter()
+                .enumerate()
+                .filter_map(|(index, child)| match child {
+                    Hitable::BVHNode(child) => Some((index, child.bounding_box().surface_area())),
+                    _ => None,
+                })
+                .max_by(|lhs, rhs| lhs.1.partial_cmp(&rhs.1).unwrap());
+            match largest {
+                Some((index, _)) => {
+                    if let Hitable::BVHNode(child) = children.swap_remove(index) {
+                        WideBVH::push_children(child, &mut children);
+                    }
+                }
+                None => break,
+            }
+        }
+        let index = self.num_children.len();
+        let width = self.width;
+        self.bounds.resize(self.bounds.len() + 6 * width, 0.0);
+        self.children
+            .resize(self.children.len() + width, WideChild::default());
+        self.num_children.push(children.len() as u8);
+
+        let mut max_depth = depth;
+        for (lane, child) in children.iter().enumerate() {
+            let aabb = child.bounding_box(t0, t1).unwrap();
+            let bounds = &mut self.bounds[index * 6 * width..(index + 1) * 6 * width];
+            for axis in 0..3 {
+                bounds[axis * width + lane] = aabb.min[axis];
+                bounds[(axis + 3) * width + lane] = aabb.max[axis];
+            }
+            let wide_child = match child {
+                Hitable::BVHNode(node) => {
+                    let offset = self.num_children.len() as u32;
+                    max_depth = max_depth.max(self.collapse(node, depth + 1, t0, t1));
+                    WideChild { offset, count: 0 }
+                }
+                _ => {
+                    let leaf = match child {
+                        Hitable::List(list) => list.hitables(),
+                        _ => std::slice::from_ref(child),
+                    };
+                    let offset = self.primitives.len() as u32;
+                    self.primitives.extend_from_slice(leaf);
+                    WideChild {
+                        offset,
+                        count: leaf.len() as u32,
+                    }
+                }
+            };
+            self.children[index * width + lane] = wide_child;
+        }
+        max_depth
+    }
+
+    fn push_children(node: &'a BVHNode<'a>, children: &mut Vec<Hitable<'a>>) {
+        let (lhs, rhs) = node.children();
+        children.push(lhs);
+        // single hitable nodes store it as both children
+        if !lhs.ptr_eq(&rhs) {
+            children.push(rhs);
+        }
+    }
+
+    pub fn ray_hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(RayHit, &Material<'_>)> {
+        self.traverse(ray, t_min, t_max, |hitable, closest_so_far| {
+            let hit = hitable.ray_hit(ray, t_min, closest_so_far, sampler)?;
+            Some((hit, hit.0.t))
+        })
+    }
+
+    pub fn ray_pick(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(RayHit, &Material<'_>, Hitable<'a>)> {
+        self.traverse(ray, t_min, t_max, |hitable, closest_so_far| {
+            let hit = hitable.ray_pick(ray, t_min, closest_so_far, sampler)?;
+            Some((hit, hit.0.t))
+        })
+    }
+
+    /// Visits the leaves `ray` may hit nearest first, `leaf_hit` is called for each of their
+    /// primitives with the closest hit distance so far and returns a hit and its distance.
+    #[inline]
+    fn traverse<'s, T, F>(&'s self, ray: &Ray, t_min: f32, t_max: f32, mut leaf_hit: F) -> Option<T>
+    where
+        F: FnMut(&'s Hitable<'a>, f32) -> Option<(T, f32)>,
+    {
+        // each node visited pushes at most all but one of its children on top of its parent's
+        let mut fixed_stack = [(WideChild::default(), 0.0); MAX_STACK_DEPTH * (MAX_WIDTH - 1) + 1];
+        let mut deep_stack;
+        let stack: &mut [(WideChild, f32)] = if self.depth < MAX_STACK_DEPTH {
+            &mut fixed_stack
+        } else {
+            deep_stack = vec![(WideChild::default(), 0.0); (self.depth + 1) * (MAX_WIDTH - 1) + 1];
+            &mut deep_stack
+        };
+        stack[0] = (WideChild::default(), t_min);
+        let mut stack_len = 1;
+        let mut result = None;
+        let mut closest_so_far = t_max;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let (child, t_near) = stack[stack_len];
+            if t_near > closest_so_far {
+                continue;
+            }
+            if child.count > 0 {
+                // leaf bounds were already tested by the parent
+                let start = child.offset as usize;
+                let end = start + child.count as usize;
+                for hitable in &self.primitives[start..end] {
+                    if let Some((hit, t)) = leaf_hit(hitable, closest_so_far) {
+                        closest_so_far = t;
+                        result = Some(hit);
+                    }
+                }
+                continue;
+            }
+
+            let index = child.offset as usize;
+            let mut t_nears = [0.0; MAX_WIDTH];
+            let mask = self.ray_hit_children(index, ray, t_min, closest_so_far, &mut t_nears);
+            // sort the hit children nearest first
+            let mut hits = [(WideChild::default(), 0.0); MAX_WIDTH];
+            let mut num_hits = 0;
+            let num_children = self.num_children[index] as usize;
+            for (lane, &t_near) in t_nears.iter().enumerate().take(num_children) {
+                if mask & (1 << lane) == 0 {
+                    continue;
+                }
+                let hit = (self.children[index * self.width + lane], t_near);
+                let mut position = num_hits;
+                while position > 0 && hits[position - 1].1 > hit.1 {
+                    hits[position] = hits[position - 1];
+                    position -= 1;
+                }
+                hits[position] = hit;
+                num_hits += 1;
+            }
+            // push the far children first so the nearest is visited next
+            for hit in hits[..num_hits].iter().rev() {
+                stack[stack_len] = *hit;
+                stack_len += 1;
+            }
+        }
+        result
+    }
+
+    /// Tests `ray` against the bounds of node `index`'s children, returns a bit mask of the
+    /// lanes hit and writes the distance the ray enters each child's bounds to `t_nears`.
+    /// Unused lanes may be set in the mask.
+    #[inline]
+    fn ray_hit_children(
+        &self,
+        index: usize,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        t_nears: &mut [f32; MAX_WIDTH],
+    ) -> u32 {
+        let bounds = &self.bounds[index * 6 * self.width..(index + 1) * 6 * self.width];
+        match (&self.feature, self.width) {
+            (TargetFeature::AVX2, MAX_WIDTH) => unsafe {
+                self.ray_hit_avx2(bounds, ray, t_min, t_max, t_nears)
+            },
+            (TargetFeature::AVX2, _) | (TargetFeature::SSE4_1, _) => {
+                let mut mask = 0;
+                for lane in (0..self.width).step_by(4) {
+                    mask |=
+                        unsafe { self.ray_hit_sse4_1(bounds, lane, ray, t_min, t_max, t_nears) }
+                            << lane;
+                }
+                mask
+            }
+            (TargetFeature::FallBack, _) => self.ray_hit_scalar(bounds, ray, t_min, t_max, t_nears),
+        }
+    }
+
+    pub fn ray_hit_scalar(
+        &self,
+        bounds: &[f32],
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        t_nears: &mut [f32; MAX_WIDTH],
+    ) -> u32 {
+        let width = self.width;
+        let mut mask = 0;
+        for (lane, t_near) in t_nears.iter_mut().enumerate().take(width) {
+            let mut t0 = t_min;
+            let mut t1 = t_max;
+            for axis in 0..3 {
+                let min_delta =
+                    (bounds[axis * width + lane] - ray.origin[axis]) * ray.rcp_direction[axis];
+                let max_delta = (bounds[(axis + 3) * width + lane] - ray.origin[axis])
+                    * ray.rcp_direction[axis];
+                t0 = t0.max(min_delta.min(max_delta));
+                t1 = t1.min(min_delta.max(max_delta));
+            }
+            // inclusive so flat bounds, such as a rect's, can be hit
+            if t1 >= t0 {
+                mask |= 1 << lane;
+            }
+            *t_near = t0;
+        }
+        mask
+    }
+
+    /// Tests the 4 children from `lane` onwards.
+    #[cfg_attr(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature(enable = "sse4.1")
+    )]
+    pub unsafe fn ray_hit_sse4_1(
+        &self,
+        bounds: &[f32],
+        lane: usize,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        t_nears: &mut [f32; MAX_WIDTH],
+    ) -> u32 {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::*;
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::*;
+        let width = self.width;
+        let mut t0 = _mm_set1_ps(t_min);
+        let mut t1 = _mm_set1_ps(t_max);
+        for axis in 0..3 {
+            let origin = _mm_set1_ps(ray.origin[axis]);
+            let rcp_direction = _mm_set1_ps(ray.rcp_direction[axis]);
+            let min = _mm_loadu_ps(bounds.get_unchecked(axis * width + lane));
+            let max = _mm_loadu_ps(bounds.get_unchecked((axis + 3) * width + lane));
+            let min_delta = _mm_mul_ps(_mm_sub_ps(min, origin), rcp_direction);
+            let max_delta = _mm_mul_ps(_mm_sub_ps(max, origin), rcp_direction);
+            t0 = _mm_max_ps(_mm_min_ps(min_delta, max_delta), t0);
+            t1 = _mm_min_ps(_mm_max_ps(min_delta, max_delta), t1);
+        }
+        _mm_storeu_ps(t_nears.get_unchecked_mut(lane), t0);
+        // inclusive so flat bounds, such as a rect's, can be hit
+        _mm_movemask_ps(_mm_cmpge_ps(t1, t0)) as u32
+    }
+
+    /// Tests all 8 children.
+    #[cfg_attr(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature(enable = "avx2")
+    )]
+    pub unsafe fn ray_hit_avx2(
+        &self,
+        bounds: &[f32],
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        t_nears: &mut [f32; MAX_WIDTH],
+    ) -> u32 {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::*;
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::*;
+        const WIDTH: usize = MAX_WIDTH;
+        let mut t0 = _mm256_set1_ps(t_min);
+        let mut t1 = _mm256_set1_ps(t_max);
+        for axis in 0..3 {
+            let origin = _mm256_set1_ps(ray.origin[axis]);
+            let rcp_direction = _mm256_set1_ps(ray.rcp_direction[axis]);
+            let min = _mm256_loadu_ps(bounds.get_unchecked(axis * WIDTH));
+            let max = _mm256_loadu_ps(bounds.get_unchecked((axis + 3) * WIDTH));
+            let min_delta = _mm256_mul_ps(_mm256_sub_ps(min, origin), rcp_direction);
+            let max_delta = _mm256_mul_ps(_mm256_sub_ps(max, origin), rcp_direction);
+            t0 = _mm256_max_ps(_mm256_min_ps(min_delta, max_delta), t0);
+            t1 = _mm256_min_ps(_mm256_max_ps(min_delta, max_delta), t1);
+        }
+        _mm256_storeu_ps(t_nears.as_mut_ptr(), t0);
+        // inclusive so flat bounds, such as a rect's, can be hit
+        _mm256_movemask_ps(_mm256_cmp_ps(t1, t0, _CMP_GE_OQ)) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WideBVH, MAX_STACK_DEPTH};
+    use crate::{
+        collision::{BVHNode, Hitable, Ray, Sphere},
+        material,
+        scene::{MAX_T, MIN_T},
+        storage::Storage,
+        texture,
+    };
+    use glam::Vec3;
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256Plus;
+
+    #[test]
+    fn deeper_than_fixed_stack() {
+        // nesting each BVH in the next makes a row of spheres into a binary tree deep enough
+        // that it is still deeper than the fixed traversal stack after collapsing
+        let mut rng = Xoshiro256Plus::seed_from_u64(0);
+        let storage = Storage::new(&mut rng);
+        let material = storage.alloc_material(material::lambertian(
+            storage.alloc_texture(texture::constant(Vec3::ONE)),
+        ));
+        let sphere = |x| {
+            Hitable::Sphere(
+                storage.alloc_sphere(Sphere::new(Vec3::new(x, 0.0, 0.0), 0.1)),
+                material,
+            )
+        };
+        let mut root = BVHNode::new(
+            &mut rng,
+            &mut [sphere(0.0)],
+            0.0,
+            0.0,
+            &storage.bvhnode_arena,
+        )
+        .unwrap();
+        for i in 1..600 {
+            let mut hitables = [Hitable::BVHNode(root), sphere(i as f32)];
+            root = BVHNode::new(&mut rng, &mut hitables, 0.0, 0.0, &storage.bvhnode_arena).unwrap();
+        }
+        for &width in &[4, 8] {
+            let bvh = WideBVH::new(root, width, 0.0, 0.0);
+            assert!(bvh.depth >= MAX_STACK_DEPTH, "depth {}", bvh.depth);
+
+            // the nearest sphere is the deepest leaf, every node above it pushes its far children
+            let ray = Ray::new(Vec3::new(-10.0, 0.0, 0.0), Vec3::X, 0.0);
+            let (ray_hit, _) = bvh.ray_hit(&ray, MIN_T, MAX_T, &mut rng).unwrap();
+            assert!((ray_hit.t - 9.9).abs() < 1e-4, "{}", ray_hit.t);
+        }
+    }
+}
+
+#[cfg(all(feature = "bench", test))]
+mod bench {
+    use crate::{
+        bench::PARAMS,
+        collision::{BVHNode, WideBVH},
+        presets,
+        scene::{MAX_T, MIN_T},
+        storage::Storage,
+    };
+    use test::Bencher;
+
+    fn random_spheres_bench(b: &mut Bencher, width: usize) {
+        let mut rng = PARAMS.new_rng();
+        let storage = Storage::new(&mut rng);
+        let (mut hitables, camera, _) = presets::random_spheres(&PARAMS, &mut rng, &storage);
+        let ray = camera.get_ray(0.5, 0.5, &mut rng);
+        let (t0, t1) = camera.shutter();
+        let bvh_root =
+            BVHNode::new(&mut rng, &mut hitables, t0, t1, &storage.bvhnode_arena).unwrap();
+        let wide_bvh = WideBVH::new(bvh_root, width, t0, t1);
+        b.iter(|| wide_bvh.ray_hit(&ray, MIN_T, MAX_T, &mut rng));
+    }
+
+    fn random_bench(b: &mut Bencher, width: usize) {
+        let mut rng = PARAMS.new_rng();
+        let storage = Storage::new(&mut rng);
+        let (mut hitables, camera, _) = presets::random(&PARAMS, &mut rng, &storage);
+        let ray = camera.get_ray(0.5, 0.5, &mut rng);
+        let (t0, t1) = camera.shutter();
+        let bvh_root =
+            BVHNode::new(&mut rng, &mut hitables, t0, t1, &storage.bvhnode_arena).unwrap();
+        let wide_bvh = WideBVH::new(bvh_root, width, t0, t1);
+        b.iter(|| wide_bvh.ray_hit(&ray, MIN_T, MAX_T, &mut rng));
+    }
+
+    #[bench]
+    fn random_spheres_ray_hit_bvh4(b: &mut Bencher) {
+        random_spheres_bench(b, 4);
+    }
+
+    #[bench]
+    fn random_spheres_ray_hit_bvh8(b: &mut Bencher) {
+        random_spheres_bench(b, 8);
+    }
+
+    #[bench]
+    fn ray_hit_bvh4(b: &mut Bencher) {
+        random_bench(b, 4);
+    }
+
+    #[bench]
+    fn ray_hit_bvh8(b: &mut Bencher) {
+        random_bench(b, 8);
+    }
+}
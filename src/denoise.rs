@@ -0,0 +1,262 @@
+use crate::aov::AovPixel;
+use glam::Vec3;
+use rayon::prelude::*;
+
+/// Number of filter passes, the kernel footprint doubles each pass so three passes cover 29 by
+/// 29 pixels.
+const ITERATIONS: u32 = 3;
+/// Cubic B-spline weights of the 5 by 5 filter kernel.
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+/// Edge stopping scales for the difference in tone mapped illumination, normal and relative
+/// depth between neighbouring pixels. Noise falls with the square root of the sample count so
+/// the illumination scale is for a single sample and shrinks as more are taken.
+const SIGMA_COLOR: f32 = 4.0;
+const SIGMA_NORMAL: f32 = 0.3;
+const SIGMA_DEPTH: f32 = 0.05;
+
+/// A pixel of the buffer being filtered along with the features guiding it.
+#[derive(Copy, Clone)]
+struct Texel {
+    illumination: Vec3,
+    normal: Vec3,
+    depth: f32,
+    material_id: u32,
+    sample_count: u32,
+}
+
+/// Removes noise from `rgb_buffer` with an edge-avoiding à-trous wavelet filter, following
+/// Dammertz et al. 2010, guided by the albedo, normal, depth and material of the first hits
+/// in `features`.
+///
+/// Colour is divided by albedo before filtering and multiplied back after, so texture detail
+/// isn't blurred along with the lighting. Neighbouring pixels are only averaged where they have
+/// the same material and similar normals, depths and illumination, and the tolerance for
+/// illumination tightens each pass as the noise it has to allow for is removed.
+pub fn denoise(
+    width: u32,
+    height: u32,
+    rgb_buffer: &[(f32, f32, f32)],
+    features: &[AovPixel],
+) -> Vec<(f32, f32, f32)> {
+    assert_eq!(rgb_buffer.len(), (width * height) as usize);
+    assert_eq!(features.len(), rgb_buffer.len());
+
+    // pixels where the camera ray missed have no albedo, filter their colour directly
+    let albedo: Vec<Vec3> = features
+        .iter()
+        .map(|feature| {
+            if feature.depth > 0.0 {
+                feature.albedo.max(Vec3::splat(0.01))
+            } else {
+                Vec3::ONE
+            }
+        })
+        .collect();
+    let mut texels: Vec<Texel> = rgb_buffer
+        .iter()
+        .zip(features.iter().zip(albedo.iter()))
+        .map(|(rgb, (feature, albedo))| Texel {
+            illumination: Vec3::new(rgb.0, rgb.1, rgb.2) / *albedo,
+            normal: feature.normal,
+            depth: feature.depth,
+            material_id: feature.material_id,
+            sample_count: feature.sample_count,
+        })
+        .collect();
+
+    let mut filtered = texels.clone();
+    for iteration in 0..ITERATIONS {
+        let step = 1 << iteration;
+        let color_scale = 4f32.powi(iteration as i32) / (SIGMA_COLOR * SIGMA_COLOR);
+        filtered
+            .par_chunks_mut(width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, out) in row.iter_mut().enumerate() {
+                    out.illumination = filter_texel(
+                        &texels,
+                        width as i32,
+                        height as i32,
+                        x as i32,
+                        y as i32,
+                        step,
+                        color_scale,
+                    );
+                }
+            });
+        std::mem::swap(&mut texels, &mut filtered);
+    }
+
+    texels
+        .iter()
+        .zip(albedo.iter())
+        .map(|(texel, albedo)| (texel.illumination * *albedo).into())
+        .collect()
+}
+
+/// Returns the weighted average of the illumination around `(x, y)` sampling every `step`
+/// pixels.
+#[inline]
+fn filter_texel(
+    texels: &[Texel],
+    width: i32,
+    height: i32,
+    x: i32,
+    y: i32,
+    step: i32,
+    color_scale: f32,
+) -> Vec3 {
+    let centre = &texels[(y * width + x) as usize];
+    let inv_sigma_color_sq = color_scale * centre.sample_count.max(1) as f32;
+    let centre_color = tone_map(centre.illumination);
+    let mut sum = Vec3::ZERO;
+    let mut weight_sum = 0.0;
+    for (j, kernel_y) in KERNEL.iter().enumerate() {
+        let qy = y + (j as i32 - 2) * step;
+        if qy < 0 || qy >= height {
+            continue;
+        }
+        for (i, kernel_x) in KERNEL.iter().enumerate() {
+            let qx = x + (i as i32 - 2) * step;
+            if qx < 0 || qx >= width {
+                continue;
+            }
+            let texel = &texels[(qy * width + qx) as usize];
+            if texel.material_id != centre.material_id {
+                continue;
+            }
+            let color_distance_sq = (tone_map(texel.illumination) - centre_color).length_squared();
+            let normal_distance_sq = (texel.normal - centre.normal).length_squared();
+            // depth differences grow with distance from the camera and between samples further
+            // apart, so are compared relative to both
+            let depth_scale = SIGMA_DEPTH * centre.depth.max(texel.depth) * step as f32;
+            let depth_distance = if depth_scale > 0.0 {
+                (texel.depth - centre.depth).abs() / depth_scale
+            } else {
+                0.0
+            };
+            let weight = kernel_x
+                * kernel_y
+                * (-color_distance_sq * inv_sigma_color_sq
+                    - normal_distance_sq / (SIGMA_NORMAL * SIGMA_NORMAL)
+                    - depth_distance)
+                    .exp();
+            sum += texel.illumination * weight;
+            weight_sum += weight;
+        }
+    }
+    // the centre pixel always has a non-zero weight
+    sum / weight_sum
+}
+
+/// Compresses bright values so colour differences around lights don't stop the filter
+/// everywhere else.
+#[inline]
+fn tone_map(rgb: Vec3) -> Vec3 {
+    rgb / (Vec3::ONE + rgb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::denoise;
+    use crate::{
+        aov::{AovBuffer, AovPixel},
+        collision::BVHBuilder,
+        film::{DisplayTransform, ToneMap},
+        params::{Accel, Params},
+        presets,
+        sampler::SamplerKind,
+        scene::Integrator,
+        storage::Storage,
+        tiles::TileOrder,
+    };
+
+    pub(super) const WIDTH: u32 = 64;
+    pub(super) const HEIGHT: u32 = 64;
+
+    pub(super) fn render_cornell_box(
+        samples: u32,
+        seed: u64,
+    ) -> (Vec<(f32, f32, f32)>, Vec<AovPixel>) {
+        let params = Params {
+            width: WIDTH,
+            height: HEIGHT,
+            samples,
+            adaptive: None,
+            min_depth: 10,
+            seed,
+            accel: Accel::LinearBvh,
+            bvh_builder: BVHBuilder::Sah,
+            integrator: Integrator::Mis,
+            sampler: SamplerKind::Independent,
+            tile_size: 32,
+            tile_order: TileOrder::Scanline,
+            crop: None,
+            display: DisplayTransform {
+                exposure: 0.0,
+                tone_map: ToneMap::Clamp,
+            },
+        };
+        let mut rng = params.new_rng();
+        let storage = Storage::new(&mut rng);
+        let (hitables, camera, sky) = presets::cornell_box(&params, &storage);
+        let scene = params.new_scene(&mut rng, &storage, hitables, &camera, sky);
+        let mut rgb_buffer = vec![(0.0, 0.0, 0.0); (WIDTH * HEIGHT) as usize];
+        let mut aov_buffer = AovBuffer::new(&scene.world(), WIDTH, HEIGHT);
+        scene.update(
+            &params,
+            &camera,
+            0,
+            &params.tiles(),
+            &mut rgb_buffer,
+            Some(&mut aov_buffer),
+            |_| (),
+        );
+        (rgb_buffer, aov_buffer.pixels)
+    }
+
+    /// Mean squared error of the displayable range, so the light's emission doesn't dominate.
+    fn mse(a: &[(f32, f32, f32)], b: &[(f32, f32, f32)]) -> f32 {
+        let clamped_sq_diff = |x: f32, y: f32| (x.min(1.0) - y.min(1.0)).powi(2);
+        let sum: f32 = a
+            .iter()
+            .zip(b.iter())
+            .map(|(a, b)| {
+                clamped_sq_diff(a.0, b.0) + clamped_sq_diff(a.1, b.1) + clamped_sq_diff(a.2, b.2)
+            })
+            .sum();
+        sum / (a.len() * 3) as f32
+    }
+
+    #[test]
+    fn denoise_cornell_box() {
+        // denoising a low sample render should bring it much closer to a converged one, which
+        // is seeded differently so its noise isn't correlated with the low sample render's
+        let (reference, _) = render_cornell_box(256, 1);
+        let (noisy, features) = render_cornell_box(4, 0);
+        let denoised = denoise(WIDTH, HEIGHT, &noisy, &features);
+        let noisy_mse = mse(&noisy, &reference);
+        let denoised_mse = mse(&denoised, &reference);
+        assert!(
+            denoised_mse < 0.5 * noisy_mse,
+            "{} {}",
+            denoised_mse,
+            noisy_mse
+        );
+    }
+}
+
+#[cfg(all(feature = "bench", test))]
+mod bench {
+    use super::{
+        denoise,
+        tests::{render_cornell_box, HEIGHT, WIDTH},
+    };
+    use test::Bencher;
+
+    #[bench]
+    fn denoise_cornell_box(b: &mut Bencher) {
+        let (noisy, features) = render_cornell_box(4, 0);
+        b.iter(|| denoise(WIDTH, HEIGHT, &noisy, &features));
+    }
+}
@@ -0,0 +1,437 @@
+use crate::{
+    aov::{AovBuffer, AovPixel},
+    checkpoint::Checkpoint,
+    offline::{self, SaveIntervals},
+    params::Params,
+    scene::TraceStats,
+    source::SceneSource,
+    storage::Storage,
+    tiles::{Tile, TileEvent},
+};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    process,
+    sync::{
+        mpsc::{channel, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Changed whenever the messages do so mismatched coordinators and workers don't talk.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Tiles sent to a worker at a time for each of its threads, more than one so its threads
+/// aren't all idle waiting for the slowest tile before the next are sent.
+const TILES_PER_THREAD: usize = 2;
+
+/// How long a worker keeps trying to connect to a coordinator that isn't listening yet.
+const CONNECT_ATTEMPTS: u32 = 30;
+
+/// A tile for a worker to render, with its pixels so far for the pass to be blended with.
+#[derive(Clone, Serialize, Deserialize)]
+struct Assignment {
+    tile: Tile,
+    pixels: Vec<(f32, f32, f32)>,
+    aovs: Option<Vec<AovPixel>>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum CoordinatorMessage {
+    /// What to render, sent once the worker has said hello. Workers load the scene themselves
+    /// and build it with `Params::new_rng` so every worker traces the same scene.
+    Job {
+        source: SceneSource,
+        params: Params,
+        gather_aovs: bool,
+    },
+    /// Tiles of pass `pass` to render and send back.
+    Render {
+        pass: u32,
+        assignments: Vec<Assignment>,
+    },
+    /// The render is finished and the worker can exit.
+    Finished,
+}
+
+#[derive(Serialize, Deserialize)]
+enum WorkerMessage {
+    Hello {
+        version: u32,
+        threads: usize,
+    },
+    /// A tile of a `Render` message, sent as soon as it is finished.
+    Tile {
+        tile: Tile,
+        pixels: Vec<(f32, f32, f32)>,
+        aovs: Option<Vec<AovPixel>>,
+        sample_counts: Vec<u32>,
+    },
+    /// Sent after all the tiles of a `Render` message with the stats for the rays traced.
+    Rendered(TraceStats),
+}
+
+fn send<W: Write, T: serde::Serialize>(writer: &mut W, message: &T) -> bincode::Result<()> {
+    bincode::serialize_into(&mut *writer, message)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn receive<R: Read, T: DeserializeOwned>(reader: &mut R) -> bincode::Result<T> {
+    bincode::deserialize_from(reader)
+}
+
+/// The tiles of the current pass waiting for a worker.
+#[derive(Default)]
+struct Queue {
+    pass: u32,
+    pending: VecDeque<Assignment>,
+    /// Batches of tiles sent to workers that haven't reported `Rendered` yet.
+    in_flight: usize,
+    finished: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+    connections: Mutex<Vec<JoinHandle<()>>>,
+}
+
+/// Renders `checkpoint` on workers connecting to `addr`, which are handed tiles as they ask
+/// for more. Tiles are seeded by their pixels and pass, so the image is the same however the
+/// tiles are spread over the workers. The coordinator writes the checkpoint and output like
+/// `offline::render_offline`.
+pub fn serve(addr: &str, checkpoint: Checkpoint, checkpoint_path: &Path, intervals: SaveIntervals) {
+    let listener = TcpListener::bind(addr).unwrap_or_else(|err| {
+        eprintln!("Failed to listen on {}: {}", addr, err);
+        process::exit(1)
+    });
+    println!("waiting for workers on {}", addr);
+
+    let params = checkpoint.params;
+    let job = Arc::new(CoordinatorMessage::Job {
+        source: checkpoint.source.clone(),
+        params,
+        gather_aovs: checkpoint.aov_pixels.is_some(),
+    });
+    let shared = Arc::new(Shared::default());
+    let (results_send, results) = channel();
+    {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        eprintln!("Failed to accept worker: {}", err);
+                        continue;
+                    }
+                };
+                let job = job.clone();
+                let connection_shared = shared.clone();
+                let results_send = results_send.clone();
+                let connection = thread::spawn(move || {
+                    let peer = stream
+                        .peer_addr()
+                        .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string());
+                    eprintln!("\nworker {} connected", peer);
+                    if let Err(err) = serve_worker(stream, &job, &connection_shared, &results_send)
+                    {
+                        eprintln!("\nworker {} failed: {}", peer, err);
+                    }
+                });
+                shared.connections.lock().unwrap().push(connection);
+            }
+        });
+    }
+
+    // the coordinator keeps its own copy of the image to send tiles' pixels so far to workers
+    let mut rgb_buffer = checkpoint.rgb.clone();
+    let mut aov_pixels = checkpoint.aov_pixels.clone();
+    offline::render_passes(
+        checkpoint,
+        checkpoint_path,
+        intervals,
+        |pass, tiles, on_tile| {
+            {
+                let mut queue = shared.queue.lock().unwrap();
+                queue.pass = pass;
+                queue.pending = tiles
+                    .iter()
+                    .map(|tile| Assignment {
+                        tile: *tile,
+                        pixels: tile.extract(&rgb_buffer, params.width),
+                        aovs: aov_pixels
+                            .as_ref()
+                            .map(|aov_pixels| tile.extract(aov_pixels, params.width)),
+                    })
+                    .collect();
+            }
+            shared.changed.notify_all();
+
+            let mut stats = TraceStats::default();
+            let mut completed = 0;
+            while completed < tiles.len() || shared.queue.lock().unwrap().in_flight != 0 {
+                match results.recv().unwrap() {
+                    WorkerMessage::Tile {
+                        tile,
+                        pixels,
+                        aovs,
+                        sample_counts,
+                    } => {
+                        tile.insert(&pixels, &mut rgb_buffer, params.width);
+                        if let (Some(aov_pixels), Some(aovs)) = (&mut aov_pixels, &aovs) {
+                            tile.insert(aovs, aov_pixels, params.width);
+                        }
+                        completed += 1;
+                        on_tile(TileEvent::Finished {
+                            tile,
+                            pixels: &pixels,
+                            aovs: aovs.as_deref(),
+                            sample_counts: &sample_counts,
+                            completed,
+                            total: tiles.len(),
+                        });
+                    }
+                    WorkerMessage::Rendered(worker_stats) => stats.add(&worker_stats),
+                    WorkerMessage::Hello { .. } => unreachable!(),
+                }
+            }
+            stats
+        },
+    );
+
+    shared.queue.lock().unwrap().finished = true;
+    shared.changed.notify_all();
+    // let the connected workers know they can exit
+    let connections: Vec<_> = shared.connections.lock().unwrap().drain(..).collect();
+    for connection in connections {
+        connection.join().unwrap();
+    }
+}
+
+/// Hands tiles to the worker on `stream` until the render is finished, forwarding what it sends
+/// back to `results`. If the worker fails its unfinished tiles are queued for other workers.
+fn serve_worker(
+    stream: TcpStream,
+    job: &CoordinatorMessage,
+    shared: &Shared,
+    results: &Sender<WorkerMessage>,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let threads = match receive(&mut reader)? {
+        WorkerMessage::Hello { version, threads } if version == PROTOCOL_VERSION => threads,
+        WorkerMessage::Hello { version, .. } => {
+            return Err(format!(
+                "protocol version {} doesn't match {}",
+                version, PROTOCOL_VERSION
+            )
+            .into())
+        }
+        _ => return Err("expected hello".into()),
+    };
+    send(&mut writer, job)?;
+
+    loop {
+        let (pass, mut assignments) = {
+            let mut queue = shared.queue.lock().unwrap();
+            while queue.pending.is_empty() && !queue.finished {
+                queue = shared.changed.wait(queue).unwrap();
+            }
+            if queue.finished {
+                drop(queue);
+                send(&mut writer, &CoordinatorMessage::Finished)?;
+                return Ok(());
+            }
+            let count = queue.pending.len().min(threads.max(1) * TILES_PER_THREAD);
+            queue.in_flight += 1;
+            (queue.pass, queue.pending.drain(..count).collect::<Vec<_>>())
+        };
+
+        let result = render_batch(&mut reader, &mut writer, pass, &mut assignments, results);
+        {
+            let mut queue = shared.queue.lock().unwrap();
+            queue.in_flight -= 1;
+            // anything the worker didn't send back goes to the others
+            queue.pending.extend(assignments);
+        }
+        shared.changed.notify_all();
+        match result {
+            Ok(stats) => results.send(WorkerMessage::Rendered(stats)).unwrap(),
+            Err(err) => {
+                // wake the coordinator to notice the batch is no longer in flight
+                results
+                    .send(WorkerMessage::Rendered(TraceStats::default()))
+                    .unwrap();
+                return Err(err.into());
+            }
+        }
+    }
+}
+
+/// Sends `assignments` to the worker and forwards the tiles it renders to `results`, removing
+/// them from `assignments` as they arrive.
+fn render_batch(
+    reader: &mut BufReader<TcpStream>,
+    writer: &mut BufWriter<TcpStream>,
+    pass: u32,
+    assignments: &mut Vec<Assignment>,
+    results: &Sender<WorkerMessage>,
+) -> bincode::Result<TraceStats> {
+    send(
+        writer,
+        &CoordinatorMessage::Render {
+            pass,
+            assignments: assignments.clone(),
+        },
+    )?;
+    loop {
+        match receive(reader)? {
+            WorkerMessage::Tile {
+                tile,
+                pixels,
+                aovs,
+                sample_counts,
+            } => {
+                let index = assignments
+                    .iter()
+                    .position(|assignment| assignment.tile == tile)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unexpected tile"))?;
+                assignments.swap_remove(index);
+                results
+                    .send(WorkerMessage::Tile {
+                        tile,
+                        pixels,
+                        aovs,
+                        sample_counts,
+                    })
+                    .unwrap();
+            }
+            WorkerMessage::Rendered(stats) if assignments.is_empty() => return Ok(stats),
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected message").into())
+            }
+        }
+    }
+}
+
+/// Renders tiles for the coordinator at `addr` until it says the render is finished.
+pub fn work(addr: &str) {
+    let mut attempts = 0;
+    let stream = loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => break stream,
+            Err(err) if attempts < CONNECT_ATTEMPTS => {
+                if attempts == 0 {
+                    eprintln!("Waiting for coordinator {}: {}", addr, err);
+                }
+                attempts += 1;
+                thread::sleep(Duration::from_secs(1));
+            }
+            Err(err) => {
+                eprintln!("Failed to connect to {}: {}", addr, err);
+                process::exit(1)
+            }
+        }
+    };
+    if let Err(err) = run_worker(stream) {
+        eprintln!("Worker failed: {}", err);
+        process::exit(1)
+    }
+}
+
+fn run_worker(stream: TcpStream) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let writer = Mutex::new(BufWriter::new(stream));
+    send(
+        &mut *writer.lock().unwrap(),
+        &WorkerMessage::Hello {
+            version: PROTOCOL_VERSION,
+            threads: rayon::current_num_threads(),
+        },
+    )?;
+    let (source, params, gather_aovs) = match receive(&mut reader)? {
+        CoordinatorMessage::Job {
+            source,
+            params,
+            gather_aovs,
+        } => (source, params, gather_aovs),
+        _ => return Err("expected a job".into()),
+    };
+
+    let mut rng = params.new_rng();
+    let storage = Storage::new(&mut rng);
+    let (hitables, camera, sky) = source.load(&params, &mut rng, &storage)?;
+    let scene = params.new_scene(&mut rng, &storage, hitables, &camera, sky);
+
+    let mut rgb_buffer = vec![(0.0, 0.0, 0.0); (params.width * params.height) as usize];
+    let mut aov_buffer = if gather_aovs {
+        Some(AovBuffer::new(&scene.world(), params.width, params.height))
+    } else {
+        None
+    };
+    let mut tile_count = 0;
+    loop {
+        let (pass, assignments) = match receive(&mut reader)? {
+            CoordinatorMessage::Render { pass, assignments } => (pass, assignments),
+            CoordinatorMessage::Finished => break,
+            CoordinatorMessage::Job { .. } => return Err("unexpected job".into()),
+        };
+        for assignment in &assignments {
+            let tile = assignment.tile;
+            tile.insert(&assignment.pixels, &mut rgb_buffer, params.width);
+            if let (Some(aov_buffer), Some(aovs)) = (&mut aov_buffer, &assignment.aovs) {
+                tile.insert(aovs, &mut aov_buffer.pixels, params.width);
+            }
+        }
+        let tiles: Vec<Tile> = assignments
+            .iter()
+            .map(|assignment| assignment.tile)
+            .collect();
+        let stats = scene.update(
+            &params,
+            &camera,
+            pass,
+            &tiles,
+            &mut rgb_buffer,
+            aov_buffer.as_mut(),
+            |event| {
+                if let TileEvent::Finished {
+                    tile,
+                    pixels,
+                    aovs,
+                    sample_counts,
+                    ..
+                } = event
+                {
+                    let message = WorkerMessage::Tile {
+                        tile,
+                        pixels: pixels.to_vec(),
+                        aovs: aovs.map(<[AovPixel]>::to_vec),
+                        sample_counts: sample_counts.to_vec(),
+                    };
+                    if let Err(err) = send(&mut *writer.lock().unwrap(), &message) {
+                        eprintln!("Lost connection to coordinator: {}", err);
+                        process::exit(1)
+                    }
+                }
+            },
+        );
+        send(
+            &mut *writer.lock().unwrap(),
+            &WorkerMessage::Rendered(stats),
+        )?;
+        tile_count += tiles.len();
+    }
+    println!("rendered {} tiles", tile_count);
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use crate::math::linear_to_srgb;
+use glam::{const_mat3, const_vec3, Mat3, Vec3};
+use serde_derive::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// How scene radiance is compressed into the displayable range.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ToneMap {
+    /// Clip each channel at 1.
+    Clamp,
+    /// Reinhard's global operator on luminance, preserving hue.
+    Reinhard,
+    /// Krzysztof Narkowicz's fit of the ACES filmic curve.
+    Aces,
+    /// An approximation of Blender's AgX, which desaturates bright colours towards white
+    /// rather than clipping them.
+    Agx,
+}
+
+impl ToneMap {
+    pub const NAMES: [&'static str; 4] = ["clamp", "reinhard", "aces", "agx"];
+
+    /// Maps linear scene radiance to linear display values in `[0, 1]`.
+    pub fn apply(self, rgb: Vec3) -> Vec3 {
+        let rgb = rgb.max(Vec3::ZERO);
+        match self {
+            ToneMap::Clamp => rgb.min(Vec3::ONE),
+            ToneMap::Reinhard => {
+                let luminance = rgb.dot(LUMINANCE);
+                (rgb / (1.0 + luminance)).min(Vec3::ONE)
+            }
+            ToneMap::Aces => {
+                const A: f32 = 2.51;
+                const B: f32 = 0.03;
+                const C: f32 = 2.43;
+                const D: f32 = 0.59;
+                const E: f32 = 0.14;
+                ((rgb * (A * rgb + B)) / (rgb * (C * rgb + D) + E)).clamp(Vec3::ZERO, Vec3::ONE)
+            }
+            ToneMap::Agx => agx(rgb),
+        }
+    }
+}
+
+impl FromStr for ToneMap {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(ToneMap::Clamp),
+            "reinhard" => Ok(ToneMap::Reinhard),
+            "aces" => Ok(ToneMap::Aces),
+            "agx" => Ok(ToneMap::Agx),
+            _ => Err(format!("unrecognised tone map '{}'", s)),
+        }
+    }
+}
+
+/// Rec. 709 luminance weights.
+pub const LUMINANCE: Vec3 = const_vec3!([0.2126, 0.7152, 0.0722]);
+
+// the AgX inset and outset matrices for linear Rec. 709 input
+const AGX_INSET: Mat3 = const_mat3!(
+    [0.842_479_06, 0.042_328_24, 0.042_375_655],
+    [0.078_433_6, 0.878_468_6, 0.078_433_6],
+    [0.079_223_745, 0.079_166_13, 0.879_143]
+);
+const AGX_OUTSET: Mat3 = const_mat3!(
+    [1.196_879, -0.052_896_85, -0.052_971_635],
+    [-0.098_020_88, 1.151_903_1, -0.098_043_45],
+    [-0.099_029_74, -0.098_961_18, 1.151_073_7]
+);
+const AGX_MIN_EV: f32 = -12.473_931;
+const AGX_MAX_EV: f32 = 4.026_069;
+
+fn agx(rgb: Vec3) -> Vec3 {
+    // encode the inset colour logarithmically between the min and max exposures
+    let rgb = AGX_INSET * rgb;
+    let log = |x: f32| {
+        let ev = x.max(1e-10).log2().clamp(AGX_MIN_EV, AGX_MAX_EV);
+        (ev - AGX_MIN_EV) / (AGX_MAX_EV - AGX_MIN_EV)
+    };
+    // polynomial approximation of the AgX base contrast sigmoid
+    let sigmoid = |x: f32| {
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.002_32
+    };
+    let rgb = Vec3::new(
+        sigmoid(log(rgb.x)),
+        sigmoid(log(rgb.y)),
+        sigmoid(log(rgb.z)),
+    );
+    // the sigmoid output is display encoded, undo that so the sRGB encoding can be shared
+    let rgb = (AGX_OUTSET * rgb).max(Vec3::ZERO);
+    Vec3::new(rgb.x.powf(2.2), rgb.y.powf(2.2), rgb.z.powf(2.2)).min(Vec3::ONE)
+}
+
+/// Converts linear radiance to 8-bit sRGB for display, used for both the preview window and
+/// PNG output so they match.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct DisplayTransform {
+    /// Exposure adjustment in stops, radiance is scaled by `2^exposure`.
+    pub exposure: f32,
+    pub tone_map: ToneMap,
+}
+
+impl Default for DisplayTransform {
+    fn default() -> Self {
+        DisplayTransform {
+            exposure: 0.0,
+            tone_map: ToneMap::Clamp,
+        }
+    }
+}
+
+impl DisplayTransform {
+    #[inline]
+    pub fn apply(&self, rgb: (f32, f32, f32)) -> (u8, u8, u8) {
+        let rgb = Vec3::new(rgb.0, rgb.1, rgb.2) * self.exposure.exp2();
+        let rgb = self.tone_map.apply(rgb);
+        linear_to_srgb((rgb.x, rgb.y, rgb.z))
+    }
+}
@@ -1,4 +1,13 @@
-use crate::{params::Params, presets, storage::Storage};
+use crate::{
+    aov::AovBuffer,
+    collision::{Instance, TlasUpdate},
+    denoise,
+    params::Params,
+    scene::TraceStats,
+    source::SceneSource,
+    storage::Storage,
+    tiles::{Tile, TileEvent},
+};
 use glium::{
     self,
     glutin::{Api, GlProfile, GlRequest},
@@ -10,12 +19,51 @@ use glium::{
 };
 use image;
 use std::{
-    sync::mpsc::{channel, RecvTimeoutError},
+    iter, process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, RecvTimeoutError},
+        Arc,
+    },
     thread,
     time::{Duration, SystemTime},
 };
 
-pub fn start_loop<'a>(preset: &str, params: Params, max_frames: Option<u32>) {
+/// Animated scenes advance by this many seconds a frame, whatever the frame actually took.
+const ANIMATION_FRAME_SECS: f32 = 1.0 / 30.0;
+
+/// What the worker thread sends back to the window while rendering.
+enum WorkerUpdate {
+    TileStarted(Tile),
+    /// A finished tile's pixels, row by row.
+    TileFinished(Tile, Vec<(f32, f32, f32)>),
+    /// The accumulated frames and a denoised copy if it was requested.
+    Frame(Vec<(f32, f32, f32)>, Option<Vec<(f32, f32, f32)>>),
+}
+
+/// Marks the corners of a tile that is being rendered.
+fn mark_tile(texels: &mut [(u8, u8, u8, u8)], stride: u32, tile: &Tile) {
+    const MARK: (u8, u8, u8, u8) = (255, 160, 0, 255);
+    let length = (tile.width.min(tile.height) / 4).max(1);
+    let (x0, y0) = (tile.x, tile.y);
+    let (x1, y1) = (tile.x + tile.width - 1, tile.y + tile.height - 1);
+    for i in 0..length {
+        for &(x, y) in &[
+            (x0 + i, y0),
+            (x1 - i, y0),
+            (x0 + i, y1),
+            (x1 - i, y1),
+            (x0, y0 + i),
+            (x1, y0 + i),
+            (x0, y1 - i),
+            (x1, y1 - i),
+        ] {
+            texels[(y * stride + x) as usize] = MARK;
+        }
+    }
+}
+
+pub fn start_loop<'a>(source: &SceneSource, params: Params, max_frames: Option<u32>) {
     let mut events_loop = glium::glutin::EventsLoop::new();
     let window = glium::glutin::WindowBuilder::new()
         .with_dimensions((params.width, params.height).into())
@@ -79,27 +127,70 @@ pub fn start_loop<'a>(preset: &str, params: Params, max_frames: Option<u32>) {
     ]);
 
     let (main_send, worker_recv) = channel::<Option<Vec<(f32, f32, f32)>>>();
-    let (worker_send, main_recv) = channel::<Vec<(f32, f32, f32)>>();
+    let (worker_send, main_recv) = channel::<WorkerUpdate>();
+    let denoise_enabled = Arc::new(AtomicBool::new(false));
 
-    let preset = preset.to_string();
+    let source = source.clone();
+    let worker_denoise_enabled = denoise_enabled.clone();
     thread::spawn(move || {
         let mut rng = params.new_rng();
 
         let storage = Storage::new(&mut rng);
         let (hitables, camera, sky) =
-            presets::from_name(&preset, &params, &mut rng, &storage).expect("unrecognised preset");
+            source
+                .load(&params, &mut rng, &storage)
+                .unwrap_or_else(|err| {
+                    eprintln!("Failed to load scene: {}", err);
+                    process::exit(1)
+                });
 
-        let scene = params.new_scene(&mut rng, &storage, hitables, sky);
+        let mut scene = params.new_scene(&mut rng, &storage, hitables, &camera, sky);
+        // animated instances are moved from where the preset placed them
+        let animation = source.animation();
+        let instances: Vec<&Instance> = (0..scene.num_instances())
+            .map(|index| scene.instance(index))
+            .collect();
+        // features are always gathered so denoising can be toggled without restarting
+        let mut aov_buffer = AovBuffer::new(&scene.world(), params.width, params.height);
+        let tiles = params.tiles();
 
         let mut frame_num = 0;
         let mut elapsed_count = 0;
         let mut elapsed_secs = 0.0;
-        let mut ray_count = 0;
+        let mut stats = TraceStats::default();
+        let mut bvh_rebuilds = 0;
         loop {
             let rgb_buffer = worker_recv.recv().unwrap();
             if let Some(mut rgb_buffer) = rgb_buffer {
                 let start_time = SystemTime::now();
-                ray_count += scene.update(&params, &camera, frame_num, &mut rgb_buffer);
+                if let Some(animation) = animation {
+                    let time = frame_num as f32 * ANIMATION_FRAME_SECS;
+                    for (index, instance) in instances.iter().enumerate() {
+                        scene.set_instance_transform(index, animation(instance, time));
+                    }
+                    if scene.update_bvh() == Some(TlasUpdate::Rebuilt) {
+                        bvh_rebuilds += 1;
+                    }
+                }
+                stats.add(&scene.update(
+                    &params,
+                    &camera,
+                    // frames of a moving scene can't be blended together
+                    if animation.is_some() { 0 } else { frame_num },
+                    &tiles,
+                    &mut rgb_buffer,
+                    Some(&mut aov_buffer),
+                    |event| {
+                        let update = match event {
+                            TileEvent::Started(tile) => WorkerUpdate::TileStarted(tile),
+                            TileEvent::Finished { tile, pixels, .. } => {
+                                WorkerUpdate::TileFinished(tile, pixels.to_vec())
+                            }
+                        };
+                        // the window may already be closing
+                        let _ = worker_send.send(update);
+                    },
+                ));
                 frame_num += 1;
                 elapsed_count += 1;
 
@@ -112,22 +203,39 @@ pub fn start_loop<'a>(preset: &str, params: Params, max_frames: Option<u32>) {
                 const RATE: u32 = 10;
 
                 if elapsed_secs > 10.0 || elapsed_count == RATE {
-                    let million_ray_count = ray_count as f64 / 1_000_000.0;
+                    let million_ray_count = stats.ray_count as f64 / 1_000_000.0;
 
                     println!(
-                        "{:.2}secs {:.2}Mrays/s {:.2}Mrays/frame {} frames",
+                        "{:.2}secs {:.2}Mrays/s {:.2}Mrays/frame {:.2} mean path depth {} frames",
                         elapsed_secs / elapsed_count as f64,
                         million_ray_count / elapsed_secs,
                         million_ray_count / elapsed_count as f64,
+                        stats.mean_path_depth(),
                         frame_num
                     );
+                    if animation.is_some() {
+                        println!("{} BVH rebuilds", bvh_rebuilds);
+                        bvh_rebuilds = 0;
+                    }
 
                     elapsed_secs = 0.0;
                     elapsed_count = 0;
-                    ray_count = 0;
+                    stats = TraceStats::default();
                 }
 
-                worker_send.send(rgb_buffer).unwrap();
+                let denoised = if worker_denoise_enabled.load(Ordering::Relaxed) {
+                    Some(denoise::denoise(
+                        params.width,
+                        params.height,
+                        &rgb_buffer,
+                        &aov_buffer.pixels,
+                    ))
+                } else {
+                    None
+                };
+                worker_send
+                    .send(WorkerUpdate::Frame(rgb_buffer, denoised))
+                    .unwrap();
             } else {
                 break;
             }
@@ -148,9 +256,17 @@ pub fn start_loop<'a>(preset: &str, params: Params, max_frames: Option<u32>) {
                     }
                     WindowEvent::KeyboardInput { input, .. } => {
                         if let ElementState::Released = input.state {
-                            if let Some(VirtualKeyCode::Escape) = input.virtual_keycode {
-                                quit = true;
-                                save = true;
+                            match input.virtual_keycode {
+                                Some(VirtualKeyCode::Escape) => {
+                                    quit = true;
+                                    save = true;
+                                }
+                                Some(VirtualKeyCode::D) => {
+                                    let enabled = !denoise_enabled.load(Ordering::Relaxed);
+                                    denoise_enabled.store(enabled, Ordering::Relaxed);
+                                    println!("denoising {}", if enabled { "on" } else { "off" });
+                                }
+                                _ => (),
                             }
                         }
                     }
@@ -164,52 +280,62 @@ pub fn start_loop<'a>(preset: &str, params: Params, max_frames: Option<u32>) {
         }
 
         // if we own the buffer then send it back to the worker thread
-        if let Some(rgb_buffer) = rgb_buffer {
+        if let Some(rgb_buffer) = rgb_buffer.take() {
             // send data to worker thread
             main_send.send(Some(rgb_buffer)).unwrap();
         }
 
-        // poll the worker thread to see if it's done
-        rgb_buffer = match main_recv.recv_timeout(Duration::from_millis(100)) {
-            Ok(rgb_buffer) => {
-                // data received - copy to buffer texture
-                {
-                    let mut mapping = buffer_texture.map();
-                    for (texel, rgb) in mapping.iter_mut().zip(rgb_buffer.iter()) {
-                        *texel = (
-                            (255.99 * rgb.0.min(1.0).max(0.0)) as u8,
-                            (255.99 * rgb.1.min(1.0).max(0.0)) as u8,
-                            (255.99 * rgb.2.min(1.0).max(0.0)) as u8,
-                            255,
-                        );
+        // poll the worker thread for finished tiles or frames
+        let updates: Vec<WorkerUpdate> = match main_recv.recv_timeout(Duration::from_millis(100)) {
+            Ok(update) => iter::once(update).chain(main_recv.try_iter()).collect(),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        // data received - copy to buffer texture
+        {
+            let mut mapping = buffer_texture.map();
+            for update in updates {
+                match update {
+                    WorkerUpdate::TileStarted(tile) => mark_tile(&mut mapping, params.width, &tile),
+                    WorkerUpdate::TileFinished(tile, pixels) => {
+                        for (index, rgb) in tile.pixel_indices(params.width).zip(pixels) {
+                            let srgb = params.display.apply(rgb);
+                            mapping[index] = (srgb.0, srgb.1, srgb.2, 255);
+                        }
                     }
-                }
+                    WorkerUpdate::Frame(frame, denoised) => {
+                        let display_buffer = denoised.as_ref().unwrap_or(&frame);
+                        for (texel, rgb) in mapping.iter_mut().zip(display_buffer.iter()) {
+                            let srgb = params.display.apply(*rgb);
+                            *texel = (srgb.0, srgb.1, srgb.2, 255);
+                        }
 
-                // only draw the buffer if we just recieved it
-                let mut target = display.draw();
-                target
-                    .draw(
-                        EmptyVertexAttributes { len: 4 },
-                        NoIndices(PrimitiveType::TriangleStrip),
-                        &program,
-                        &uniform! { tex: &buffer_texture, stride: params.width as i32 },
-                        &Default::default(),
-                    )
-                    .unwrap();
-                target.finish().unwrap();
+                        frame_num += 1;
+                        if let Some(max_frames) = max_frames {
+                            if frame_num >= max_frames {
+                                quit = true;
+                            }
+                        }
 
-                frame_num += 1;
-                if let Some(max_frames) = max_frames {
-                    if frame_num >= max_frames {
-                        quit = true;
+                        rgb_buffer = Some(frame);
                     }
                 }
-
-                Some(rgb_buffer)
             }
-            Err(RecvTimeoutError::Timeout) => None,
-            Err(RecvTimeoutError::Disconnected) => break,
-        };
+        }
+
+        // only draw the buffer if we just recieved something
+        let mut target = display.draw();
+        target
+            .draw(
+                EmptyVertexAttributes { len: 4 },
+                NoIndices(PrimitiveType::TriangleStrip),
+                &program,
+                &uniform! { tex: &buffer_texture, stride: params.width as i32 },
+                &Default::default(),
+            )
+            .unwrap();
+        target.finish().unwrap();
     }
 
     if save {
@@ -0,0 +1,155 @@
+use crate::{
+    collision::{Hitable, Ray, Rect, Sphere},
+    material::Material,
+    sampler::Sampler,
+    scene::{MAX_T, MIN_T},
+    simd::sinf_cosf,
+};
+use glam::{vec3, Vec3};
+use std::f32;
+
+/// An emissive hitable that directions can be sampled towards for next event estimation.
+#[derive(Copy, Clone, Debug)]
+pub enum Light<'a> {
+    Sphere(&'a Sphere),
+    Rect(&'a Rect),
+}
+
+impl<'a> Light<'a> {
+    /// Returns the solid angle probability density of `random_direction` returning `direction`
+    /// from `origin`, this is zero for directions that miss the light.
+    pub fn pdf_value(&self, origin: Vec3, direction: Vec3) -> f32 {
+        let ray = Ray::new(origin, direction, 0.0);
+        match self {
+            Light::Sphere(sphere) => {
+                if sphere.ray_hit(&ray, MIN_T, MAX_T).is_none() {
+                    return 0.0;
+                }
+                match sphere_one_minus_cos_theta_max(sphere, origin) {
+                    Some(one_minus_cos_theta_max) => {
+                        1.0 / (2.0 * f32::consts::PI * one_minus_cos_theta_max)
+                    }
+                    None => 0.0,
+                }
+            }
+            Light::Rect(rect) => {
+                if let Some(ray_hit) = rect.ray_hit(&ray, MIN_T, MAX_T) {
+                    let length_squared = direction.length_squared();
+                    let distance_squared = ray_hit.t * ray_hit.t * length_squared;
+                    let cosine = direction.dot(ray_hit.normal).abs() / length_squared.sqrt();
+                    let pdf = distance_squared / (cosine * rect.area());
+                    if pdf.is_finite() {
+                        return pdf;
+                    }
+                }
+                0.0
+            }
+        }
+    }
+
+    /// Returns a normalized direction from `origin` towards a random point on the light, or
+    /// `None` if `origin` is inside it.
+    pub fn random_direction(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Option<Vec3> {
+        match self {
+            Light::Sphere(sphere) => {
+                // uniformly sample the cone of directions the sphere subtends
+                let one_minus_cos_theta_max = sphere_one_minus_cos_theta_max(sphere, origin)?;
+                let w = (sphere.centre() - origin).normalize();
+                let (u, v) = orthonormal_basis(w);
+                let (a, b) = sampler.next_2d();
+                let z = 1.0 - a * one_minus_cos_theta_max;
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let (sinp, cosp) = sinf_cosf(2.0 * f32::consts::PI * b);
+                Some(u * (r * cosp) + v * (r * sinp) + w * z)
+            }
+            Light::Rect(rect) => {
+                let to_light = rect.sample_point(sampler.next_2d()) - origin;
+                if to_light.length_squared() > 0.0 {
+                    Some(to_light.normalize())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Returns `1 - cos(theta_max)` where `theta_max` is the half angle of the cone `sphere`
+/// subtends from `origin`, or `None` if `origin` is inside the sphere.
+#[inline]
+fn sphere_one_minus_cos_theta_max(sphere: &Sphere, origin: Vec3) -> Option<f32> {
+    let distance_squared = (sphere.centre() - origin).length_squared();
+    let x = sphere.radius() * sphere.radius() / distance_squared;
+    if x < 1.0 {
+        // rearranged from 1 - sqrt(1 - x) to avoid cancellation for small or distant spheres
+        Some(x / (1.0 + (1.0 - x).sqrt()))
+    } else {
+        None
+    }
+}
+
+#[inline]
+fn orthonormal_basis(w: Vec3) -> (Vec3, Vec3) {
+    let a = if w.x.abs() > 0.9 {
+        vec3(0.0, 1.0, 0.0)
+    } else {
+        vec3(1.0, 0.0, 0.0)
+    };
+    let v = w.cross(a).normalize();
+    let u = w.cross(v);
+    (u, v)
+}
+
+/// The lights in a scene, sampled uniformly.
+#[derive(Debug, Default)]
+pub struct Lights<'a> {
+    lights: Vec<Light<'a>>,
+}
+
+impl<'a> Lights<'a> {
+    /// Collects the spheres and rects with a diffuse light material, including those nested in
+    /// lists. Any other emissive hitables are still found by scattered rays but never sampled.
+    pub fn from_hitables(hitables: &[Hitable<'a>]) -> Lights<'a> {
+        let mut lights = Lights::default();
+        lights.collect(hitables);
+        lights
+    }
+
+    fn collect(&mut self, hitables: &[Hitable<'a>]) {
+        for hitable in hitables {
+            match *hitable {
+                Hitable::Sphere(sphere, Material::DiffuseLight { .. }) => {
+                    self.lights.push(Light::Sphere(sphere))
+                }
+                Hitable::Rect(rect, Material::DiffuseLight { .. }) => {
+                    self.lights.push(Light::Rect(rect))
+                }
+                Hitable::List(list) => self.collect(list.hitables()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the probability density of `random_direction` returning `direction`, which may
+    /// have been sampled from any of the lights.
+    pub fn pdf_value(&self, origin: Vec3, direction: Vec3) -> f32 {
+        if self.lights.is_empty() {
+            return 0.0;
+        }
+        let sum: f32 = self
+            .lights
+            .iter()
+            .map(|light| light.pdf_value(origin, direction))
+            .sum();
+        sum / self.lights.len() as f32
+    }
+
+    /// Returns a direction from `origin` towards a randomly chosen light.
+    pub fn random_direction(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Option<Vec3> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let index = (sampler.next_1d() * self.lights.len() as f32) as usize;
+        self.lights[index.min(self.lights.len() - 1)].random_direction(origin, sampler)
+    }
+}
@@ -4,23 +4,52 @@
 #[cfg(feature = "bench")]
 extern crate test;
 
-#[cfg(feature = "bench")]
+mod adaptive;
+mod aov;
+#[cfg(any(feature = "bench", test))]
 mod bench;
 mod camera;
+mod checkpoint;
 mod collision;
+mod denoise;
+mod distributed;
+mod film;
 mod glium_window;
+mod light;
 mod material;
 mod math;
+mod obj;
 mod offline;
+mod output;
 mod params;
 mod perlin;
 mod presets;
+mod sampler;
 mod scene;
+mod scene_file;
 mod simd;
+mod source;
 mod storage;
 mod texture;
+mod tiles;
 
-use clap::{value_t, App, Arg};
+use adaptive::AdaptiveSampling;
+use aov::Aov;
+use checkpoint::Checkpoint;
+use clap::{value_t, values_t, App, Arg};
+use collision::BVHBuilder;
+use film::{DisplayTransform, ToneMap};
+use offline::{OutputOptions, SaveIntervals, StopConditions};
+use output::ImageFormat;
+use params::Accel;
+use sampler::SamplerKind;
+use scene::Integrator;
+use source::SceneSource;
+use std::{
+    path::{Path, PathBuf},
+    process,
+};
+use tiles::TileOrder;
 
 fn main() {
     let matches = App::new("Toy Path Tracer")
@@ -37,12 +66,21 @@ fn main() {
                 .long("height")
                 .takes_value(true),
             Arg::with_name("samples")
-                .help("Number of samples per pixel")
+                .help("Number of samples per pixel, the minimum with --noise-threshold")
                 .short("S")
                 .long("samples")
                 .takes_value(true),
+            Arg::with_name("noise-threshold")
+                .help("Sample pixels adaptively until their relative error is below this")
+                .long("noise-threshold")
+                .takes_value(true),
+            Arg::with_name("max-samples")
+                .help("Most samples per pixel to take with --noise-threshold")
+                .long("max-samples")
+                .takes_value(true)
+                .requires("noise-threshold"),
             Arg::with_name("depth")
-                .help("Maximum bounces per ray")
+                .help("Bounces per ray before paths may be terminated by Russian roulette")
                 .short("D")
                 .long("depth")
                 .takes_value(true),
@@ -55,6 +93,20 @@ fn main() {
                 .short("P")
                 .long("preset")
                 .takes_value(true),
+            Arg::with_name("obj")
+                .help("Wavefront OBJ file to render instead of a preset")
+                .long("obj")
+                .takes_value(true)
+                .conflicts_with("preset"),
+            Arg::with_name("scene")
+                .help("JSON scene description file to render instead of a preset")
+                .long("scene")
+                .takes_value(true)
+                .conflicts_with_all(&["preset", "obj"]),
+            Arg::with_name("export-scene")
+                .help("Write the loaded scene to a JSON scene description file and exit")
+                .long("export-scene")
+                .takes_value(true),
             Arg::with_name("frames")
                 .help("Process a fixed number of frames and exit")
                 .short("F")
@@ -64,34 +116,234 @@ fn main() {
                 .help("Use bounding volume hierarchy instead of a flat list")
                 .short("B")
                 .long("bvh"),
+            Arg::with_name("accel")
+                .help("Acceleration structure to trace rays through")
+                .long("accel")
+                .takes_value(true)
+                .possible_values(&Accel::NAMES)
+                .conflicts_with("bvh"),
+            Arg::with_name("bvh-builder")
+                .help("How to split bounding volume hierarchy nodes")
+                .long("bvh-builder")
+                .takes_value(true)
+                .possible_values(&BVHBuilder::NAMES),
+            Arg::with_name("integrator")
+                .help("How to estimate the light arriving along each ray")
+                .long("integrator")
+                .takes_value(true)
+                .possible_values(&Integrator::NAMES),
+            Arg::with_name("sampler")
+                .help("How to generate the sample values paths are traced with")
+                .long("sampler")
+                .takes_value(true)
+                .possible_values(&SamplerKind::NAMES),
+            Arg::with_name("tile-size")
+                .help("Width and height in pixels of the tiles frames are rendered in")
+                .long("tile-size")
+                .takes_value(true),
+            Arg::with_name("tile-order")
+                .help("Order tiles are rendered in")
+                .long("tile-order")
+                .takes_value(true)
+                .possible_values(&TileOrder::NAMES),
+            Arg::with_name("crop")
+                .help("Only render the region x0,y0,x1,y1 from the top left, in pixels or fractions of the image")
+                .long("crop")
+                .takes_value(true)
+                .allow_hyphen_values(true),
+            Arg::with_name("exposure")
+                .help("Exposure adjustment in stops applied before tone mapping")
+                .long("exposure")
+                .takes_value(true)
+                .allow_hyphen_values(true),
+            Arg::with_name("tone-map")
+                .help("How to compress radiance into the displayable range")
+                .long("tone-map")
+                .takes_value(true)
+                .possible_values(&ToneMap::NAMES),
             Arg::with_name("offline")
                 .help("Don't create a preview render window")
                 .short("O")
                 .long("offline"),
+            Arg::with_name("output")
+                .help("Image file to write the offline render to, png, exr, hdr or pfm")
+                .long("output")
+                .takes_value(true)
+                .requires("offline")
+                .validator(|path| match ImageFormat::from_path(Path::new(&path)) {
+                    Some(_) => Ok(()),
+                    None => Err(format!(
+                        "unsupported image format, expected one of {}",
+                        ImageFormat::EXTENSIONS.join(", ")
+                    )),
+                }),
+            Arg::with_name("aov")
+                .help("Auxiliary outputs to write with the offline render")
+                .long("aov")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .requires("offline")
+                .possible_values(&Aov::NAMES),
+            Arg::with_name("denoise")
+                .help("Denoise the offline render guided by first hit albedo, normal and depth")
+                .long("denoise")
+                .requires("offline"),
+            Arg::with_name("full-canvas")
+                .help("Write the whole image with the --crop region filled in rather than just the region")
+                .long("full-canvas")
+                .requires_all(&["offline", "crop"]),
+            Arg::with_name("max-passes")
+                .help("Most passes over the image to blend in the offline render")
+                .long("max-passes")
+                .takes_value(true)
+                .requires("offline"),
+            Arg::with_name("time-limit")
+                .help("Seconds after which the offline render starts no more passes")
+                .long("time-limit")
+                .takes_value(true)
+                .requires("offline"),
+            Arg::with_name("target-noise")
+                .help("Relative error of the offline render below which it starts no more passes")
+                .long("target-noise")
+                .takes_value(true)
+                .requires("offline"),
+            Arg::with_name("output-interval")
+                .help("Seconds between writing the offline render so far to the output")
+                .long("output-interval")
+                .takes_value(true),
+            Arg::with_name("checkpoint")
+                .help("File to save checkpoints of the offline render to, the output with a .checkpoint extension by default")
+                .long("checkpoint")
+                .takes_value(true)
+                .requires("offline"),
+            Arg::with_name("checkpoint-interval")
+                .help("Seconds between saving checkpoints of the offline render")
+                .long("checkpoint-interval")
+                .takes_value(true),
+            Arg::with_name("resume")
+                .help("Continue an offline render from a checkpoint file")
+                .long("resume")
+                .takes_value(true)
+                .conflicts_with_all(&["preset", "obj", "scene", "export-scene", "print"]),
+            Arg::with_name("serve")
+                .help("Render offline on workers connecting to this address, e.g. 0.0.0.0:7878")
+                .long("serve")
+                .takes_value(true)
+                .requires("offline"),
+            Arg::with_name("worker")
+                .help("Render tiles for the coordinator at this address")
+                .long("worker")
+                .takes_value(true)
+                .conflicts_with_all(&["offline", "resume", "export-scene", "print"]),
             Arg::with_name("print")
-                .help("Debug print a ray trace and exit")
+                .help("Debug print the BVH's stats and a ray trace and exit")
                 .short("X")
                 .long("print"),
         ])
         .get_matches();
 
-    let params = params::Params {
-        width: value_t!(matches, "width", u32).unwrap_or(1280),
-        height: value_t!(matches, "height", u32).unwrap_or(720),
-        samples: value_t!(matches, "samples", u32).unwrap_or(4),
-        max_depth: value_t!(matches, "depth", u32).unwrap_or(10),
-        random_seed: matches.is_present("random"),
-        use_bvh: matches.is_present("bvh"),
+    let source = if let Some(path) = matches.value_of("obj") {
+        SceneSource::Obj(path.into())
+    } else if let Some(path) = matches.value_of("scene") {
+        SceneSource::File(path.into())
+    } else {
+        SceneSource::Preset(
+            matches
+                .value_of("preset")
+                .unwrap_or("two_perlin_spheres")
+                .to_string(),
+        )
     };
 
-    let preset = matches.value_of("preset").unwrap_or("two_perlin_spheres");
+    // animated presets move their instances, which needs them in a BVH
+    let animated = source.animation().is_some();
+
+    let samples = value_t!(matches, "samples", u32).unwrap_or(4);
+    let width = value_t!(matches, "width", u32).unwrap_or(1280);
+    let height = value_t!(matches, "height", u32).unwrap_or(720);
+    let params = params::Params {
+        width,
+        height,
+        samples,
+        adaptive: value_t!(matches, "noise-threshold", f32)
+            .ok()
+            .map(|noise_threshold| AdaptiveSampling {
+                noise_threshold,
+                max_samples: value_t!(matches, "max-samples", u32).unwrap_or(samples * 16),
+            }),
+        min_depth: value_t!(matches, "depth", u32).unwrap_or(10),
+        seed: if matches.is_present("random") {
+            rand::random()
+        } else {
+            0
+        },
+        accel: value_t!(matches, "accel", Accel).unwrap_or(
+            if matches.is_present("bvh") || animated {
+                Accel::Bvh
+            } else {
+                Accel::List
+            },
+        ),
+        bvh_builder: value_t!(matches, "bvh-builder", BVHBuilder).unwrap_or(BVHBuilder::Sah),
+        integrator: value_t!(matches, "integrator", Integrator).unwrap_or(Integrator::Mis),
+        sampler: value_t!(matches, "sampler", SamplerKind).unwrap_or(SamplerKind::Sobol),
+        tile_size: value_t!(matches, "tile-size", u32).unwrap_or(32),
+        tile_order: value_t!(matches, "tile-order", TileOrder).unwrap_or(TileOrder::Spiral),
+        crop: matches.value_of("crop").map(|crop| {
+            tiles::parse_crop(crop, width, height).unwrap_or_else(|err| {
+                eprintln!("Invalid --crop: {}", err);
+                process::exit(1)
+            })
+        }),
+        display: DisplayTransform {
+            exposure: value_t!(matches, "exposure", f32).unwrap_or(0.0),
+            tone_map: value_t!(matches, "tone-map", ToneMap).unwrap_or(ToneMap::Clamp),
+        },
+    };
 
-    if matches.is_present("print") {
-        offline::print_ray_trace(preset, params);
+    let intervals = SaveIntervals {
+        checkpoint_secs: value_t!(matches, "checkpoint-interval", f64).unwrap_or(300.0),
+        output_secs: value_t!(matches, "output-interval", f64).ok(),
+    };
+    let render_offline = |checkpoint, checkpoint_path: &Path| match matches.value_of("serve") {
+        Some(addr) => distributed::serve(addr, checkpoint, checkpoint_path, intervals),
+        None => offline::render_offline(checkpoint, checkpoint_path, intervals),
+    };
+    if let Some(addr) = matches.value_of("worker") {
+        distributed::work(addr);
+    } else if let Some(path) = matches.value_of("resume") {
+        let path = Path::new(path);
+        render_offline(offline::load_checkpoint(path), path);
+    } else if let Some(path) = matches.value_of("export-scene") {
+        offline::export_scene(&source, params, Path::new(path));
+    } else if matches.is_present("print") {
+        offline::print_ray_trace(&source, params);
     } else if matches.is_present("offline") {
-        offline::render_offline(preset, params);
+        let output = OutputOptions {
+            path: matches.value_of("output").unwrap_or("output.png").into(),
+            aovs: values_t!(matches, "aov", Aov).unwrap_or_default(),
+            denoise: matches.is_present("denoise"),
+            full_canvas: matches.is_present("full-canvas"),
+        };
+        let stop = StopConditions {
+            max_passes: value_t!(matches, "max-passes", u32).ok(),
+            time_limit_secs: value_t!(matches, "time-limit", f64).ok(),
+            target_noise: value_t!(matches, "target-noise", f32).ok(),
+        };
+        let checkpoint_path = matches
+            .value_of("checkpoint")
+            .map_or_else(|| output.path.with_extension("checkpoint"), PathBuf::from);
+        render_offline(
+            Checkpoint::new(source, params, output, stop),
+            &checkpoint_path,
+        );
     } else {
+        if animated && matches!(params.accel, Accel::List | Accel::Soa) {
+            eprintln!("Animated presets need a BVH --accel to move their instances");
+            process::exit(1);
+        }
         let max_frames = value_t!(matches, "frames", u32).ok().and_then(Some);
-        glium_window::start_loop(preset, params, max_frames);
+        glium_window::start_loop(&source, params, max_frames);
     }
 }
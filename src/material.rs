@@ -1,14 +1,14 @@
 use crate::{
-    collision::{Ray, RayHit},
-    math::{random_in_unit_sphere, random_unit_vector, reflect, refract, schlick},
+    collision::RayHit,
+    math::{reflect, refract, sample_unit_ball, sample_unit_vector, schlick},
+    sampler::Sampler,
     texture::Texture,
 };
-use glam::{vec3, Vec3};
-use rand::Rng;
-use rand_xoshiro::Xoshiro256Plus;
+use glam::Vec3;
 use std::f32;
 
-// #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+const FRAC_1_4PI: f32 = 0.25 * f32::consts::FRAC_1_PI;
+
 #[derive(Clone, Copy, Debug)]
 pub enum Material<'a> {
     Lambertian { albedo: &'a Texture<'a> },
@@ -48,112 +48,195 @@ fn get_sphere_uv(normal: Vec3) -> (f32, f32) {
     return (u, v);
 }
 
+/// A direction sampled from a material's BSDF.
+#[derive(Clone, Copy, Debug)]
+pub struct BsdfSample {
+    /// The normalized direction light is scattered from, pointing away from the hit point.
+    pub wi: Vec3,
+    /// The BSDF value for `wi`, see `Material::f`. For delta lobes this is the attenuation
+    /// scaled by the probability of choosing the lobe.
+    pub f: Vec3,
+    /// The solid angle density `wi` was sampled with, or for delta lobes the probability of
+    /// choosing the lobe.
+    pub pdf: f32,
+    /// True if `wi` came from a delta lobe, which `Material::f` and `Material::pdf` return zero
+    /// for.
+    pub is_delta: bool,
+}
+
+impl BsdfSample {
+    /// The factor the light arriving along `wi` is scaled by.
+    #[inline]
+    pub fn weight(&self) -> Vec3 {
+        self.f / self.pdf
+    }
+}
+
 impl<'a> Material<'a> {
-    fn scatter_lambertian(
+    fn sample_lambertian(
         albedo: &Texture,
-        ray_in: &Ray,
         ray_hit: &RayHit,
-        rng: &mut Xoshiro256Plus,
-    ) -> Option<(Vec3, Ray)> {
-        let target = ray_hit.point + ray_hit.normal + random_unit_vector(rng);
-        Some((
-            albedo.value(ray_hit.u, ray_hit.v, ray_hit.point),
-            Ray::new(
-                ray_hit.point,
-                (target - ray_hit.point).normalize(),
-                ray_in.time,
-            ),
-        ))
-    }
-
-    fn scatter_metal(
-        albedo: &Vec3,
+        sampler: &mut dyn Sampler,
+    ) -> Option<BsdfSample> {
+        let wi = (ray_hit.normal + sample_unit_vector(sampler.next_2d())).normalize();
+        let pdf = wi.dot(ray_hit.normal) * f32::consts::FRAC_1_PI;
+        if pdf > 0.0 {
+            Some(BsdfSample {
+                wi,
+                f: albedo.value(ray_hit.u, ray_hit.v, ray_hit.point) * pdf,
+                pdf,
+                is_delta: false,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn sample_metal(
+        albedo: Vec3,
         fuzz: f32,
-        ray_in: &Ray,
         ray_hit: &RayHit,
-        rng: &mut Xoshiro256Plus,
-    ) -> Option<(Vec3, Ray)> {
-        let reflected = reflect(ray_in.direction, ray_hit.normal);
+        wo: Vec3,
+        sampler: &mut dyn Sampler,
+    ) -> Option<BsdfSample> {
+        let reflected = reflect(-wo, ray_hit.normal);
         if reflected.dot(ray_hit.normal) > 0.0 {
-            Some((
-                *albedo,
-                Ray::new(
-                    ray_hit.point,
-                    (reflected + fuzz * random_in_unit_sphere(rng)).normalize(),
-                    ray_in.time,
-                ),
-            ))
+            Some(BsdfSample {
+                wi: (reflected + fuzz * sample_unit_ball(sampler.next_2d(), sampler.next_1d()))
+                    .normalize(),
+                f: albedo,
+                pdf: 1.0,
+                is_delta: true,
+            })
         } else {
             None
         }
     }
 
-    fn scatter_dielectric(
+    fn sample_dielectric(
         ref_idx: f32,
-        ray_in: &Ray,
         ray_hit: &RayHit,
-        rng: &mut Xoshiro256Plus,
-    ) -> Option<(Vec3, Ray)> {
-        let attenuation = vec3(1.0, 1.0, 1.0);
-        let rdotn = ray_in.direction.dot(ray_hit.normal);
+        wo: Vec3,
+        sampler: &mut dyn Sampler,
+    ) -> Option<BsdfSample> {
+        let direction = -wo;
+        let rdotn = direction.dot(ray_hit.normal);
         let (outward_normal, ni_over_nt, cosine) = if rdotn > 0.0 {
-            let cosine = rdotn / ray_in.direction.length();
-            let cosine = (1.0 - ref_idx * ref_idx * (1.0 - cosine * cosine)).sqrt();
+            let cosine = (1.0 - ref_idx * ref_idx * (1.0 - rdotn * rdotn)).sqrt();
             (-ray_hit.normal, ref_idx, cosine)
         } else {
-            let cosine = -rdotn / ray_in.direction.length();
-            (ray_hit.normal, 1.0 / ref_idx, cosine)
+            (ray_hit.normal, 1.0 / ref_idx, -rdotn)
         };
-        if let Some(refracted) = refract(ray_in.direction, outward_normal, ni_over_nt) {
-            let reflect_prob = schlick(cosine, ref_idx);
-            if rng.gen::<f32>() > reflect_prob {
-                return Some((
-                    attenuation,
-                    Ray::new(ray_hit.point, refracted.normalize(), ray_in.time),
-                ));
+        // choose between reflection and refraction by the Fresnel reflectance, so each lobe's
+        // probability cancels its attenuation. The sample is always taken so total internal
+        // reflection doesn't shift the dimensions of the rest of the path.
+        let choice = sampler.next_1d();
+        let mut reflect_prob = 1.0;
+        if let Some(refracted) = refract(direction, outward_normal, ni_over_nt) {
+            reflect_prob = schlick(cosine, ref_idx);
+            if choice > reflect_prob {
+                let refract_prob = 1.0 - reflect_prob;
+                return Some(BsdfSample {
+                    wi: refracted.normalize(),
+                    f: Vec3::splat(refract_prob),
+                    pdf: refract_prob,
+                    is_delta: true,
+                });
             }
         }
-        Some((
-            attenuation,
-            Ray::new(
-                ray_hit.point,
-                reflect(ray_in.direction, ray_hit.normal).normalize(),
-                ray_in.time,
-            ),
-        ))
+        Some(BsdfSample {
+            wi: reflect(direction, ray_hit.normal).normalize(),
+            f: Vec3::splat(reflect_prob),
+            pdf: reflect_prob,
+            is_delta: true,
+        })
     }
 
-    fn scatter_isotropic(
+    fn sample_isotropic(
         albedo: &Texture,
-        ray_in: &Ray,
         ray_hit: &RayHit,
-        rng: &mut Xoshiro256Plus,
-    ) -> Option<(Vec3, Ray)> {
-        Some((
-            albedo.value(ray_hit.u, ray_hit.v, ray_hit.point),
-            Ray::new(ray_hit.point, random_in_unit_sphere(rng), ray_in.time),
-        ))
+        sampler: &mut dyn Sampler,
+    ) -> Option<BsdfSample> {
+        Some(BsdfSample {
+            wi: sample_unit_vector(sampler.next_2d()),
+            f: albedo.value(ray_hit.u, ray_hit.v, ray_hit.point) * FRAC_1_4PI,
+            pdf: FRAC_1_4PI,
+            is_delta: false,
+        })
+    }
+
+    /// Returns true if the material only scatters into delta lobes, such as perfect specular
+    /// reflection, which can't be evaluated for an arbitrary direction.
+    ///
+    /// Metal's fuzzed reflection isn't a true delta lobe but has no closed form density, so it
+    /// is treated as one.
+    #[inline]
+    pub fn is_delta(&self) -> bool {
+        match self {
+            Material::Metal { albedo: _, fuzz: _ } | Material::Dielectric { ref_idx: _ } => true,
+            Material::Lambertian { albedo: _ }
+            | Material::Isotropic { albedo: _ }
+            | Material::DiffuseLight { emit: _ } => false,
+        }
+    }
+
+    /// Evaluates the BSDF for light arriving from `wi` and leaving towards `wo`, both normalized
+    /// and pointing away from the hit point.
+    ///
+    /// The result includes the cosine of the angle between `wi` and the surface normal, so the
+    /// light scattered towards `wo` is `f * Li`. Volumes have no cosine term. Delta lobes
+    /// evaluate to zero.
+    pub fn f(&self, ray_hit: &RayHit, wi: Vec3, _wo: Vec3) -> Vec3 {
+        match self {
+            Material::Lambertian { albedo } => {
+                albedo.value(ray_hit.u, ray_hit.v, ray_hit.point)
+                    * Material::pdf_lambertian(ray_hit, wi)
+            }
+            Material::Isotropic { albedo } => {
+                albedo.value(ray_hit.u, ray_hit.v, ray_hit.point) * FRAC_1_4PI
+            }
+            Material::Metal { albedo: _, fuzz: _ }
+            | Material::Dielectric { ref_idx: _ }
+            | Material::DiffuseLight { emit: _ } => Vec3::ZERO,
+        }
+    }
+
+    #[inline]
+    fn pdf_lambertian(ray_hit: &RayHit, wi: Vec3) -> f32 {
+        wi.dot(ray_hit.normal).max(0.0) * f32::consts::FRAC_1_PI
     }
 
-    pub fn scatter(
+    /// Returns the solid angle density of `sample` choosing `wi` for light leaving towards
+    /// `wo`. Delta lobes have zero density.
+    pub fn pdf(&self, ray_hit: &RayHit, wi: Vec3, _wo: Vec3) -> f32 {
+        match self {
+            Material::Lambertian { albedo: _ } => Material::pdf_lambertian(ray_hit, wi),
+            Material::Isotropic { albedo: _ } => FRAC_1_4PI,
+            Material::Metal { albedo: _, fuzz: _ }
+            | Material::Dielectric { ref_idx: _ }
+            | Material::DiffuseLight { emit: _ } => 0.0,
+        }
+    }
+
+    /// Samples the direction light scattered towards `wo` arrives from, `None` if the
+    /// material absorbs it.
+    pub fn sample(
         &self,
-        ray: &Ray,
         ray_hit: &RayHit,
-        rng: &mut Xoshiro256Plus,
-    ) -> Option<(Vec3, Ray)> {
+        wo: Vec3,
+        sampler: &mut dyn Sampler,
+    ) -> Option<BsdfSample> {
         match self {
             Material::Lambertian { albedo } => {
-                Material::scatter_lambertian(albedo, ray, ray_hit, rng)
+                Material::sample_lambertian(albedo, ray_hit, sampler)
             }
             Material::Metal { albedo, fuzz } => {
-                Material::scatter_metal(albedo, *fuzz, ray, ray_hit, rng)
+                Material::sample_metal(*albedo, *fuzz, ray_hit, wo, sampler)
             }
             Material::Dielectric { ref_idx } => {
-                Material::scatter_dielectric(*ref_idx, ray, ray_hit, rng)
-            }
-            Material::Isotropic { albedo } => {
-                Material::scatter_isotropic(albedo, ray, ray_hit, rng)
+                Material::sample_dielectric(*ref_idx, ray_hit, wo, sampler)
             }
+            Material::Isotropic { albedo } => Material::sample_isotropic(albedo, ray_hit, sampler),
             Material::DiffuseLight { emit: _ } => None,
         }
     }
@@ -166,6 +249,21 @@ impl<'a> Material<'a> {
         }
     }
 
+    /// Returns the fraction of light the material reflects at `ray_hit`, ignoring direction.
+    /// Glass is white and lights report their emission clipped to one.
+    pub fn albedo(&self, ray_hit: &RayHit) -> Vec3 {
+        match self {
+            Material::Lambertian { albedo } | Material::Isotropic { albedo } => {
+                albedo.value(ray_hit.u, ray_hit.v, ray_hit.point)
+            }
+            Material::Metal { albedo, fuzz: _ } => *albedo,
+            Material::Dielectric { ref_idx: _ } => Vec3::ONE,
+            Material::DiffuseLight { emit } => emit
+                .value(ray_hit.u, ray_hit.v, ray_hit.point)
+                .min(Vec3::ONE),
+        }
+    }
+
     pub fn get_sphere_uv(&self, normal: Vec3) -> (f32, f32) {
         if let Material::Lambertian { albedo } = self {
             if let Texture::Image { image: _ } = albedo {
@@ -179,3 +277,69 @@ impl<'a> Material<'a> {
         (0.0, 0.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        collision::RayHit,
+        material::{lambertian, Material},
+        math::sample_unit_vector,
+        sampler::Sampler,
+        texture,
+    };
+    use glam::{vec3, Vec3};
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256Plus;
+    use std::f32;
+
+    const FURNACE_SAMPLES: u32 = 100_000;
+
+    fn furnace_hit() -> RayHit {
+        RayHit {
+            point: Vec3::ZERO,
+            normal: Vec3::Y,
+            t: 1.0,
+            u: 0.0,
+            v: 0.0,
+        }
+    }
+
+    /// Estimates the light a white material reflects towards `wo` when uniformly lit from every
+    /// direction, integrating `f` over uniformly distributed directions so it is checked
+    /// independently of `sample`.
+    fn furnace_f(material: &Material, wo: Vec3, rng: &mut Xoshiro256Plus) -> f32 {
+        let ray_hit = furnace_hit();
+        let mut total = 0.0;
+        for _ in 0..FURNACE_SAMPLES {
+            let wi = sample_unit_vector(rng.next_2d());
+            total += material.f(&ray_hit, wi, wo).x * 4.0 * f32::consts::PI;
+        }
+        total / FURNACE_SAMPLES as f32
+    }
+
+    /// Estimates the same as `furnace_f` with the material's own importance sampling.
+    fn furnace_sample(material: &Material, wo: Vec3, rng: &mut Xoshiro256Plus) -> f32 {
+        let ray_hit = furnace_hit();
+        let mut total = 0.0;
+        for _ in 0..FURNACE_SAMPLES {
+            if let Some(sample) = material.sample(&ray_hit, wo, rng) {
+                assert!((sample.pdf - material.pdf(&ray_hit, sample.wi, wo)).abs() < 1e-4);
+                total += sample.weight().x;
+            }
+        }
+        total / FURNACE_SAMPLES as f32
+    }
+
+    #[test]
+    fn lambertian_furnace() {
+        // a white lambertian surface conserves energy, reflecting all of the light it receives
+        let white = texture::constant(Vec3::ONE);
+        let material = lambertian(&white);
+        let wo = vec3(0.3, 0.8, -0.2).normalize();
+        let mut rng = Xoshiro256Plus::seed_from_u64(0);
+        let f_estimate = furnace_f(&material, wo, &mut rng);
+        assert!((f_estimate - 1.0).abs() < 0.02, "{}", f_estimate);
+        let sample_estimate = furnace_sample(&material, wo, &mut rng);
+        assert!((sample_estimate - 1.0).abs() < 1e-4, "{}", sample_estimate);
+    }
+}
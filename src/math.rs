@@ -1,50 +1,54 @@
 use crate::simd::sinf_cosf;
 use glam::{vec3, Vec3};
-use rand::Rng;
 use std::f32;
 
-pub fn random_in_unit_disk<T: Rng>(rng: &mut T) -> Vec3 {
-    loop {
-        let p = 2.0 * vec3(rng.gen(), rng.gen(), 0.0) - vec3(1.0, 1.0, 0.0);
-        if p.dot(p) < 1.0 {
-            return p;
-        }
-    }
-}
+// The sampling functions below map uniform samples in the unit square directly rather than
+// rejecting samples, so that well distributed samples stay well distributed.
 
-pub fn random_in_unit_sphere<T: Rng>(rng: &mut T) -> Vec3 {
-    loop {
-        let p = vec3(
-            2.0 * rng.gen::<f32>() - 1.0,
-            2.0 * rng.gen::<f32>() - 1.0,
-            2.0 * rng.gen::<f32>() - 1.0,
-        );
-        if p.length_squared() < 1.0 {
-            return p;
-        }
+/// Maps a sample in the unit square to a uniformly distributed point in the unit disk on the xy
+/// plane with Shirley and Chiu's concentric mapping.
+pub fn sample_unit_disk((a, b): (f32, f32)) -> Vec3 {
+    let a = 2.0 * a - 1.0;
+    let b = 2.0 * b - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vec3::ZERO;
     }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, f32::consts::FRAC_PI_4 * (b / a))
+    } else {
+        (b, f32::consts::FRAC_PI_2 - f32::consts::FRAC_PI_4 * (a / b))
+    };
+    let (sint, cost) = sinf_cosf(theta);
+    vec3(r * cost, r * sint, 0.0)
 }
 
-pub fn random_unit_vector<T: Rng>(rng: &mut T) -> Vec3 {
-    let z = rng.gen::<f32>() * 2.0 - 1.0;
-    let a = rng.gen::<f32>() * 2.0 * f32::consts::PI;
-    let r = (1.0 - z * z).sqrt();
+/// Maps a sample in the unit square to a uniformly distributed unit vector.
+pub fn sample_unit_vector((a, b): (f32, f32)) -> Vec3 {
+    let z = a * 2.0 - 1.0;
+    let a = b * 2.0 * f32::consts::PI;
+    let r = (1.0 - z * z).max(0.0).sqrt();
     let (sina, cosa) = sinf_cosf(a);
     vec3(r * cosa, r * sina, z)
 }
 
+/// Maps a sample in the unit cube to a uniformly distributed point in the unit sphere.
+pub fn sample_unit_ball(direction: (f32, f32), radius: f32) -> Vec3 {
+    sample_unit_vector(direction) * radius.cbrt()
+}
+
+/// Encodes linear values with the sRGB transfer function, clamping them to `[0, 1]`, and
+/// quantizes them to 8 bits.
 pub fn linear_to_srgb(rgb: (f32, f32, f32)) -> (u8, u8, u8) {
-    let rgb = (rgb.0.max(0.0), rgb.1.max(0.0), rgb.2.max(0.0));
-    let srgb = (
-        (1.055 * rgb.0.powf(0.416_666_66) - 0.055).max(0.0).min(1.0),
-        (1.055 * rgb.1.powf(0.416_666_66) - 0.055).max(0.0).min(1.0),
-        (1.055 * rgb.2.powf(0.416_666_66) - 0.055).max(0.0).min(1.0),
-    );
-    (
-        (srgb.0 * 255.99) as u8,
-        (srgb.1 * 255.99) as u8,
-        (srgb.2 * 255.99) as u8,
-    )
+    let encode = |x: f32| {
+        let x = x.clamp(0.0, 1.0);
+        let srgb = if x <= 0.003_130_8 {
+            12.92 * x
+        } else {
+            1.055 * x.powf(0.416_666_66) - 0.055
+        };
+        (srgb * 255.99) as u8
+    };
+    (encode(rgb.0), encode(rgb.1), encode(rgb.2))
 }
 
 #[inline]
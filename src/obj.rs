@@ -0,0 +1,518 @@
+use crate::{
+    collision::{BVHBuilder, BVHNode, Hitable, TriangleMesh, AABB},
+    material::{self, Material},
+    storage::Storage,
+    texture::{self, RgbImage},
+};
+use glam::{Vec2, Vec3};
+use rand_xoshiro::Xoshiro256Plus;
+use std::{
+    collections::HashMap,
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Image {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    Empty {
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io { path, source } => {
+                write!(f, "failed to read '{}': {}", path.display(), source)
+            }
+            ObjError::Image { path, source } => {
+                write!(f, "failed to load image '{}': {}", path.display(), source)
+            }
+            ObjError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+            ObjError::Empty { path } => write!(f, "'{}' contains no faces", path.display()),
+        }
+    }
+}
+
+impl error::Error for ObjError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ObjError::Io { source, .. } => Some(source),
+            ObjError::Image { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Material description parsed from a `.mtl` file before it is mapped onto a `Material`.
+#[derive(Clone, Debug)]
+struct MtlMaterial {
+    diffuse: Vec3,
+    specular: Vec3,
+    emissive: Vec3,
+    shininess: f32,
+    optical_density: Option<f32>,
+    dissolve: f32,
+    illum: u32,
+    diffuse_map: Option<PathBuf>,
+}
+
+impl Default for MtlMaterial {
+    fn default() -> Self {
+        MtlMaterial {
+            diffuse: Vec3::splat(0.73),
+            specular: Vec3::ZERO,
+            emissive: Vec3::ZERO,
+            shininess: 0.0,
+            optical_density: None,
+            dissolve: 1.0,
+            illum: 2,
+            diffuse_map: None,
+        }
+    }
+}
+
+impl MtlMaterial {
+    /// Maps MTL parameters onto the closest matching built-in material.
+    fn to_material<'a>(&self, storage: &'a Storage<'a>) -> Result<Material<'a>, ObjError> {
+        if self.emissive.max_element() > 0.0 {
+            let emit = storage.alloc_texture(texture::constant(self.emissive));
+            return Ok(material::diffuse_light(emit));
+        }
+        if let Some(ref_idx) = self.optical_density {
+            // illumination models 4, 6, 7 and 9 are the refractive glass models
+            if self.dissolve < 1.0 || matches!(self.illum, 4 | 6 | 7 | 9) {
+                return Ok(material::dielectric(ref_idx));
+            }
+        }
+        if self.specular.max_element() > 0.0
+            && (self.illum == 3 || self.specular.max_element() > self.diffuse.max_element())
+        {
+            // approximate roughness from the Phong exponent
+            let fuzz = (2.0 / (self.shininess + 2.0)).sqrt().min(1.0);
+            return Ok(material::metal(self.specular, fuzz));
+        }
+        let albedo = if let Some(path) = &self.diffuse_map {
+            let image = RgbImage::open(path).map_err(|source| ObjError::Image {
+                path: path.clone(),
+                source,
+            })?;
+            storage.alloc_texture(texture::rgb_image(storage.alloc_image(image)))
+        } else {
+            storage.alloc_texture(texture::constant(self.diffuse))
+        };
+        Ok(material::lambertian(albedo))
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, ObjError> {
+    fs::read_to_string(path).map_err(|source| ObjError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+struct LineParser<'p> {
+    path: &'p Path,
+    line: usize,
+}
+
+impl<'p> LineParser<'p> {
+    fn error<T>(&self, message: String) -> Result<T, ObjError> {
+        Err(ObjError::Parse {
+            path: self.path.to_path_buf(),
+            line: self.line,
+            message,
+        })
+    }
+
+    fn floats<'s, I>(
+        &self,
+        keyword: &str,
+        args: I,
+        min: usize,
+        max: usize,
+    ) -> Result<Vec<f32>, ObjError>
+    where
+        I: Iterator<Item = &'s str>,
+    {
+        let mut values = Vec::with_capacity(max);
+        for arg in args {
+            match arg.parse::<f32>() {
+                Ok(value) => values.push(value),
+                Err(_) => return self.error(format!("invalid number '{}' in '{}'", arg, keyword)),
+            }
+        }
+        if values.len() < min || values.len() > max {
+            return self.error(format!(
+                "'{}' expects {} to {} values, got {}",
+                keyword,
+                min,
+                max,
+                values.len()
+            ));
+        }
+        Ok(values)
+    }
+
+    fn vec3<'s, I>(&self, keyword: &str, args: I) -> Result<Vec3, ObjError>
+    where
+        I: Iterator<Item = &'s str>,
+    {
+        let values = self.floats(keyword, args, 3, 3)?;
+        Ok(Vec3::new(values[0], values[1], values[2]))
+    }
+
+    /// Resolves a 1-based (or negative, relative) OBJ index into a 0-based index.
+    fn index(&self, token: &str, kind: &str, len: usize) -> Result<usize, ObjError> {
+        let index = match token.parse::<i64>() {
+            Ok(index) => index,
+            Err(_) => return self.error(format!("invalid {} index '{}'", kind, token)),
+        };
+        let resolved = if index > 0 {
+            index - 1
+        } else {
+            len as i64 + index
+        };
+        if index == 0 || resolved < 0 || resolved >= len as i64 {
+            return self.error(format!(
+                "{} index {} out of range, {} defined",
+                kind, index, len
+            ));
+        }
+        Ok(resolved as usize)
+    }
+}
+
+fn load_mtl(path: &Path, materials: &mut HashMap<String, MtlMaterial>) -> Result<(), ObjError> {
+    let source = read_file(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut parser = LineParser { path, line: 0 };
+    let mut current: Option<(String, MtlMaterial)> = None;
+    for (line_index, line) in source.lines().enumerate() {
+        parser.line = line_index + 1;
+        let line = line.split('#').next().unwrap().trim();
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        if keyword == "newmtl" {
+            let name = tokens.collect::<Vec<_>>().join(" ");
+            if name.is_empty() {
+                return parser.error("'newmtl' is missing a material name".to_string());
+            }
+            if let Some((name, mtl)) = current.take() {
+                materials.insert(name, mtl);
+            }
+            current = Some((name, MtlMaterial::default()));
+            continue;
+        }
+        let mtl = match current.as_mut() {
+            Some((_, mtl)) => mtl,
+            None => return parser.error(format!("'{}' before 'newmtl'", keyword)),
+        };
+        match keyword {
+            "Kd" => mtl.diffuse = parser.vec3(keyword, tokens)?,
+            "Ks" => mtl.specular = parser.vec3(keyword, tokens)?,
+            "Ke" => mtl.emissive = parser.vec3(keyword, tokens)?,
+            "Ns" => mtl.shininess = parser.floats(keyword, tokens, 1, 1)?[0],
+            "Ni" => mtl.optical_density = Some(parser.floats(keyword, tokens, 1, 1)?[0]),
+            "d" => mtl.dissolve = parser.floats(keyword, tokens, 1, 1)?[0],
+            "Tr" => mtl.dissolve = 1.0 - parser.floats(keyword, tokens, 1, 1)?[0],
+            "illum" => {
+                let value = tokens.next().unwrap_or("");
+                mtl.illum = match value.parse() {
+                    Ok(illum) => illum,
+                    Err(_) => return parser.error(format!("invalid illum '{}'", value)),
+                };
+            }
+            "map_Kd" => {
+                // texture options come before the file name, which is always last
+                match tokens.last() {
+                    Some(file) => mtl.diffuse_map = Some(dir.join(file)),
+                    None => return parser.error("'map_Kd' is missing a file name".to_string()),
+                }
+            }
+            _ => (),
+        }
+    }
+    if let Some((name, mtl)) = current {
+        materials.insert(name, mtl);
+    }
+    Ok(())
+}
+
+/// Triangles for a single `g` or `o` group, vertices are de-duplicated per group.
+#[derive(Default)]
+struct Group {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<Vec2>,
+    indices: Vec<[u32; 3]>,
+    materials: Vec<Option<String>>,
+    vertex_map: HashMap<(usize, Option<usize>, Option<usize>), u32>,
+    missing_normals: bool,
+    missing_uvs: bool,
+}
+
+impl Group {
+    fn vertex(
+        &mut self,
+        key: (usize, Option<usize>, Option<usize>),
+        positions: &[Vec3],
+        uvs: &[Vec2],
+        normals: &[Vec3],
+    ) -> u32 {
+        if let Some(&index) = self.vertex_map.get(&key) {
+            return index;
+        }
+        let (position, uv, normal) = key;
+        self.positions.push(positions[position]);
+        match uv {
+            Some(uv) => self.uvs.push(uvs[uv]),
+            None => {
+                self.missing_uvs = true;
+                self.uvs.push(Vec2::ZERO);
+            }
+        }
+        match normal {
+            Some(normal) => {
+                let normal = normals[normal].normalize_or_zero();
+                // a zero length normal can't shade, the faces' own normals are used instead
+                if normal == Vec3::ZERO {
+                    self.missing_normals = true;
+                }
+                self.normals.push(normal);
+            }
+            None => {
+                self.missing_normals = true;
+                self.normals.push(Vec3::ZERO);
+            }
+        }
+        let index = self.positions.len() as u32 - 1;
+        self.vertex_map.insert(key, index);
+        index
+    }
+}
+
+/// Loads a Wavefront OBJ file and any referenced MTL libraries.
+///
+/// Each group becomes a `TriangleMesh` with its own BVH over its faces. Returns the hitables
+/// along with the bounds of the whole model so callers can frame a camera.
+pub fn load<'a>(
+    path: &Path,
+    builder: BVHBuilder,
+    rng: &mut Xoshiro256Plus,
+    storage: &'a Storage<'a>,
+) -> Result<(Vec<Hitable<'a>>, AABB), ObjError> {
+    let source = read_file(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut parser = LineParser { path, line: 0 };
+
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    let mut normals = Vec::new();
+    let mut mtl_materials = HashMap::new();
+    let mut current_material: Option<String> = None;
+    let mut groups = vec![Group::default()];
+    let mut face = Vec::with_capacity(4);
+
+    for (line_index, line) in source.lines().enumerate() {
+        parser.line = line_index + 1;
+        let line = line.split('#').next().unwrap().trim();
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        match keyword {
+            "v" => {
+                // an optional w component is ignored
+                let values = parser.floats(keyword, tokens, 3, 4)?;
+                positions.push(Vec3::new(values[0], values[1], values[2]));
+            }
+            "vt" => {
+                let values = parser.floats(keyword, tokens, 1, 3)?;
+                uvs.push(Vec2::new(values[0], values.get(1).copied().unwrap_or(0.0)));
+            }
+            "vn" => normals.push(parser.vec3(keyword, tokens)?),
+            "f" => {
+                face.clear();
+                for token in tokens {
+                    let mut parts = token.split('/');
+                    let position =
+                        parser.index(parts.next().unwrap(), "vertex", positions.len())?;
+                    let uv = match parts.next() {
+                        Some("") | None => None,
+                        Some(uv) => Some(parser.index(uv, "texture coordinate", uvs.len())?),
+                    };
+                    let normal = match parts.next() {
+                        Some("") | None => None,
+                        Some(normal) => Some(parser.index(normal, "normal", normals.len())?),
+                    };
+                    if parts.next().is_some() {
+                        return parser.error(format!("malformed face vertex '{}'", token));
+                    }
+                    face.push((position, uv, normal));
+                }
+                if face.len() < 3 {
+                    return parser.error(format!(
+                        "face has {} vertices, at least 3 are required",
+                        face.len()
+                    ));
+                }
+                let group = groups.last_mut().unwrap();
+                let indices: Vec<u32> = face
+                    .iter()
+                    .map(|&key| group.vertex(key, &positions, &uvs, &normals))
+                    .collect();
+                // fan triangulate polygons
+                for i in 1..indices.len() - 1 {
+                    group.indices.push([indices[0], indices[i], indices[i + 1]]);
+                    group.materials.push(current_material.clone());
+                }
+            }
+            "g" | "o" if !groups.last().unwrap().indices.is_empty() => {
+                groups.push(Group::default());
+            }
+            "usemtl" => {
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                if !mtl_materials.contains_key(&name) {
+                    return parser.error(format!("unknown material '{}'", name));
+                }
+                current_material = Some(name);
+            }
+            "mtllib" => {
+                for file in tokens {
+                    load_mtl(&dir.join(file), &mut mtl_materials)?;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut materials: HashMap<Option<String>, &'a Material<'a>> = HashMap::new();
+    let mut hitables = Vec::new();
+    let mut bounds = AABB::invalid();
+    for mut group in groups.into_iter().filter(|group| !group.indices.is_empty()) {
+        let mut face_materials = Vec::with_capacity(group.materials.len());
+        for name in &group.materials {
+            let material = match materials.get(name) {
+                Some(material) => *material,
+                None => {
+                    let mtl = match name {
+                        Some(name) => mtl_materials[name].clone(),
+                        None => MtlMaterial::default(),
+                    };
+                    let material: &'a Material<'a> =
+                        storage.alloc_material(mtl.to_material(storage)?);
+                    materials.insert(name.clone(), material);
+                    material
+                }
+            };
+            face_materials.push(material);
+        }
+        // smooth shading and texture coordinates need every vertex to provide them
+        if group.missing_normals {
+            group.normals.clear();
+        }
+        if group.missing_uvs {
+            group.uvs.clear();
+        }
+        let mesh = storage.alloc_triangle_mesh(TriangleMesh::new(
+            group.positions,
+            group.normals,
+            group.uvs,
+            group.indices,
+            face_materials,
+        ));
+        bounds.add_assign(&mesh.bounding_box());
+        let mut faces = mesh.faces();
+        // meshes are static so any shutter interval gives the same bounds
+        let bvh_root = BVHNode::build(builder, rng, &mut faces, 0.0, 0.0, storage).unwrap();
+        hitables.push(Hitable::BVHNode(bvh_root));
+    }
+
+    if hitables.is_empty() {
+        return Err(ObjError::Empty {
+            path: path.to_path_buf(),
+        });
+    }
+
+    Ok((hitables, bounds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load;
+    use crate::{
+        collision::{BVHBuilder, Ray},
+        storage::Storage,
+    };
+    use glam::Vec3;
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256Plus;
+    use std::{env, fs, path::PathBuf};
+
+    fn write_obj(name: &str, source: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("pathtrace-rs-{}.obj", name));
+        fs::write(&path, source).unwrap();
+        path
+    }
+
+    #[test]
+    fn zero_normal_uses_face_normal() {
+        let path = write_obj(
+            "zero-normal",
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nvn 0 0 0\nf 1//1 2//2 3//1\n",
+        );
+        let mut rng = Xoshiro256Plus::seed_from_u64(0);
+        let storage = Storage::new(&mut rng);
+        let (hitables, _) = load(&path, BVHBuilder::Sah, &mut rng, &storage).unwrap();
+        let ray = Ray::new(Vec3::new(0.25, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let (ray_hit, _) = hitables[0]
+            .ray_hit(&ray, 0.001, f32::MAX, &mut rng)
+            .expect("missed the triangle");
+        assert_eq!(ray_hit.normal.z.abs(), 1.0, "{:?}", ray_hit.normal);
+    }
+
+    #[test]
+    fn parse_error_has_file_and_line() {
+        let path = write_obj("parse-error", "v 0 0 0\nv 1 0 0\nv 0 one 0\n");
+        let mut rng = Xoshiro256Plus::seed_from_u64(0);
+        let storage = Storage::new(&mut rng);
+        let err = load(&path, BVHBuilder::Sah, &mut rng, &storage).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("{}:3: invalid number 'one' in 'v'", path.display())
+        );
+
+        let path = write_obj("face-index", "v 0 0 0\nv 1 0 0\nf 1 2 3\n");
+        let err = load(&path, BVHBuilder::Sah, &mut rng, &storage).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{}:3: vertex index 3 out of range, 2 defined",
+                path.display()
+            )
+        );
+    }
+}
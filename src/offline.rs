@@ -1,60 +1,386 @@
-use crate::{math::linear_to_srgb, params::Params, presets, storage::Storage};
-use image;
-use std::time::SystemTime;
+use crate::{
+    aov::{Aov, AovBuffer},
+    checkpoint::Checkpoint,
+    denoise, output,
+    params::Params,
+    scene::TraceStats,
+    scene_file,
+    source::SceneSource,
+    storage::Storage,
+    tiles::{Tile, TileEvent},
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 
-pub fn print_ray_trace(preset: &str, params: Params) {
+pub fn print_ray_trace(source: &SceneSource, params: Params) {
     let mut rng = params.new_rng();
 
     let storage = Storage::new(&mut rng);
-    let (hitables, camera, sky) =
-        presets::from_name(&preset, &params, &mut rng, &storage).expect("unrecognised preset");
-    let scene = params.new_scene(&mut rng, &storage, hitables, sky);
+    let (hitables, camera, sky) = source
+        .load(&params, &mut rng, &storage)
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to load scene: {}", err);
+            process::exit(1)
+        });
+    let scene = params.new_scene(&mut rng, &storage, hitables, &camera, sky);
     let ray = camera.get_ray(0.5, 0.5, &mut rng);
-    scene.print_ray_trace(&ray, &mut rng);
+    let (t0, t1) = camera.shutter();
+    scene.print_ray_trace(&ray, t0, t1, &mut rng);
 }
 
-pub fn render_offline(preset: &str, params: Params) {
+pub fn export_scene(source: &SceneSource, params: Params, path: &Path) {
     let mut rng = params.new_rng();
 
     let storage = Storage::new(&mut rng);
-    let (hitables, camera, sky) =
-        presets::from_name(&preset, &params, &mut rng, &storage).expect("unrecognised preset");
+    let scene = source
+        .load(&params, &mut rng, &storage)
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to load scene: {}", err);
+            process::exit(1)
+        });
+    if let Err(err) = scene_file::export(&scene, path).save(path) {
+        eprintln!("Failed to export scene: {}", err);
+        process::exit(1)
+    }
+    println!("exported scene to '{}'", path.display());
+}
+
+/// When progressive offline rendering stops starting new passes over the image. A single pass
+/// is rendered if none are set.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StopConditions {
+    pub max_passes: Option<u32>,
+    /// Seconds of rendering after which no more passes are started, nor passes that would
+    /// probably finish after it going by the average time they have taken.
+    pub time_limit_secs: Option<f64>,
+    /// The estimated noise of the image below which no more passes are started, see
+    /// `Checkpoint::noise`.
+    pub target_noise: Option<f32>,
+}
+
+impl StopConditions {
+    /// Returns true if no more passes should be started after `passes` have taken
+    /// `elapsed_secs` and left the image with `noise`.
+    fn is_met(&self, passes: u32, elapsed_secs: f64, noise: f32) -> bool {
+        if self.max_passes.is_none()
+            && self.time_limit_secs.is_none()
+            && self.target_noise.is_none()
+        {
+            return passes >= 1;
+        }
+        let pass_secs = elapsed_secs / passes as f64;
+        self.max_passes
+            .is_some_and(|max_passes| passes >= max_passes)
+            || self
+                .time_limit_secs
+                .is_some_and(|time_limit| elapsed_secs + pass_secs > time_limit)
+            || self
+                .target_noise
+                .is_some_and(|target_noise| passes >= 2 && noise <= target_noise)
+    }
+}
+
+/// What an offline render writes when it finishes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutputOptions {
+    pub path: PathBuf,
+    pub aovs: Vec<Aov>,
+    pub denoise: bool,
+    /// Write the whole image with only the crop region filled in, rather than just the region.
+    pub full_canvas: bool,
+}
+
+/// How often an offline render saves its progress while it runs.
+#[derive(Copy, Clone, Debug)]
+pub struct SaveIntervals {
+    pub checkpoint_secs: f64,
+    /// Seconds between writing the image so far to the output path, it is only written at the
+    /// end if `None`.
+    pub output_secs: Option<f64>,
+}
+
+/// Loads the checkpoint of an interrupted render from `path`.
+pub fn load_checkpoint(path: &Path) -> Checkpoint {
+    let checkpoint = Checkpoint::load(path).unwrap_or_else(|err| {
+        eprintln!("Failed to load checkpoint: {}", err);
+        process::exit(1)
+    });
+    println!(
+        "resuming '{}' at pass {} with {}/{} tiles finished",
+        path.display(),
+        checkpoint.passes + 1,
+        checkpoint.finished_tiles.len(),
+        checkpoint.params.tiles().len()
+    );
+    checkpoint
+}
 
-    let scene = params.new_scene(&mut rng, &storage, hitables, sky);
+/// Renders the rest of `checkpoint` on this machine, see `render_passes`.
+pub fn render_offline(checkpoint: Checkpoint, checkpoint_path: &Path, intervals: SaveIntervals) {
+    let params = checkpoint.params;
+    let mut rng = params.new_rng();
 
-    let mut rgb_buffer = vec![(0.0, 0.0, 0.0); (params.width * params.height) as usize];
+    let storage = Storage::new(&mut rng);
+    let (hitables, camera, sky) = checkpoint
+        .source
+        .load(&params, &mut rng, &storage)
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to load scene: {}", err);
+            process::exit(1)
+        });
+
+    let scene = params.new_scene(&mut rng, &storage, hitables, &camera, sky);
+
+    // the finished tiles are already in the buffers and aren't rendered again
+    let mut rgb_buffer = checkpoint.rgb.clone();
+    let mut aov_buffer = checkpoint.aov_pixels.as_ref().map(|aov_pixels| {
+        let mut aov_buffer = AovBuffer::new(&scene.world(), params.width, params.height);
+        aov_buffer.pixels.copy_from_slice(aov_pixels);
+        aov_buffer
+    });
+    render_passes(
+        checkpoint,
+        checkpoint_path,
+        intervals,
+        |pass, tiles, on_tile| {
+            // offline passes are blended like the window's frames
+            scene.update(
+                &params,
+                &camera,
+                pass,
+                tiles,
+                &mut rgb_buffer,
+                aov_buffer.as_mut(),
+                on_tile,
+            )
+        },
+    );
+}
+
+/// Renders passes over the unfinished tiles of `checkpoint` until its stop conditions are met
+/// and writes the output. `render_pass` renders the given tiles of a pass, reporting them to
+/// `on_tile`, and returns the stats for the rays it traced.
+///
+/// The checkpoint is saved to `checkpoint_path` and the image so far to the output whenever a
+/// tile finishes more than their interval after they were last saved. If the render is
+/// interrupted with Ctrl-C the checkpoint and the image so far are saved before exiting.
+pub fn render_passes<R>(
+    checkpoint: Checkpoint,
+    checkpoint_path: &Path,
+    intervals: SaveIntervals,
+    mut render_pass: R,
+) where
+    R: FnMut(u32, &[Tile], &(dyn Fn(TileEvent) + Sync)) -> TraceStats,
+{
+    let params = checkpoint.params;
+    let mut tiles = checkpoint.unfinished_tiles();
+    let mut resumed_tiles = checkpoint.finished_tiles.len();
+    let resumed_secs = checkpoint.elapsed_secs;
 
     let start_time = SystemTime::now();
-    let frame_num = 0; // only ever processing 1 frame in offline
-    let ray_count = scene.update(&params, &camera, frame_num, &mut rgb_buffer);
-    let elapsed = start_time
-        .elapsed()
-        .expect("SystemTime elapsed time failed");
-    let elapsed_secs =
-        elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+    let checkpoint = Arc::new(Mutex::new(checkpoint));
+    {
+        let checkpoint = checkpoint.clone();
+        let checkpoint_path = checkpoint_path.to_path_buf();
+        ctrlc::set_handler(move || {
+            // tiles are added to the checkpoint under the lock so it only holds finished ones
+            let mut checkpoint = checkpoint.lock().unwrap();
+            checkpoint.elapsed_secs = resumed_secs + secs_since(start_time);
+            eprintln!();
+            save_checkpoint(&checkpoint, &checkpoint_path);
+            save_output(&checkpoint, &checkpoint.rgb);
+            println!(
+                "interrupted, resume with --resume '{}'",
+                checkpoint_path.display()
+            );
+            process::exit(130)
+        })
+        .expect("Failed to set Ctrl-C handler");
+    }
+
+    let last_saved = Mutex::new(start_time);
+    let last_output = Mutex::new(start_time);
+    let mut stats = TraceStats::default();
+    let mut passes = checkpoint.lock().unwrap().passes;
+    loop {
+        let pass_start_time = SystemTime::now();
+        stats.add(&render_pass(passes, &tiles, &|event| {
+            if let TileEvent::Finished {
+                tile,
+                pixels,
+                aovs,
+                sample_counts,
+                completed,
+                total,
+            } = event
+            {
+                let mut checkpoint = checkpoint.lock().unwrap();
+                checkpoint.add_tile(tile, pixels, sample_counts, aovs);
+                print_progress(pass_start_time, passes, resumed_tiles, completed, total);
+
+                let mut last_saved = last_saved.lock().unwrap();
+                if completed < total && secs_since(*last_saved) >= intervals.checkpoint_secs {
+                    checkpoint.elapsed_secs = resumed_secs + secs_since(start_time);
+                    save_checkpoint(&checkpoint, checkpoint_path);
+                    *last_saved = SystemTime::now();
+                }
+                if let Some(output_secs) = intervals.output_secs {
+                    let mut last_output = last_output.lock().unwrap();
+                    if secs_since(*last_output) >= output_secs {
+                        save_output(&checkpoint, &checkpoint.rgb);
+                        *last_output = SystemTime::now();
+                    }
+                }
+            }
+        }));
+        eprintln!();
+
+        let mut checkpoint = checkpoint.lock().unwrap();
+        checkpoint.finish_pass();
+        passes = checkpoint.passes;
+        let elapsed_secs = resumed_secs + secs_since(start_time);
+        let noise = checkpoint.noise();
+        if passes > 1 {
+            println!(
+                "pass {} finished after {:.2}secs with noise {:.4}",
+                passes, elapsed_secs, noise
+            );
+        }
+        if checkpoint.stop.is_met(passes, elapsed_secs, noise) {
+            break;
+        }
+        tiles = params.tiles();
+        resumed_tiles = 0;
+    }
+    let elapsed_secs = secs_since(start_time);
 
+    let region = params.region();
+    let region_pixels = (region.width * region.height) as f64;
     println!(
-        "{:.2}secs {}rays {:.2}Mrays/s",
+        "{:.2}secs {}rays {:.2}Mrays/s {:.2} mean path depth {} max path depth {:.2} samples per pixel",
         elapsed_secs,
-        ray_count,
-        ray_count as f64 / 1_000_000.0 / elapsed_secs
+        stats.ray_count,
+        stats.ray_count as f64 / 1_000_000.0 / elapsed_secs,
+        stats.mean_path_depth(),
+        stats.max_path_depth,
+        stats.path_count as f64 / region_pixels
     );
+    if passes > 1 || resumed_secs > 0.0 {
+        let checkpoint = checkpoint.lock().unwrap();
+        let total_samples: u64 = checkpoint.sample_counts.iter().map(|&n| u64::from(n)).sum();
+        println!(
+            "{:.2}secs {} passes {:.2} samples per pixel in total",
+            resumed_secs + elapsed_secs,
+            passes,
+            total_samples as f64 / region_pixels
+        );
+    }
+
+    // copied out so Ctrl-C can still save the checkpoint while denoising
+    let (mut rgb_buffer, aov_pixels, denoise) = {
+        let checkpoint = checkpoint.lock().unwrap();
+        (
+            checkpoint.rgb.clone(),
+            checkpoint.aov_pixels.clone(),
+            checkpoint.output.denoise,
+        )
+    };
+    if denoise {
+        // only the region is denoised so the black surroundings don't bleed into it
+        let start_time = SystemTime::now();
+        let features = region.extract(&aov_pixels.unwrap(), params.width);
+        let noisy = region.extract(&rgb_buffer, params.width);
+        let denoised = denoise::denoise(region.width, region.height, &noisy, &features);
+        region.insert(&denoised, &mut rgb_buffer, params.width);
+        println!("{:.2}secs denoising", secs_since(start_time));
+    }
 
-    let mut image_bytes = Vec::with_capacity(rgb_buffer.len() * 3);
-    for row in rgb_buffer.chunks(params.width as usize).rev() {
-        for rgb in row {
-            let srgb = linear_to_srgb(*rgb);
-            image_bytes.push(srgb.0);
-            image_bytes.push(srgb.1);
-            image_bytes.push(srgb.2);
+    save_output(&checkpoint.lock().unwrap(), &rgb_buffer);
+    if checkpoint_path.exists() {
+        if let Err(err) = fs::remove_file(checkpoint_path) {
+            eprintln!(
+                "Failed to remove checkpoint '{}': {}",
+                checkpoint_path.display(),
+                err
+            );
         }
     }
-    image::save_buffer(
-        "output.png",
-        &image_bytes,
-        params.width,
-        params.height,
-        image::ColorType::Rgb8,
-    )
-    .expect("Failed to save output image");
+}
+
+fn save_checkpoint(checkpoint: &Checkpoint, path: &Path) {
+    if let Err(err) = checkpoint.save(path) {
+        eprintln!("Failed to save checkpoint: {}", err);
+        process::exit(1)
+    }
+}
+
+/// Saves `rgb_buffer` and the auxiliary outputs of `checkpoint` to its output path, cropped to
+/// the rendered region unless the whole canvas is wanted.
+fn save_output(checkpoint: &Checkpoint, rgb_buffer: &[(f32, f32, f32)]) {
+    let params = &checkpoint.params;
+    let aov_pixels = checkpoint.aov_pixels.as_deref().unwrap_or(&[]);
+    let result = match params.crop {
+        Some(crop) if !checkpoint.output.full_canvas => output::save_image(
+            &checkpoint.output.path,
+            crop.width,
+            crop.height,
+            &crop.extract(rgb_buffer, params.width),
+            &params.display,
+            &checkpoint.output.aovs,
+            &if aov_pixels.is_empty() {
+                Vec::new()
+            } else {
+                crop.extract(aov_pixels, params.width)
+            },
+        ),
+        _ => output::save_image(
+            &checkpoint.output.path,
+            params.width,
+            params.height,
+            rgb_buffer,
+            &params.display,
+            &checkpoint.output.aovs,
+            aov_pixels,
+        ),
+    };
+    if let Err(err) = result {
+        eprintln!("Failed to save output image: {}", err);
+        process::exit(1)
+    }
+}
+
+fn secs_since(start_time: SystemTime) -> f64 {
+    let elapsed = start_time
+        .elapsed()
+        .expect("SystemTime elapsed time failed");
+    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0
+}
+
+/// Prints the number of finished tiles of pass `pass` and an estimate of the time left in it
+/// over the previous report, assuming the remaining tiles take as long as the ones finished
+/// since `start_time` on average. `resumed` tiles were finished before the render was resumed.
+fn print_progress(
+    start_time: SystemTime,
+    pass: u32,
+    resumed: usize,
+    completed: usize,
+    total: usize,
+) {
+    let elapsed_secs = secs_since(start_time);
+    let remaining_secs = elapsed_secs * (total - completed) as f64 / completed as f64;
+    eprint!(
+        "\rpass {} {}/{} tiles {:.0}% {:.1}secs elapsed ETA {:.1}secs   ",
+        pass + 1,
+        resumed + completed,
+        resumed + total,
+        100.0 * (resumed + completed) as f64 / (resumed + total) as f64,
+        elapsed_secs,
+        remaining_secs
+    );
 }
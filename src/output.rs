@@ -0,0 +1,357 @@
+use crate::{
+    aov::{Aov, AovPixel},
+    film::DisplayTransform,
+};
+use image::{codecs::hdr::HdrEncoder, ImageError, Rgb};
+use std::{
+    error, fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// The image file formats a render can be written as, chosen by file extension.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageFormat {
+    /// 8-bit sRGB.
+    Png,
+    /// Uncompressed 32-bit float OpenEXR.
+    Exr,
+    /// Radiance RGBE.
+    Hdr,
+    /// 32-bit float Portable Float Map.
+    Pfm,
+}
+
+impl ImageFormat {
+    pub const EXTENSIONS: [&'static str; 4] = ["png", "exr", "hdr", "pfm"];
+
+    pub fn from_path(path: &Path) -> Option<ImageFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(ImageFormat::Png),
+            "exr" => Some(ImageFormat::Exr),
+            "hdr" => Some(ImageFormat::Hdr),
+            "pfm" => Some(ImageFormat::Pfm),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OutputError {
+    Io { path: PathBuf, source: io::Error },
+    Image { path: PathBuf, source: ImageError },
+    UnsupportedFormat { path: PathBuf },
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputError::Io { path, source } => {
+                write!(f, "failed to write '{}': {}", path.display(), source)
+            }
+            OutputError::Image { path, source } => write!(f, "{}: {}", path.display(), source),
+            OutputError::UnsupportedFormat { path } => write!(
+                f,
+                "{}: unsupported image format, expected one of {}",
+                path.display(),
+                ImageFormat::EXTENSIONS.join(", ")
+            ),
+        }
+    }
+}
+
+impl error::Error for OutputError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            OutputError::Io { source, .. } => Some(source),
+            OutputError::Image { source, .. } => Some(source),
+            OutputError::UnsupportedFormat { .. } => None,
+        }
+    }
+}
+
+/// Writes a linear radiance buffer, stored bottom row first, to `path` in the format its
+/// extension selects. PNG is converted with `display`, the other formats keep the linear
+/// values for grading elsewhere.
+///
+/// Each of `aovs` is read from `aov_pixels` and added to EXR files as a layer, for other
+/// formats it is written alongside as `<stem>.<aov>.<extension>`. PNG outputs are scaled to be
+/// viewable, see `Aov::to_display`.
+pub fn save_image(
+    path: &Path,
+    width: u32,
+    height: u32,
+    rgb_buffer: &[(f32, f32, f32)],
+    display: &DisplayTransform,
+    aovs: &[Aov],
+    aov_pixels: &[AovPixel],
+) -> Result<(), OutputError> {
+    assert_eq!(rgb_buffer.len(), (width * height) as usize);
+    let format = ImageFormat::from_path(path).ok_or_else(|| OutputError::UnsupportedFormat {
+        path: path.to_path_buf(),
+    })?;
+    match format {
+        ImageFormat::Png => {
+            let srgb_buffer: Vec<_> = rgb_buffer.iter().map(|rgb| display.apply(*rgb)).collect();
+            write_png(path, width, height, &srgb_buffer)?;
+        }
+        ImageFormat::Exr => {
+            let mut channels = rgb_channels(rgb_buffer.iter());
+            for aov in aovs {
+                assert_eq!(aov_pixels.len(), rgb_buffer.len());
+                for (index, channel_name) in aov.channel_names().iter().enumerate() {
+                    let values = aov_pixels
+                        .iter()
+                        .map(|pixel| {
+                            let value = aov.value(pixel);
+                            [value.0, value.1, value.2][index]
+                        })
+                        .collect();
+                    channels.push((format!("{}.{}", aov.name(), channel_name), values));
+                }
+            }
+            return write_float_image(path, format, width, height, channels);
+        }
+        ImageFormat::Hdr | ImageFormat::Pfm => {
+            write_float_image(path, format, width, height, rgb_channels(rgb_buffer.iter()))?;
+        }
+    }
+
+    for aov in aovs {
+        assert_eq!(aov_pixels.len(), rgb_buffer.len());
+        let aov_path = aov_path(path, *aov);
+        if format == ImageFormat::Png {
+            write_png(&aov_path, width, height, &aov.to_display(aov_pixels))?;
+        } else {
+            let values: Vec<_> = aov_pixels.iter().map(|pixel| aov.value(pixel)).collect();
+            write_float_image(
+                &aov_path,
+                format,
+                width,
+                height,
+                rgb_channels(values.iter()),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns `path` with the name of `aov` inserted before the extension.
+fn aov_path(path: &Path, aov: Aov) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(aov.name());
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+fn rgb_channels<'b>(rgb: impl Iterator<Item = &'b (f32, f32, f32)>) -> Vec<(String, Vec<f32>)> {
+    let mut channels = vec![
+        ("R".to_string(), Vec::new()),
+        ("G".to_string(), Vec::new()),
+        ("B".to_string(), Vec::new()),
+    ];
+    for rgb in rgb {
+        channels[0].1.push(rgb.0);
+        channels[1].1.push(rgb.1);
+        channels[2].1.push(rgb.2);
+    }
+    channels
+}
+
+/// Writes 8-bit sRGB values, stored bottom row first, as a PNG.
+fn write_png(
+    path: &Path,
+    width: u32,
+    height: u32,
+    srgb_buffer: &[(u8, u8, u8)],
+) -> Result<(), OutputError> {
+    let mut image_bytes = Vec::with_capacity(srgb_buffer.len() * 3);
+    for row in srgb_buffer.chunks(width as usize).rev() {
+        for srgb in row {
+            image_bytes.push(srgb.0);
+            image_bytes.push(srgb.1);
+            image_bytes.push(srgb.2);
+        }
+    }
+    image::save_buffer(path, &image_bytes, width, height, image::ColorType::Rgb8).map_err(
+        |source| OutputError::Image {
+            path: path.to_path_buf(),
+            source,
+        },
+    )
+}
+
+/// Writes named channels of linear values, stored bottom row first, in one of the floating
+/// point formats. Only EXR can hold channels other than R, G and B.
+fn write_float_image(
+    path: &Path,
+    format: ImageFormat,
+    width: u32,
+    height: u32,
+    channels: Vec<(String, Vec<f32>)>,
+) -> Result<(), OutputError> {
+    let io_error = |source| OutputError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    let rgb_buffer = || -> Vec<(f32, f32, f32)> {
+        (0..(width * height) as usize)
+            .map(|index| {
+                (
+                    channels[0].1[index],
+                    channels[1].1[index],
+                    channels[2].1[index],
+                )
+            })
+            .collect()
+    };
+    match format {
+        ImageFormat::Hdr => {
+            let pixels: Vec<Rgb<f32>> = rgb_buffer()
+                .chunks(width as usize)
+                .rev()
+                .flatten()
+                .map(|rgb| Rgb([rgb.0, rgb.1, rgb.2]))
+                .collect();
+            let file = File::create(path).map_err(io_error)?;
+            HdrEncoder::new(BufWriter::new(file))
+                .encode(&pixels, width as usize, height as usize)
+                .map_err(|source| OutputError::Image {
+                    path: path.to_path_buf(),
+                    source,
+                })
+        }
+        ImageFormat::Exr => {
+            let mut writer = BufWriter::new(File::create(path).map_err(io_error)?);
+            write_exr(&mut writer, width, height, channels)
+                .and_then(|_| writer.flush())
+                .map_err(io_error)
+        }
+        ImageFormat::Pfm => {
+            let mut writer = BufWriter::new(File::create(path).map_err(io_error)?);
+            write_pfm(&mut writer, width, height, &rgb_buffer())
+                .and_then(|_| writer.flush())
+                .map_err(io_error)
+        }
+        ImageFormat::Png => unreachable!("PNG is not a floating point format"),
+    }
+}
+
+/// Writes a colour PFM, a negative scale marks the floats as little endian. PFM rows are
+/// stored bottom first, the same as the render buffer.
+fn write_pfm<W: Write>(
+    writer: &mut W,
+    width: u32,
+    height: u32,
+    rgb_buffer: &[(f32, f32, f32)],
+) -> io::Result<()> {
+    write!(writer, "PF\n{} {}\n-1.0\n", width, height)?;
+    for rgb in rgb_buffer {
+        writer.write_all(&rgb.0.to_le_bytes())?;
+        writer.write_all(&rgb.1.to_le_bytes())?;
+        writer.write_all(&rgb.2.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_exr_attribute<W: Write>(
+    writer: &mut W,
+    name: &str,
+    type_name: &str,
+    value: &[u8],
+) -> io::Result<()> {
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(&[0])?;
+    writer.write_all(type_name.as_bytes())?;
+    writer.write_all(&[0])?;
+    writer.write_all(&(value.len() as i32).to_le_bytes())?;
+    writer.write_all(value)
+}
+
+/// Writes a single part, scanline OpenEXR with uncompressed 32-bit float channels, one
+/// scanline per chunk. Channel names with a dot prefix are read as layers by most software.
+/// EXR rows are stored top first.
+fn write_exr<W: Write>(
+    writer: &mut W,
+    width: u32,
+    height: u32,
+    mut channels: Vec<(String, Vec<f32>)>,
+) -> io::Result<()> {
+    const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+    const VERSION: [u8; 4] = [2, 0, 0, 0];
+    const PIXEL_TYPE_FLOAT: i32 = 2;
+    const NO_COMPRESSION: u8 = 0;
+    const INCREASING_Y: u8 = 0;
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&VERSION);
+
+    // channels must be in alphabetical order
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut channel_list = Vec::new();
+    for (name, values) in &channels {
+        assert_eq!(values.len(), (width * height) as usize);
+        channel_list.extend_from_slice(name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        // linear flag and reserved bytes
+        channel_list.extend_from_slice(&[0, 0, 0, 0]);
+        // x and y sampling
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channel_list.push(0);
+
+    let mut window = Vec::new();
+    for value in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&value.to_le_bytes());
+    }
+
+    write_exr_attribute(&mut header, "channels", "chlist", &channel_list)?;
+    write_exr_attribute(&mut header, "compression", "compression", &[NO_COMPRESSION])?;
+    write_exr_attribute(&mut header, "dataWindow", "box2i", &window)?;
+    write_exr_attribute(&mut header, "displayWindow", "box2i", &window)?;
+    write_exr_attribute(&mut header, "lineOrder", "lineOrder", &[INCREASING_Y])?;
+    write_exr_attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1.0f32.to_le_bytes(),
+    )?;
+    write_exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+    write_exr_attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1.0f32.to_le_bytes(),
+    )?;
+    header.push(0);
+    writer.write_all(&header)?;
+
+    // the offset table holds the file position of each scanline chunk
+    let line_size = width as usize * channels.len() * 4;
+    let chunk_size = 4 + 4 + line_size;
+    let first_chunk = header.len() + height as usize * 8;
+    for y in 0..height as usize {
+        writer.write_all(&((first_chunk + y * chunk_size) as u64).to_le_bytes())?;
+    }
+
+    for y in 0..height as usize {
+        writer.write_all(&(y as i32).to_le_bytes())?;
+        writer.write_all(&(line_size as i32).to_le_bytes())?;
+        let row_start = (height as usize - 1 - y) * width as usize;
+        for (_, values) in &channels {
+            for value in &values[row_start..row_start + width as usize] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
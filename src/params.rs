@@ -1,29 +1,103 @@
 use crate::{
-    collision::{BVHNode, Hitable},
-    scene::Scene,
+    adaptive::AdaptiveSampling,
+    camera::Camera,
+    collision::{BVHBuilder, BVHNode, Hitable, LinearBVH, SpheresSoA, Tlas, WideBVH},
+    film::DisplayTransform,
+    light::Lights,
+    sampler::SamplerKind,
+    scene::{Integrator, Scene},
     storage::Storage,
+    tiles::{self, Tile, TileOrder},
 };
 use glam::Vec3;
 use rand::SeedableRng;
 use rand_xoshiro::Xoshiro256Plus;
+use serde_derive::{Deserialize, Serialize};
+use std::str::FromStr;
 
-#[derive(Copy, Clone, Debug)]
+/// The acceleration structure the scene's hitables are traced through.
+///
+/// With any of the BVHs, scenes with instances are traced through a `Tlas` whose instanced
+/// geometry and remaining hitables are each built into that BVH.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Accel {
+    /// Test every hitable in turn, scenes of only spheres are tested as `Soa`.
+    List,
+    /// Test the spheres several at a time with SIMD and then the other hitables in turn.
+    Soa,
+    /// A tree of arena allocated `BVHNode`s.
+    Bvh,
+    /// A `BVHNode` tree flattened into a `LinearBVH`.
+    LinearBvh,
+    /// A `LinearBVH` with bounds interpolated by ray time, for motion blurred scenes.
+    MotionBvh,
+    /// A `BVHNode` tree collapsed into a `WideBVH` with 4 children per node.
+    Bvh4,
+    /// A `BVHNode` tree collapsed into a `WideBVH` with 8 children per node.
+    Bvh8,
+}
+
+impl Accel {
+    pub const NAMES: [&'static str; 7] = ["list", "soa", "bvh", "linear", "motion", "bvh4", "bvh8"];
+}
+
+impl FromStr for Accel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "list" => Ok(Accel::List),
+            "soa" => Ok(Accel::Soa),
+            "bvh" => Ok(Accel::Bvh),
+            "linear" => Ok(Accel::LinearBvh),
+            "motion" => Ok(Accel::MotionBvh),
+            "bvh4" => Ok(Accel::Bvh4),
+            "bvh8" => Ok(Accel::Bvh8),
+            _ => Err(format!("unrecognised acceleration structure '{}'", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Params {
     pub width: u32,
     pub height: u32,
+    /// Samples per pixel, or the minimum per pixel with adaptive sampling.
     pub samples: u32,
-    pub max_depth: u32,
-    pub random_seed: bool,
-    pub use_bvh: bool,
+    pub adaptive: Option<AdaptiveSampling>,
+    pub min_depth: u32,
+    /// Seeds the random numbers used to build the scene and the samplers.
+    pub seed: u64,
+    pub accel: Accel,
+    pub bvh_builder: BVHBuilder,
+    pub integrator: Integrator,
+    pub sampler: SamplerKind,
+    /// Width and height of the tiles frames are rendered in.
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    /// The region of the image to render, the rest is left black. The whole image is
+    /// rendered if `None`.
+    pub crop: Option<Tile>,
+    pub display: DisplayTransform,
 }
 
 impl Params {
     pub fn new_rng(&self) -> Xoshiro256Plus {
-        if self.random_seed {
-            Xoshiro256Plus::seed_from_u64(rand::random())
-        } else {
-            Xoshiro256Plus::seed_from_u64(0)
-        }
+        Xoshiro256Plus::seed_from_u64(self.seed)
+    }
+
+    /// The part of the image that is rendered, either `crop` or the whole image.
+    pub fn region(&self) -> Tile {
+        self.crop.unwrap_or(Tile {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        })
+    }
+
+    /// The tiles a frame is rendered in, in the order they are started.
+    pub fn tiles(&self) -> Vec<Tile> {
+        tiles::tiles(self.region(), self.tile_size, self.tile_order)
     }
 
     pub fn new_scene<'a>(
@@ -31,17 +105,101 @@ impl Params {
         rng: &mut Xoshiro256Plus,
         storage: &'a Storage<'a>,
         mut hitables: Vec<Hitable<'a>>,
+        camera: &Camera,
         sky: Option<Vec3>,
     ) -> Scene<'a> {
-        let hitable_list = if self.use_bvh {
-            let bvh_root = BVHNode::new(rng, &mut hitables, &storage.bvhnode_arena).unwrap();
-            dbg!(bvh_root.get_stats());
-
-            Hitable::BVHNode(bvh_root)
+        let lights = if self.integrator == Integrator::Naive {
+            Lights::default()
         } else {
-            Hitable::List(storage.alloc_hitables(hitables))
+            Lights::from_hitables(&hitables)
+        };
+
+        let spheres_only = hitables
+            .iter()
+            .all(|hitable| matches!(hitable, Hitable::Sphere(_, _)));
+        let hitable_list = match self.accel {
+            Accel::List if !spheres_only => Hitable::List(storage.alloc_hitables(hitables)),
+            Accel::List | Accel::Soa => {
+                let mut hitables = SpheresSoA::pack(&hitables, storage);
+                if hitables.len() == 1 {
+                    hitables.pop().unwrap()
+                } else {
+                    Hitable::List(storage.alloc_hitables(hitables))
+                }
+            }
+            _ => {
+                let (t0, t1) = camera.shutter();
+                if hitables
+                    .iter()
+                    .any(|hitable| matches!(hitable, Hitable::Instance(_)))
+                {
+                    // instanced geometry is built once and shared by all its instances
+                    let tlas = Tlas::new(
+                        self.bvh_builder,
+                        rng,
+                        hitables,
+                        t0,
+                        t1,
+                        storage,
+                        |rng, geometry| self.new_blas(rng, geometry, t0, t1, storage),
+                    );
+                    return Scene::with_tlas(tlas, lights, sky);
+                } else {
+                    let bvh_root =
+                        BVHNode::build(self.bvh_builder, rng, &mut hitables, t0, t1, storage)
+                            .unwrap();
+                    self.bvh_accel(bvh_root, t0, t1, storage)
+                }
+            }
+        };
+
+        Scene::new(hitable_list, lights, sky)
+    }
+
+    /// Builds the bottom level acceleration structure of a `Tlas` for `geometry`. BVHs are
+    /// converted to the selected accel and lists are built into one, anything else is a
+    /// single primitive which is used as is.
+    fn new_blas<'a>(
+        &self,
+        rng: &mut Xoshiro256Plus,
+        geometry: Hitable<'a>,
+        t0: f32,
+        t1: f32,
+        storage: &'a Storage<'a>,
+    ) -> Hitable<'a> {
+        let bvh_root = match geometry {
+            Hitable::BVHNode(node) => node,
+            Hitable::List(list) => {
+                let mut hitables = list.hitables().to_vec();
+                BVHNode::build(self.bvh_builder, rng, &mut hitables, t0, t1, storage).unwrap()
+            }
+            _ => return geometry,
         };
+        self.bvh_accel(bvh_root, t0, t1, storage)
+    }
 
-        Scene::new(hitable_list, sky)
+    /// Converts a built BVH to the selected accel.
+    fn bvh_accel<'a>(
+        &self,
+        bvh_root: &'a BVHNode<'a>,
+        t0: f32,
+        t1: f32,
+        storage: &'a Storage<'a>,
+    ) -> Hitable<'a> {
+        match self.accel {
+            Accel::LinearBvh => {
+                Hitable::LinearBVH(storage.alloc_linear_bvh(LinearBVH::new(bvh_root, t0, t1)))
+            }
+            Accel::MotionBvh => Hitable::LinearBVH(
+                storage.alloc_linear_bvh(LinearBVH::with_motion(bvh_root, t0, t1)),
+            ),
+            Accel::Bvh4 => {
+                Hitable::WideBVH(storage.alloc_wide_bvh(WideBVH::new(bvh_root, 4, t0, t1)))
+            }
+            Accel::Bvh8 => {
+                Hitable::WideBVH(storage.alloc_wide_bvh(WideBVH::new(bvh_root, 8, t0, t1)))
+            }
+            _ => Hitable::BVHNode(bvh_root),
+        }
     }
 }
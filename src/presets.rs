@@ -1,14 +1,18 @@
 use crate::{
     camera::Camera,
-    collision::{ConstantMedium, Cuboid, Hitable, Instance, MovingSphere, Rect, Sphere},
+    collision::{
+        BVHNode, ConstantMedium, Cuboid, Hitable, Instance, MovingSphere, Rect, Sphere, Triangle,
+        TriangleMesh,
+    },
     material,
     params::Params,
     storage::Storage,
     texture::{self, RgbImage, Texture},
 };
-use glam::{Affine3A, Quat, Vec3};
+use glam::{Affine3A, Quat, Vec2, Vec3};
 use rand::Rng;
 use rand_xoshiro::Xoshiro256Plus;
+use std::collections::HashMap;
 
 pub fn from_name<'a>(
     name: &str,
@@ -32,11 +36,26 @@ pub fn from_name<'a>(
         "two_perlin_spheres" => Some(two_perlin_spheres(params, storage)),
         "simple_light" => Some(simple_light(params, storage)),
         "earth" => Some(earth(params, storage)),
+        "icosphere" => Some(icosphere(params, rng, storage)),
+        "instances" => Some(instances(params, rng, storage)),
+        "orbits" => Some(orbits(params, rng, storage)),
         "final" => Some(final_scene(params, rng, storage)),
         _ => None,
     }
 }
 
+/// Moves an animated preset's instances, returning the transform of `instance` at `time`
+/// seconds. The instance has the transform the preset created it with.
+pub type Animation = fn(instance: &Instance, time: f32) -> Affine3A;
+
+/// The animation of the preset `name`, if it is animated.
+pub fn animation(name: &str) -> Option<Animation> {
+    match name {
+        "orbits" => Some(orbit),
+        _ => None,
+    }
+}
+
 pub fn final_scene<'a>(
     params: &Params,
     rng: &mut Xoshiro256Plus,
@@ -580,7 +599,9 @@ pub fn earth<'a>(
         )
     };
 
-    let earth_image = storage.alloc_image(RgbImage::open("media/earthmap.jpg"));
+    let earth_image = storage.alloc_image(
+        RgbImage::open("media/earthmap.jpg").expect("Failed to open media/earthmap.jpg"),
+    );
     let earth_texture = storage.alloc_texture(texture::rgb_image(earth_image));
 
     let hitables = vec![sphere(
@@ -592,6 +613,347 @@ pub fn earth<'a>(
     (hitables, camera, None)
 }
 
+fn icosphere_mesh(subdivisions: u32) -> (Vec<Vec3>, Vec<[u32; 3]>) {
+    let t = (1.0 + 5.0_f32.sqrt()) * 0.5;
+    let mut positions: Vec<Vec3> = [
+        (-1.0, t, 0.0),
+        (1.0, t, 0.0),
+        (-1.0, -t, 0.0),
+        (1.0, -t, 0.0),
+        (0.0, -1.0, t),
+        (0.0, 1.0, t),
+        (0.0, -1.0, -t),
+        (0.0, 1.0, -t),
+        (t, 0.0, -1.0),
+        (t, 0.0, 1.0),
+        (-t, 0.0, -1.0),
+        (-t, 0.0, 1.0),
+    ]
+    .iter()
+    .map(|&(x, y, z)| Vec3::new(x, y, z).normalize())
+    .collect();
+    let mut indices = vec![
+        [0, 11, 5],
+        [0, 5, 1],
+        [0, 1, 7],
+        [0, 7, 10],
+        [0, 10, 11],
+        [1, 5, 9],
+        [5, 11, 4],
+        [11, 10, 2],
+        [10, 7, 6],
+        [7, 1, 8],
+        [3, 9, 4],
+        [3, 4, 2],
+        [3, 2, 6],
+        [3, 6, 8],
+        [3, 8, 9],
+        [4, 9, 5],
+        [2, 4, 11],
+        [6, 2, 10],
+        [8, 6, 7],
+        [9, 8, 1],
+    ];
+
+    for _ in 0..subdivisions {
+        let mut midpoints = HashMap::new();
+        let mut midpoint = |a: u32, b: u32| -> u32 {
+            let key = (a.min(b), a.max(b));
+            *midpoints.entry(key).or_insert_with(|| {
+                let p = (positions[a as usize] + positions[b as usize]).normalize();
+                positions.push(p);
+                positions.len() as u32 - 1
+            })
+        };
+        let mut subdivided = Vec::with_capacity(indices.len() * 4);
+        for [i0, i1, i2] in indices {
+            let a = midpoint(i0, i1);
+            let b = midpoint(i1, i2);
+            let c = midpoint(i2, i0);
+            subdivided.push([i0, a, c]);
+            subdivided.push([i1, b, a]);
+            subdivided.push([i2, c, b]);
+            subdivided.push([a, b, c]);
+        }
+        indices = subdivided;
+    }
+
+    (positions, indices)
+}
+
+pub fn icosphere<'a>(
+    params: &Params,
+    rng: &mut Xoshiro256Plus,
+    storage: &'a Storage<'a>,
+) -> (Vec<Hitable<'a>>, Camera, Option<Vec3>) {
+    let lookfrom = Vec3::new(13.0, 2.0, 3.0);
+    let lookat = Vec3::new(0.0, 1.0, 0.0);
+    let dist_to_focus = 10.0;
+    let aperture = 0.0;
+    let camera = Camera::new(
+        lookfrom,
+        lookat,
+        Vec3::new(0.0, 1.0, 0.0),
+        20.0,
+        params.width as f32 / params.height as f32,
+        aperture,
+        dist_to_focus,
+        0.0,
+        0.0,
+    );
+
+    let constant = |albedo| -> &Texture { storage.alloc_texture(texture::constant(albedo)) };
+
+    let ground = storage.alloc_material(material::lambertian(storage.alloc_texture(
+        texture::checker(
+            constant(Vec3::new(0.2, 0.3, 0.1)),
+            constant(Vec3::new(0.9, 0.9, 0.9)),
+        ),
+    )));
+    let gold = storage.alloc_material(material::metal(Vec3::new(0.8, 0.6, 0.2), 0.1));
+    let red = storage.alloc_material(material::lambertian(constant(Vec3::new(0.7, 0.1, 0.1))));
+
+    // unit sphere vertices double as smooth vertex normals
+    let (positions, indices) = icosphere_mesh(2);
+    let normals = positions.clone();
+    let uvs = positions
+        .iter()
+        .map(|p| {
+            Vec2::new(
+                0.5 + p.z.atan2(p.x) * 0.5 * std::f32::consts::FRAC_1_PI,
+                0.5 + 0.5 * p.y,
+            )
+        })
+        .collect();
+    let mesh = storage.alloc_triangle_mesh(TriangleMesh::with_material(
+        positions, normals, uvs, indices, gold,
+    ));
+    let mut faces = mesh.faces();
+    let mesh_bvh = Hitable::BVHNode(
+        BVHNode::build(params.bvh_builder, rng, &mut faces, 0.0, 0.0, storage).expect("empty mesh"),
+    );
+
+    let hitables = vec![
+        Hitable::Sphere(
+            storage.alloc_sphere(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0)),
+            ground,
+        ),
+        Hitable::Instance(storage.alloc_instance(Instance::new(
+            mesh_bvh,
+            Affine3A::from_translation(Vec3::new(0.0, 1.0, -1.2)),
+        ))),
+        Hitable::Instance(storage.alloc_instance(Instance::new(
+            mesh_bvh,
+            Affine3A::from_translation(Vec3::new(0.0, 1.0, 1.2)),
+        ))),
+        Hitable::Triangle(
+            storage.alloc_triangle(Triangle::new(
+                Vec3::new(-3.0, 0.0, 2.5),
+                Vec3::new(-3.0, 0.0, -2.5),
+                Vec3::new(-3.0, 3.0, 0.0),
+            )),
+            red,
+        ),
+    ];
+
+    (hitables, camera, None)
+}
+
+/// A field of 10,000 instances of three icosphere meshes, each mesh is stored and built into a
+/// BVH once and shared by all its instances.
+pub fn instances<'a>(
+    params: &Params,
+    rng: &mut Xoshiro256Plus,
+    storage: &'a Storage<'a>,
+) -> (Vec<Hitable<'a>>, Camera, Option<Vec3>) {
+    let lookfrom = Vec3::new(20.0, 5.0, 20.0);
+    let lookat = Vec3::new(0.0, 0.0, 0.0);
+    let dist_to_focus = lookfrom.length();
+    let aperture = 0.0;
+    let camera = Camera::new(
+        lookfrom,
+        lookat,
+        Vec3::new(0.0, 1.0, 0.0),
+        30.0,
+        params.width as f32 / params.height as f32,
+        aperture,
+        dist_to_focus,
+        0.0,
+        0.0,
+    );
+
+    let constant = |albedo| -> &Texture { storage.alloc_texture(texture::constant(albedo)) };
+
+    let ground = storage.alloc_material(material::lambertian(storage.alloc_texture(
+        texture::checker(
+            constant(Vec3::new(0.2, 0.3, 0.1)),
+            constant(Vec3::new(0.9, 0.9, 0.9)),
+        ),
+    )));
+    let materials = vec![
+        material::metal(Vec3::new(0.8, 0.6, 0.2), 0.1),
+        material::lambertian(constant(Vec3::new(0.7, 0.1, 0.1))),
+        material::dielectric(1.5),
+    ];
+
+    // unit sphere vertices double as smooth vertex normals
+    let (positions, indices) = icosphere_mesh(3);
+    let meshes: Vec<Hitable> = materials
+        .into_iter()
+        .map(|material| {
+            let mesh = storage.alloc_triangle_mesh(TriangleMesh::with_material(
+                positions.clone(),
+                positions.clone(),
+                Vec::new(),
+                indices.clone(),
+                storage.alloc_material(material),
+            ));
+            let mut faces = mesh.faces();
+            Hitable::BVHNode(
+                BVHNode::build(params.bvh_builder, rng, &mut faces, 0.0, 0.0, storage)
+                    .expect("empty mesh"),
+            )
+        })
+        .collect();
+
+    let n = 100;
+    let mut hitables = Vec::with_capacity(n * n + 1);
+    hitables.push(Hitable::Sphere(
+        storage.alloc_sphere(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0)),
+        ground,
+    ));
+    for i in 0..n {
+        for j in 0..n {
+            let mesh = meshes[rng.gen_range(0..meshes.len())];
+            // squash the spheres so their rotation shows
+            let scale = rng.gen_range(0.2..0.4)
+                * Vec3::new(1.0, rng.gen_range(0.5..1.0), rng.gen_range(0.7..1.0));
+            let axis = Vec3::new(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+            )
+            .normalize_or_zero();
+            let rotation = if axis == Vec3::ZERO {
+                Quat::IDENTITY
+            } else {
+                Quat::from_axis_angle(axis, rng.gen_range(0.0..std::f32::consts::TAU))
+            };
+            // resting on the ground whatever the rotation
+            let centre = Vec3::new(
+                i as f32 - n as f32 * 0.5 + rng.gen_range(-0.25..0.25),
+                scale.max_element(),
+                j as f32 - n as f32 * 0.5 + rng.gen_range(-0.25..0.25),
+            );
+            hitables.push(Hitable::Instance(storage.alloc_instance(Instance::new(
+                mesh,
+                Affine3A::from_scale_rotation_translation(scale, rotation, centre),
+            ))));
+        }
+    }
+
+    (hitables, camera, None)
+}
+
+/// Rings of icospheres and cubes orbiting a metal sphere, animated by `orbit`.
+pub fn orbits<'a>(
+    params: &Params,
+    rng: &mut Xoshiro256Plus,
+    storage: &'a Storage<'a>,
+) -> (Vec<Hitable<'a>>, Camera, Option<Vec3>) {
+    let lookfrom = Vec3::new(0.0, 9.0, 22.0);
+    let lookat = Vec3::new(0.0, 0.5, 0.0);
+    let dist_to_focus = (lookfrom - lookat).length();
+    let aperture = 0.0;
+    let camera = Camera::new(
+        lookfrom,
+        lookat,
+        Vec3::new(0.0, 1.0, 0.0),
+        40.0,
+        params.width as f32 / params.height as f32,
+        aperture,
+        dist_to_focus,
+        0.0,
+        0.0,
+    );
+
+    let constant = |albedo| -> &Texture { storage.alloc_texture(texture::constant(albedo)) };
+
+    let ground = storage.alloc_material(material::lambertian(storage.alloc_texture(
+        texture::checker(
+            constant(Vec3::new(0.2, 0.3, 0.1)),
+            constant(Vec3::new(0.9, 0.9, 0.9)),
+        ),
+    )));
+    let metal = storage.alloc_material(material::metal(Vec3::new(0.7, 0.6, 0.5), 0.0));
+    let blue = storage.alloc_material(material::lambertian(constant(Vec3::new(0.1, 0.2, 0.7))));
+    let orange = storage.alloc_material(material::lambertian(constant(Vec3::new(0.8, 0.4, 0.1))));
+
+    // unit sphere vertices double as smooth vertex normals
+    let (positions, indices) = icosphere_mesh(2);
+    let mesh = storage.alloc_triangle_mesh(TriangleMesh::with_material(
+        positions.clone(),
+        positions,
+        Vec::new(),
+        indices,
+        blue,
+    ));
+    let mut faces = mesh.faces();
+    let shapes = [
+        Hitable::BVHNode(
+            BVHNode::build(params.bvh_builder, rng, &mut faces, 0.0, 0.0, storage)
+                .expect("empty mesh"),
+        ),
+        Hitable::Cuboid(
+            storage.alloc_cuboid(Cuboid::new(Vec3::splat(-0.7), Vec3::splat(0.7))),
+            orange,
+        ),
+    ];
+
+    let n = 2000;
+    let mut hitables = Vec::with_capacity(n + 2);
+    hitables.push(Hitable::Sphere(
+        storage.alloc_sphere(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0)),
+        ground,
+    ));
+    hitables.push(Hitable::Sphere(
+        storage.alloc_sphere(Sphere::new(Vec3::new(0.0, 1.5, 0.0), 1.5)),
+        metal,
+    ));
+    for _ in 0..n {
+        let radius = rng.gen_range(3.0..12.0);
+        let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+        let scale = rng.gen_range(0.1..0.25);
+        let translation = Vec3::new(
+            radius * angle.cos(),
+            rng.gen_range(0.5..2.5),
+            radius * angle.sin(),
+        );
+        let rotation = Quat::from_rotation_y(rng.gen_range(0.0..std::f32::consts::TAU));
+        hitables.push(Hitable::Instance(storage.alloc_instance(Instance::new(
+            shapes[rng.gen_range(0..shapes.len())],
+            Affine3A::from_scale_rotation_translation(Vec3::splat(scale), rotation, translation),
+        ))));
+    }
+
+    (hitables, camera, None)
+}
+
+/// The animation of `orbits`, every instance circles the centre spinning and bobbing as it
+/// goes. Inner orbits are faster so the rings shear apart over time.
+fn orbit(instance: &Instance, time: f32) -> Affine3A {
+    let (scale, rotation, translation) = instance.transform().to_scale_rotation_translation();
+    let radius = translation.x.hypot(translation.z);
+    let orbit = Quat::from_rotation_y(time * 8.0 / radius.powf(1.5));
+    let spin = Quat::from_rotation_x(time * 2.0);
+    let bob = 0.25 * (time * 3.0 + radius).sin();
+    Affine3A::from_scale_rotation_translation(
+        scale,
+        orbit * rotation * spin,
+        orbit * translation + Vec3::new(0.0, bob, 0.0),
+    )
+}
+
 // pub fn aras_p<'a>(params: &Params, storage: &'a Storage<'a>) -> (Scene<'a>, Camera, Option<Vec3>) {
 //     let lookfrom = Vec3::new(0.0, 2.0, 3.0);
 //     let lookat = Vec3::new(0.0, 0.0, 0.0);
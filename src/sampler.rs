@@ -0,0 +1,524 @@
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256Plus;
+use serde_derive::{Deserialize, Serialize};
+use std::{str::FromStr, sync::OnceLock};
+
+/// A source of sample values in `[0, 1)` for the random decisions made while tracing a path.
+///
+/// Each call consumes the next dimension of the current sample, so as long as paths make their
+/// decisions in the same order the values for a given dimension are well distributed across a
+/// pixel's samples.
+pub trait Sampler {
+    /// Starts the pixel's `index`th sample from the first dimension.
+    fn start_sample(&mut self, index: u32);
+    fn next_1d(&mut self) -> f32;
+    fn next_2d(&mut self) -> (f32, f32);
+}
+
+/// Independent uniform random samples.
+impl Sampler for Xoshiro256Plus {
+    #[inline]
+    fn start_sample(&mut self, _index: u32) {}
+
+    #[inline]
+    fn next_1d(&mut self) -> f32 {
+        self.gen()
+    }
+
+    #[inline]
+    fn next_2d(&mut self) -> (f32, f32) {
+        (self.gen(), self.gen())
+    }
+}
+
+/// The sampler used for each pixel.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SamplerKind {
+    /// Independent uniform random numbers.
+    Independent,
+    /// Jittered samples from randomly ordered strata of the expected sample count.
+    Stratified,
+    /// The Halton sequence with its digits randomly scrambled per pixel.
+    Halton,
+    /// Owen-scrambled Sobol (0, 2) sequences, shuffled and scrambled per pixel and dimension.
+    Sobol,
+    /// A Sobol sequence shared by all pixels but rotated by a blue noise mask, which spreads
+    /// the remaining error as high frequency noise that is less visible and easier to filter.
+    BlueNoise,
+}
+
+impl SamplerKind {
+    pub const NAMES: [&'static str; 5] =
+        ["independent", "stratified", "halton", "sobol", "blue-noise"];
+
+    /// Creates the sampler for pixel `(x, y)`. Every pixel of a frame shares `frame_seed`, which
+    /// should change between frames so they are independent. `samples_per_pixel` is the number
+    /// of samples stratification is designed for.
+    pub fn new_sampler(
+        self,
+        x: u32,
+        y: u32,
+        frame_seed: u32,
+        samples_per_pixel: u32,
+    ) -> Box<dyn Sampler> {
+        let pixel_seed = hash(hash_combine(hash_combine(frame_seed, x), y));
+        match self {
+            SamplerKind::Independent => Box::new(Xoshiro256Plus::seed_from_u64(pixel_seed as u64)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler {
+                seed: pixel_seed,
+                samples_per_pixel: samples_per_pixel.max(1),
+                index: 0,
+                dimension: 0,
+                rng: Xoshiro256Plus::seed_from_u64(pixel_seed as u64),
+            }),
+            SamplerKind::Halton => Box::new(HaltonSampler {
+                seed: pixel_seed,
+                index: 0,
+                dimension: 0,
+                rng: Xoshiro256Plus::seed_from_u64(pixel_seed as u64),
+            }),
+            SamplerKind::Sobol => Box::new(SobolSampler {
+                seed: pixel_seed,
+                index: 0,
+                dimension: 0,
+            }),
+            SamplerKind::BlueNoise => Box::new(BlueNoiseSampler {
+                x,
+                y,
+                seed: frame_seed,
+                index: 0,
+                dimension: 0,
+            }),
+        }
+    }
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "independent" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            "blue-noise" => Ok(SamplerKind::BlueNoise),
+            _ => Err(format!("unrecognised sampler '{}'", s)),
+        }
+    }
+}
+
+/// Chris Wellons' lowbias32 integer hash.
+#[inline]
+pub fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+#[inline]
+fn hash_combine(seed: u32, value: u32) -> u32 {
+    seed ^ (value
+        .wrapping_add(0x9e37_79b9)
+        .wrapping_add(seed << 6)
+        .wrapping_add(seed >> 2))
+}
+
+/// Converts the high bits of `x` to a float in `[0, 1)`.
+#[inline]
+fn to_unit_float(x: u32) -> f32 {
+    (x >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+}
+
+/// Adds `offset` to `x` wrapping around 1, a Cranley-Patterson rotation.
+#[inline]
+fn rotate(x: f32, offset: f32) -> f32 {
+    let x = x + offset;
+    // rounding can land exactly on 1 which is outside the sample range
+    let x = if x >= 1.0 { x - 1.0 } else { x };
+    x.min(1.0 - f32::EPSILON / 2.0)
+}
+
+/// Returns element `index` of a random permutation of `0..len` chosen by `seed`, Kensler's
+/// cycle walking permutation from "Correlated Multi-Jittered Sampling".
+fn permute(mut index: u32, len: u32, seed: u32) -> u32 {
+    if len <= 1 {
+        return 0;
+    }
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        index ^= seed;
+        index = index.wrapping_mul(0xe170_893d);
+        index ^= seed >> 16;
+        index ^= (index & w) >> 4;
+        index ^= seed >> 8;
+        index = index.wrapping_mul(0x0929_eb3f);
+        index ^= seed >> 23;
+        index ^= (index & w) >> 1;
+        index = index.wrapping_mul(1 | seed >> 27);
+        index = index.wrapping_mul(0x6935_fa69);
+        index ^= (index & w) >> 11;
+        index = index.wrapping_mul(0x74dc_b303);
+        index ^= (index & w) >> 2;
+        index = index.wrapping_mul(0x9e50_1cc3);
+        index ^= (index & w) >> 2;
+        index = index.wrapping_mul(0xc860_a3df);
+        index &= w;
+        index ^= index >> 5;
+        if index < len {
+            break;
+        }
+    }
+    (index.wrapping_add(seed)) % len
+}
+
+struct StratifiedSampler {
+    seed: u32,
+    samples_per_pixel: u32,
+    index: u32,
+    dimension: u32,
+    rng: Xoshiro256Plus,
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, index: u32) {
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        // each dimension visits the strata in its own order so dimensions aren't correlated,
+        // samples beyond the expected count start over
+        let strata = self.samples_per_pixel;
+        let seed = hash_combine(self.seed, self.dimension);
+        self.dimension += 1;
+        let stratum = permute(self.index % strata, strata, seed);
+        ((stratum as f32 + self.rng.gen::<f32>()) / strata as f32).min(1.0 - f32::EPSILON / 2.0)
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        // a square grid of at least the expected count, strata not sampled are chosen at random
+        let n = (self.samples_per_pixel as f32).sqrt().ceil() as u32;
+        let strata = n * n;
+        let seed = hash_combine(self.seed, self.dimension);
+        self.dimension += 2;
+        let stratum = permute(self.index % strata, strata, seed);
+        let (sx, sy) = (stratum % n, stratum / n);
+        let inv_n = 1.0 / n as f32;
+        (
+            ((sx as f32 + self.rng.gen::<f32>()) * inv_n).min(1.0 - f32::EPSILON / 2.0),
+            ((sy as f32 + self.rng.gen::<f32>()) * inv_n).min(1.0 - f32::EPSILON / 2.0),
+        )
+    }
+}
+
+/// Bases of the Halton sequence, dimensions past these fall back to random values.
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+/// Returns the `index`th element of the radical inverse sequence in `base` with each digit
+/// randomly permuted depending on the digits before it, an Owen scramble chosen by `seed`.
+#[inline]
+fn owen_scrambled_radical_inverse(base: u32, mut index: u32, seed: u32) -> f32 {
+    // enough digits for the precision of the result even when the index runs out of them
+    let num_digits = (24.0 / (base as f32).log2()).ceil() as u32;
+    let inv_base = 1.0 / base as f32;
+    let mut inv_base_n = 1.0;
+    let mut reversed: u64 = 0;
+    for _ in 0..num_digits {
+        let next = index / base;
+        let digit = index - next * base;
+        let digit_seed = hash(seed ^ reversed as u32);
+        reversed = reversed * base as u64 + permute(digit, base, digit_seed) as u64;
+        inv_base_n *= inv_base;
+        index = next;
+    }
+    (reversed as f32 * inv_base_n).min(1.0 - f32::EPSILON / 2.0)
+}
+
+struct HaltonSampler {
+    seed: u32,
+    index: u32,
+    dimension: u32,
+    rng: Xoshiro256Plus,
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, index: u32) {
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dimension = self.dimension as usize;
+        self.dimension += 1;
+        if dimension < PRIMES.len() {
+            let seed = hash(hash_combine(self.seed, dimension as u32));
+            owen_scrambled_radical_inverse(PRIMES[dimension], self.index, seed)
+        } else {
+            self.rng.gen()
+        }
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        (self.next_1d(), self.next_1d())
+    }
+}
+
+/// The first dimension of the Sobol sequence, the base 2 van der Corput sequence.
+#[inline]
+fn sobol_dimension0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+/// The second dimension of the Sobol sequence, its direction numbers are the rows of Pascal's
+/// triangle mod 2.
+#[inline]
+fn sobol_dimension1(mut index: u32) -> u32 {
+    let mut v = 1 << 31;
+    let mut x = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            x ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    x
+}
+
+/// Burley's improved Laine-Karras hash, which permutes the bits of `x` such that each bit only
+/// depends on the bits below it.
+#[inline]
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+/// An Owen scramble of the fixed point value `x`, from Burley's "Practical Hash-based Owen
+/// Scrambling".
+#[inline]
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// Returns the `index`th point of an Owen-scrambled Sobol (0, 2) sequence whose order and
+/// scrambling are chosen by `seed`.
+#[inline]
+fn shuffled_scrambled_sobol_2d(index: u32, seed: u32) -> (u32, u32) {
+    let index = nested_uniform_scramble(index, seed);
+    let seed = hash(seed);
+    (
+        nested_uniform_scramble(sobol_dimension0(index), hash_combine(seed, 0)),
+        nested_uniform_scramble(sobol_dimension1(index), hash_combine(seed, 1)),
+    )
+}
+
+/// Owen-scrambled Sobol samples padded to any number of dimensions, every one or two
+/// dimensions use the first one or two Sobol dimensions with independent shuffling and
+/// scrambling.
+struct SobolSampler {
+    seed: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, index: u32) {
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let seed = hash(hash_combine(self.seed, self.dimension));
+        self.dimension += 1;
+        let (x, _) = shuffled_scrambled_sobol_2d(self.index, seed);
+        to_unit_float(x)
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let seed = hash(hash_combine(self.seed, self.dimension));
+        self.dimension += 2;
+        let (x, y) = shuffled_scrambled_sobol_2d(self.index, seed);
+        (to_unit_float(x), to_unit_float(y))
+    }
+}
+
+struct BlueNoiseSampler {
+    x: u32,
+    y: u32,
+    seed: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl BlueNoiseSampler {
+    /// Returns the mask value for this pixel, offset by a different amount for each dimension
+    /// so that dimensions aren't correlated.
+    #[inline]
+    fn mask_offset(&self, dimension: u32) -> f32 {
+        let shift = hash(hash_combine(self.seed, dimension));
+        let x = (self.x + (shift & 0xffff)) as usize % BLUE_NOISE_SIZE;
+        let y = (self.y + (shift >> 16)) as usize % BLUE_NOISE_SIZE;
+        blue_noise_mask()[y * BLUE_NOISE_SIZE + x]
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_sample(&mut self, index: u32) {
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        // the sequence is the same for every pixel, only the rotation varies
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let (x, _) =
+            shuffled_scrambled_sobol_2d(self.index, hash(hash_combine(self.seed, dimension)));
+        rotate(to_unit_float(x), self.mask_offset(dimension))
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let dimension = self.dimension;
+        self.dimension += 2;
+        let (x, y) =
+            shuffled_scrambled_sobol_2d(self.index, hash(hash_combine(self.seed, dimension)));
+        (
+            rotate(to_unit_float(x), self.mask_offset(dimension)),
+            rotate(to_unit_float(y), self.mask_offset(dimension + 1)),
+        )
+    }
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Returns a tileable blue noise mask of `BLUE_NOISE_SIZE` squared values in `[0, 1)`,
+/// generated on first use.
+fn blue_noise_mask() -> &'static [f32] {
+    static MASK: OnceLock<Vec<f32>> = OnceLock::new();
+    MASK.get_or_init(|| void_and_cluster(BLUE_NOISE_SIZE))
+}
+
+/// Generates a `size` by `size` tileable blue noise dither array with Ulichney's
+/// void-and-cluster method, each pixel is ranked by the order it is added to a pattern that
+/// is kept as evenly spread as possible.
+fn void_and_cluster(size: usize) -> Vec<f32> {
+    const SIGMA: f32 = 1.5;
+    let len = size * size;
+
+    // gaussian weight by toroidal offset
+    let mut gaussian = vec![0.0; len];
+    for dy in 0..size {
+        for dx in 0..size {
+            let wx = dx.min(size - dx) as f32;
+            let wy = dy.min(size - dy) as f32;
+            gaussian[dy * size + dx] = (-(wx * wx + wy * wy) / (2.0 * SIGMA * SIGMA)).exp();
+        }
+    }
+
+    // energy[i] is the sum of the gaussian weights of every point in the pattern around i
+    let update = |energy: &mut [f32], index: usize, sign: f32| {
+        let (px, py) = (index % size, index / size);
+        for y in 0..size {
+            let dy = (y + size - py) % size;
+            for x in 0..size {
+                let dx = (x + size - px) % size;
+                energy[y * size + x] += sign * gaussian[dy * size + dx];
+            }
+        }
+    };
+    let tightest_cluster = |pattern: &[bool], energy: &[f32], value: bool| {
+        (0..len)
+            .filter(|&i| pattern[i] == value)
+            .max_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap())
+            .unwrap()
+    };
+    let largest_void = |pattern: &[bool], energy: &[f32], value: bool| {
+        (0..len)
+            .filter(|&i| pattern[i] != value)
+            .min_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap())
+            .unwrap()
+    };
+
+    // start from a random pattern and move points from clusters to voids until it settles
+    let mut rng = Xoshiro256Plus::seed_from_u64(0);
+    let mut pattern = vec![false; len];
+    let mut energy = vec![0.0; len];
+    let mut ones = 0;
+    while ones < len / 10 {
+        let index = rng.gen_range(0..len);
+        if !pattern[index] {
+            pattern[index] = true;
+            update(&mut energy, index, 1.0);
+            ones += 1;
+        }
+    }
+    loop {
+        let cluster = tightest_cluster(&pattern, &energy, true);
+        pattern[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        let void = largest_void(&pattern, &energy, true);
+        pattern[void] = true;
+        update(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut rank = vec![0; len];
+    let initial_pattern = pattern.clone();
+    let initial_energy = energy.clone();
+
+    // rank the initial points by removing the most clustered first
+    let mut count = ones;
+    while count > 0 {
+        let cluster = tightest_cluster(&pattern, &energy, true);
+        pattern[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        count -= 1;
+        rank[cluster] = count;
+    }
+
+    // fill the largest voids up to half full
+    pattern = initial_pattern;
+    energy = initial_energy;
+    let mut count = ones;
+    while count < len / 2 {
+        let void = largest_void(&pattern, &energy, true);
+        pattern[void] = true;
+        update(&mut energy, void, 1.0);
+        rank[void] = count;
+        count += 1;
+    }
+
+    // past half full the empty pixels are the minority, so fill the tightest clusters of them
+    energy.iter_mut().for_each(|e| *e = 0.0);
+    for (index, _) in pattern.iter().enumerate().filter(|(_, &set)| !set) {
+        update(&mut energy, index, 1.0);
+    }
+    while count < len {
+        let cluster = tightest_cluster(&pattern, &energy, false);
+        pattern[cluster] = true;
+        update(&mut energy, cluster, -1.0);
+        rank[cluster] = count;
+        count += 1;
+    }
+
+    rank.iter()
+        .map(|&rank| (rank as f32 + 0.5) / len as f32)
+        .collect()
+}
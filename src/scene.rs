@@ -1,38 +1,228 @@
 use crate::{
+    adaptive::PixelVariance,
+    aov::{AovBuffer, AovPixel, SceneIds},
     camera::Camera,
-    collision::{Hitable, Ray},
+    collision::{
+        lanes, Hitable, Instance, PacketHits, Ray, RayHit, RayPacket, Tlas, TlasUpdate,
+        MAX_PACKET_SIZE,
+    },
+    light::Lights,
+    material::{BsdfSample, Material},
     params::Params,
+    sampler::{self, Sampler},
+    tiles::{Tile, TileEvent},
 };
-use glam::{vec3, Vec3};
-use rand::{Rng, SeedableRng};
-use rand_xoshiro::Xoshiro256Plus;
-use rayon::prelude::*;
+use glam::{vec3, Affine3A, Vec3};
+use serde_derive::{Deserialize, Serialize};
 use std::{
     f32,
-    sync::atomic::{AtomicUsize, Ordering},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Mutex,
+    },
 };
 
 pub const MAX_T: f32 = f32::MAX;
 pub const MIN_T: f32 = 0.001;
 
+/// How the radiance arriving along each camera ray is estimated.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Integrator {
+    /// Follow scattered rays until they happen to hit a light or the sky.
+    Naive,
+    /// Also sample a light at every diffuse hit, weighting light and scattering samples with
+    /// multiple importance sampling.
+    Mis,
+}
+
+impl Integrator {
+    pub const NAMES: [&'static str; 2] = ["mis", "naive"];
+}
+
+impl FromStr for Integrator {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "naive" => Ok(Integrator::Naive),
+            "mis" => Ok(Integrator::Mis),
+            _ => Err(format!("unrecognised integrator '{}'", s)),
+        }
+    }
+}
+
+/// The power heuristic with an exponent of two for weighting a sample drawn with density
+/// `pdf` against another strategy that could have drawn it with density `other_pdf`.
+#[inline]
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let pdf_squared = pdf * pdf;
+    if pdf_squared > 0.0 {
+        pdf_squared / (pdf_squared + other_pdf * other_pdf)
+    } else {
+        0.0
+    }
+}
+
+/// Counts gathered while rendering one or more frames.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TraceStats {
+    pub ray_count: usize,
+    pub path_count: usize,
+    pub path_depth_total: usize,
+    pub max_path_depth: u32,
+}
+
+impl TraceStats {
+    pub fn add(&mut self, other: &TraceStats) {
+        self.ray_count += other.ray_count;
+        self.path_count += other.path_count;
+        self.path_depth_total += other.path_depth_total;
+        self.max_path_depth = self.max_path_depth.max(other.max_path_depth);
+    }
+
+    /// The average number of bounces made by each path.
+    pub fn mean_path_depth(&self) -> f64 {
+        if self.path_count > 0 {
+            self.path_depth_total as f64 / self.path_count as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A path part way through being traced.
+#[derive(Copy, Clone, Debug)]
+struct Path {
+    ray: Ray,
+    radiance: Vec3,
+    throughput: Vec3,
+    /// Density `ray` was scattered with, none for camera rays and delta lobes.
+    scattering_pdf: Option<f32>,
+    depth: u32,
+}
+
+impl Path {
+    fn new(ray: Ray) -> Path {
+        Path {
+            ray,
+            radiance: Vec3::ZERO,
+            throughput: Vec3::ONE,
+            scattering_pdf: None,
+            depth: 0,
+        }
+    }
+}
+
+/// A hit a path has scattered from, waiting for the shadow ray of its light sample.
+#[derive(Copy, Clone, Debug)]
+struct Bounce<'m> {
+    ray_hit: RayHit,
+    material: &'m Material<'m>,
+    wo: Vec3,
+    sample: BsdfSample,
+    light: Option<LightSample>,
+}
+
+/// A shadow ray towards a light and the densities of it being sampled by the light and by the
+/// material it scatters from.
+#[derive(Copy, Clone, Debug)]
+struct LightSample {
+    ray: Ray,
+    light_pdf: f32,
+    scattering_pdf: f32,
+}
+
+/// What rays are traced through, a scene with instances owns its TLAS so they can be moved.
+enum World<'a> {
+    Hitable(Hitable<'a>),
+    Tlas(Tlas<'a>),
+}
+
 pub struct Scene<'a> {
-    world: Hitable<'a>,
+    world: World<'a>,
+    lights: Lights<'a>,
     sky: Option<Vec3>,
+    /// How many pixels are rendered together with their camera rays traced as a packet, 1 if
+    /// the CPU can't trace packets.
+    packet_size: usize,
     ray_count: AtomicUsize,
+    path_count: AtomicUsize,
+    path_depth_total: AtomicUsize,
+    max_path_depth: AtomicU32,
 }
 
 impl<'a> Scene<'a> {
-    pub fn new(world: Hitable<'a>, sky: Option<Vec3>) -> Scene<'a> {
+    pub fn new(world: Hitable<'a>, lights: Lights<'a>, sky: Option<Vec3>) -> Scene<'a> {
+        Scene::with_world(World::Hitable(world), lights, sky)
+    }
+
+    /// A scene traced through `tlas`, whose instances can be moved between frames.
+    pub fn with_tlas(tlas: Tlas<'a>, lights: Lights<'a>, sky: Option<Vec3>) -> Scene<'a> {
+        Scene::with_world(World::Tlas(tlas), lights, sky)
+    }
+
+    fn with_world(world: World<'a>, lights: Lights<'a>, sky: Option<Vec3>) -> Scene<'a> {
         Scene {
             world,
+            lights,
             sky,
+            packet_size: RayPacket::detect_size(),
             ray_count: AtomicUsize::new(0),
+            path_count: AtomicUsize::new(0),
+            path_depth_total: AtomicUsize::new(0),
+            max_path_depth: AtomicU32::new(0),
+        }
+    }
+
+    pub fn world(&self) -> Hitable<'_> {
+        match &self.world {
+            World::Hitable(hitable) => *hitable,
+            World::Tlas(tlas) => Hitable::Tlas(tlas),
+        }
+    }
+
+    /// The number of instances that can be moved with `set_instance_transform`, 0 unless the
+    /// scene is traced through a TLAS.
+    pub fn num_instances(&self) -> usize {
+        match &self.world {
+            World::Hitable(_) => 0,
+            World::Tlas(tlas) => tlas.num_instances(),
+        }
+    }
+
+    /// The instance at `index`, in the order they were in the scene's hitables, with the
+    /// transform it was loaded with.
+    pub fn instance(&self, index: usize) -> &'a Instance<'a> {
+        match &self.world {
+            World::Hitable(_) => panic!("scene has no movable instances"),
+            World::Tlas(tlas) => tlas.instance(index),
+        }
+    }
+
+    /// Moves the instance at `index`. The BVH is only updated to match by `update_bvh`, which
+    /// should be called once all the instances have been moved.
+    pub fn set_instance_transform(&mut self, index: usize, transform: Affine3A) {
+        match &mut self.world {
+            World::Hitable(_) => panic!("scene has no movable instances"),
+            World::Tlas(tlas) => tlas.set_transform(index, transform),
         }
     }
 
-    pub fn print_ray_trace(&self, ray: &Ray, rng: &mut Xoshiro256Plus) {
-        if let Hitable::BVHNode(node) = self.world {
-            node.print_ray_hit(ray, MIN_T, MAX_T, rng);
+    /// Refits the BVH over the instances to their current transforms, or rebuilds it if
+    /// refitting has made it too slow to trace. Returns `None` if the scene has no instances.
+    pub fn update_bvh(&mut self) -> Option<TlasUpdate> {
+        match &mut self.world {
+            World::Hitable(_) => None,
+            World::Tlas(tlas) => Some(tlas.update()),
+        }
+    }
+
+    /// Prints the stats of the scene's BVH, built over the shutter interval `t0` to `t1`, and
+    /// the nodes `ray` visits.
+    pub fn print_ray_trace(&self, ray: &Ray, t0: f32, t1: f32, sampler: &mut dyn Sampler) {
+        if let Hitable::BVHNode(node) = self.world() {
+            dbg!(node.get_stats(t0, t1));
+            node.print_ray_hit(ray, MIN_T, MAX_T, sampler);
         }
     }
 
@@ -46,77 +236,631 @@ impl<'a> Scene<'a> {
         }
     }
 
-    fn ray_trace(
+    /// Traces a path from `ray` and returns the light arriving back along it, adding the
+    /// number of bounces the path made to `path_depth`.
+    ///
+    /// With `Integrator::Mis` a light is also sampled at every hit with a material that isn't
+    /// purely specular. Emission found by a diffusely scattered ray could also have been found by
+    /// the previous hit's light sample, so it is weighted against the density of the light
+    /// sample having chosen the same direction.
+    ///
+    /// Paths are never cut off at a fixed depth, after `min_depth` bounces they are terminated
+    /// by Russian roulette instead which keeps the estimate unbiased.
+    ///
+    /// If `first_hit` is given the surface `ray` hits is added to it.
+    #[allow(clippy::too_many_arguments)]
+    fn trace_path(
         &self,
-        ray_in: &Ray,
-        depth: u32,
-        max_depth: u32,
-        rng: &mut Xoshiro256Plus,
+        ray: &Ray,
+        integrator: Integrator,
+        min_depth: u32,
+        sampler: &mut dyn Sampler,
         ray_count: &mut usize,
+        path_depth: &mut u32,
+        first_hit: Option<(&SceneIds, &mut AovPixel)>,
     ) -> Vec3 {
+        let world = self.world();
+        let mut path = Path::new(*ray);
         *ray_count += 1;
-        if let Some((ray_hit, material)) = self.world.ray_hit(ray_in, MIN_T, MAX_T, rng) {
-            let emitted = material.emitted(ray_hit.u, ray_hit.v, ray_hit.point);
-            if depth < max_depth {
-                if let Some((attenuation, scattered)) = material.scatter(ray_in, &ray_hit, rng) {
-                    return emitted
-                        + attenuation
-                            * self.ray_trace(&scattered, depth + 1, max_depth, rng, ray_count);
+        let hit = if let Some((ids, aov)) = first_hit {
+            world
+                .ray_pick(ray, MIN_T, MAX_T, sampler)
+                .map(|(ray_hit, material, primitive)| {
+                    aov.add_hit(ids, ray, &ray_hit, material, &primitive);
+                    (ray_hit, material)
+                })
+        } else {
+            world.ray_hit(ray, MIN_T, MAX_T, sampler)
+        };
+        if self.bounce(&mut path, hit, integrator, min_depth, sampler, ray_count) {
+            self.continue_path(&mut path, integrator, min_depth, sampler, ray_count);
+        }
+        *path_depth = path.depth;
+        path.radiance
+    }
+
+    /// Like `trace_path` for each ray of `packet` in `mask`, using the sampler of its lane.
+    ///
+    /// The camera rays are traced as a packet and so are the shadow rays of their light
+    /// samples, which start close together and head for the same lights. The rest of each path
+    /// is traced one ray at a time as scattered rays soon go their own way.
+    #[allow(clippy::too_many_arguments)]
+    fn trace_packet(
+        &self,
+        packet: &RayPacket,
+        mask: u32,
+        integrator: Integrator,
+        min_depth: u32,
+        samplers: &mut [Box<dyn Sampler>],
+        ray_count: &mut usize,
+        path_depths: &mut [u32; MAX_PACKET_SIZE],
+        mut first_hits: Option<(&SceneIds, &mut [AovPixel])>,
+    ) -> [Vec3; MAX_PACKET_SIZE] {
+        let world = self.world();
+        *ray_count += mask.count_ones() as usize;
+        let mut hits = PacketHits::new(MAX_T);
+        world.ray_pick_packet(packet, MIN_T, mask, &mut hits, samplers);
+
+        let mut paths = packet.rays.map(Path::new);
+        let mut bounces = [None; MAX_PACKET_SIZE];
+        let mut shadow_packet = RayPacket::new(packet.size());
+        let mut shadow_mask = 0u32;
+        for lane in lanes(mask) {
+            let hit = hits.hits[lane].map(|(ray_hit, material, primitive)| {
+                if let Some((ids, aovs)) = &mut first_hits {
+                    aovs[lane].add_hit(ids, &packet.rays[lane], &ray_hit, material, &primitive);
+                }
+                (ray_hit, material)
+            });
+            let sampler = samplers[lane].as_mut();
+            bounces[lane] = self.scatter(&mut paths[lane], hit, integrator, sampler);
+            if let Some(light) = bounces[lane].and_then(|bounce: Bounce| bounce.light) {
+                shadow_packet.set(lane, light.ray);
+                shadow_mask |= 1 << lane;
+            }
+        }
+
+        *ray_count += shadow_mask.count_ones() as usize;
+        let mut light_hits = PacketHits::new(MAX_T);
+        world.ray_pick_packet(
+            &shadow_packet,
+            MIN_T,
+            shadow_mask,
+            &mut light_hits,
+            samplers,
+        );
+
+        let mut radiance = [Vec3::ZERO; MAX_PACKET_SIZE];
+        for lane in lanes(mask) {
+            let path = &mut paths[lane];
+            if let Some(bounce) = &bounces[lane] {
+                let light_hit =
+                    light_hits.hits[lane].map(|(light_hit, material, _)| (light_hit, material));
+                let sampler = samplers[lane].as_mut();
+                if self.finish_bounce(path, bounce, light_hit, integrator, min_depth, sampler) {
+                    self.continue_path(path, integrator, min_depth, sampler, ray_count);
                 }
             }
-            emitted
+            radiance[lane] = path.radiance;
+            path_depths[lane] = path.depth;
+        }
+        radiance
+    }
+
+    /// Traces `path` until it is terminated.
+    fn continue_path(
+        &self,
+        path: &mut Path,
+        integrator: Integrator,
+        min_depth: u32,
+        sampler: &mut dyn Sampler,
+        ray_count: &mut usize,
+    ) {
+        let world = self.world();
+        loop {
+            *ray_count += 1;
+            let hit = world.ray_hit(&path.ray, MIN_T, MAX_T, sampler);
+            if !self.bounce(path, hit, integrator, min_depth, sampler, ray_count) {
+                break;
+            }
+        }
+    }
+
+    /// Scatters `path` from `hit`, what its ray hit, tracing the shadow ray of the light
+    /// sample on its own. Returns false if the path is terminated.
+    fn bounce(
+        &self,
+        path: &mut Path,
+        hit: Option<(RayHit, &Material)>,
+        integrator: Integrator,
+        min_depth: u32,
+        sampler: &mut dyn Sampler,
+        ray_count: &mut usize,
+    ) -> bool {
+        let world = self.world();
+        let bounce = match self.scatter(path, hit, integrator, sampler) {
+            Some(bounce) => bounce,
+            None => return false,
+        };
+        let light_hit = bounce.light.and_then(|light| {
+            *ray_count += 1;
+            world.ray_hit(&light.ray, MIN_T, MAX_T, sampler)
+        });
+        self.finish_bounce(path, &bounce, light_hit, integrator, min_depth, sampler)
+    }
+
+    /// Adds the light emitted by `hit`, or the sky if `path`'s ray missed, and samples the
+    /// direction the path scatters in along with a light to trace a shadow ray towards.
+    /// Returns `None` if the path ends here.
+    fn scatter<'m>(
+        &self,
+        path: &mut Path,
+        hit: Option<(RayHit, &'m Material<'m>)>,
+        integrator: Integrator,
+        sampler: &mut dyn Sampler,
+    ) -> Option<Bounce<'m>> {
+        let (ray_hit, material) = match hit {
+            Some(hit) => hit,
+            None => {
+                path.radiance += path.throughput * self.sky(&path.ray);
+                return None;
+            }
+        };
+
+        let mut emitted = material.emitted(ray_hit.u, ray_hit.v, ray_hit.point);
+        if integrator == Integrator::Mis && emitted != Vec3::ZERO {
+            if let Some(scattering_pdf) = path.scattering_pdf {
+                let light_pdf = self.lights.pdf_value(path.ray.origin, path.ray.direction);
+                emitted *= power_heuristic(scattering_pdf, light_pdf);
+            }
+        }
+        path.radiance += path.throughput * emitted;
+
+        let wo = -path.ray.direction.normalize();
+        let sample = material.sample(&ray_hit, wo, sampler)?;
+        let light = if integrator == Integrator::Mis && !material.is_delta() {
+            self.sample_light(&path.ray, &ray_hit, material, wo, sampler)
         } else {
-            self.sky(ray_in)
+            None
+        };
+        Some(Bounce {
+            ray_hit,
+            material,
+            wo,
+            sample,
+            light,
+        })
+    }
+
+    /// Adds the light `bounce`'s shadow ray found at `light_hit` and moves `path` on to its
+    /// scattered ray. Returns false if the path is terminated.
+    fn finish_bounce(
+        &self,
+        path: &mut Path,
+        bounce: &Bounce,
+        light_hit: Option<(RayHit, &Material)>,
+        integrator: Integrator,
+        min_depth: u32,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        if integrator == Integrator::Mis && !bounce.material.is_delta() {
+            path.radiance += path.throughput * Scene::light_radiance(bounce, light_hit);
+        }
+
+        path.throughput *= bounce.sample.weight();
+        path.depth += 1;
+        if path.depth >= min_depth {
+            // the less light a path can still carry the more likely it is terminated, the
+            // survivors are scaled up to compensate. Always allow some chance of termination
+            // so lossless paths, such as total internal reflection in glass, still end.
+            let survival_prob = path.throughput.max_element().min(0.95);
+            if sampler.next_1d() >= survival_prob {
+                return false;
+            }
+            path.throughput /= survival_prob;
+        }
+
+        path.ray = Ray::new(bounce.ray_hit.point, bounce.sample.wi, path.ray.time);
+        path.scattering_pdf = if bounce.sample.is_delta {
+            None
+        } else {
+            Some(bounce.sample.pdf)
+        };
+        true
+    }
+
+    /// Samples a direction from `ray_hit` towards a random light for `material` scattering
+    /// towards `wo`. Returns `None` if no light can be sampled or `material` can't scatter
+    /// light from it.
+    fn sample_light(
+        &self,
+        ray_in: &Ray,
+        ray_hit: &RayHit,
+        material: &Material,
+        wo: Vec3,
+        sampler: &mut dyn Sampler,
+    ) -> Option<LightSample> {
+        let direction = self.lights.random_direction(ray_hit.point, sampler)?;
+        let scattering_pdf = material.pdf(ray_hit, direction, wo);
+        let light_pdf = self.lights.pdf_value(ray_hit.point, direction);
+        if scattering_pdf <= 0.0 || light_pdf <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            ray: Ray::new(ray_hit.point, direction, ray_in.time),
+            light_pdf,
+            scattering_pdf,
+        })
+    }
+
+    /// Returns the light arriving at `bounce`'s hit from whatever its shadow ray hit,
+    /// `light_hit`, scattered towards `bounce.wo` and weighted against the material sampling
+    /// the same direction.
+    ///
+    /// Whatever the shadow ray hits first contributes its emission, not just the sampled light.
+    /// This keeps it consistent with the weighting in `scatter`, which doesn't know which light
+    /// a scattered ray would have been sampled from either.
+    fn light_radiance(bounce: &Bounce, light_hit: Option<(RayHit, &Material)>) -> Vec3 {
+        match (bounce.light, light_hit) {
+            (Some(light), Some((light_hit, light_material))) => {
+                let emitted = light_material.emitted(light_hit.u, light_hit.v, light_hit.point);
+                bounce
+                    .material
+                    .f(&bounce.ray_hit, light.ray.direction, bounce.wo)
+                    * emitted
+                    / light.light_pdf
+                    * power_heuristic(light.light_pdf, light.scattering_pdf)
+            }
+            _ => Vec3::ZERO,
         }
     }
 
-    pub fn update(
+    /// Renders one frame into `buffer`, blending it with the previous `frame_num` frames. If
+    /// `aovs` is given the auxiliary outputs are gathered from the same camera rays.
+    ///
+    /// Only the pixels of `tiles` are rendered, the tiles are started in the order given and
+    /// `on_tile` is called as each one starts and finishes.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update<F>(
         &self,
         params: &Params,
         camera: &Camera,
         frame_num: u32,
+        tiles: &[Tile],
         buffer: &mut [(f32, f32, f32)],
-    ) -> usize {
+        aovs: Option<&mut AovBuffer>,
+        on_tile: F,
+    ) -> TraceStats
+    where
+        F: Fn(TileEvent) + Sync,
+    {
         self.ray_count.store(0, Ordering::Relaxed);
+        self.path_count.store(0, Ordering::Relaxed);
+        self.path_depth_total.store(0, Ordering::Relaxed);
+        self.max_path_depth.store(0, Ordering::Relaxed);
+
+        // shared by every pixel of the frame, samplers derive their own per pixel seeds from it.
+        // It only depends on the frame and `params.seed` so a resumed render continues the same
+        // sequences
+        let frame_seed = sampler::hash(
+            frame_num
+                ^ sampler::hash(params.seed as u32 ^ sampler::hash((params.seed >> 32) as u32)),
+        );
+
+        let (ids, aov_pixels) = match aovs {
+            Some(aovs) => {
+                assert_eq!(aovs.pixels.len(), buffer.len());
+                (Some(&aovs.ids), Some(&mut aovs.pixels[..]))
+            }
+            None => (None, None),
+        };
+        let film = Mutex::new((buffer, aov_pixels));
+        let next_tile = AtomicUsize::new(0);
+        let completed = AtomicUsize::new(0);
+
+        // each thread takes the next tile when it is free so tiles start in order, they are
+        // rendered into a copy so the buffers are only locked to copy them in and out
+        rayon::scope(|scope| {
+            for _ in 0..rayon::current_num_threads() {
+                scope.spawn(|_| {
+                    let mut pixels = Vec::new();
+                    let mut colors = Vec::new();
+                    let mut aovs = Vec::new();
+                    let mut sample_counts = Vec::new();
+                    while let Some(&tile) = tiles.get(next_tile.fetch_add(1, Ordering::Relaxed)) {
+                        on_tile(TileEvent::Started(tile));
+                        {
+                            let film = film.lock().unwrap();
+                            colors.clear();
+                            colors.extend(tile.pixel_indices(params.width).map(|i| film.0[i]));
+                            if let Some(aov_pixels) = &film.1 {
+                                aovs.clear();
+                                aovs.extend(
+                                    tile.pixel_indices(params.width).map(|i| aov_pixels[i]),
+                                );
+                            }
+                        }
 
+                        pixels.clear();
+                        pixels.extend(tile.pixel_indices(params.width).map(|i| i as u32));
+                        sample_counts.clear();
+                        for (n, chunk) in pixels.chunks(self.packet_size).enumerate() {
+                            let start = n * self.packet_size;
+                            let end = start + chunk.len();
+                            self.render_pixels(
+                                params,
+                                camera,
+                                frame_num,
+                                frame_seed,
+                                chunk,
+                                &mut colors[start..end],
+                                ids.map(|ids| (ids, &mut aovs[start..end])),
+                                &mut sample_counts,
+                            );
+                        }
+
+                        let completed = {
+                            let mut film = film.lock().unwrap();
+                            let (buffer, aov_pixels) = &mut *film;
+                            for (n, i) in tile.pixel_indices(params.width).enumerate() {
+                                buffer[i] = colors[n];
+                                if let Some(aov_pixels) = aov_pixels {
+                                    aov_pixels[i] = aovs[n];
+                                }
+                            }
+                            completed.fetch_add(1, Ordering::Relaxed) + 1
+                        };
+                        on_tile(TileEvent::Finished {
+                            tile,
+                            pixels: &colors,
+                            aovs: ids.map(|_| &aovs[..]),
+                            sample_counts: &sample_counts,
+                            completed,
+                            total: tiles.len(),
+                        });
+                    }
+                });
+            }
+        });
+
+        TraceStats {
+            ray_count: self.ray_count.load(Ordering::Relaxed),
+            path_count: self.path_count.load(Ordering::Relaxed),
+            path_depth_total: self.path_depth_total.load(Ordering::Relaxed),
+            max_path_depth: self.max_path_depth.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Renders `pixels`, at most `packet_size` of them, taking `params.samples` samples each or
+    /// with adaptive sampling continuing until each pixel's estimated error is low enough, and
+    /// appends the number of samples taken for each to `sample_counts`.
+    ///
+    /// The samples are taken in rounds of one for every pixel that still needs one, whose
+    /// camera rays are traced as a packet.
+    #[allow(clippy::too_many_arguments)]
+    fn render_pixels(
+        &self,
+        params: &Params,
+        camera: &Camera,
+        frame_num: u32,
+        frame_seed: u32,
+        pixels: &[u32],
+        colors_out: &mut [(f32, f32, f32)],
+        mut aovs_out: Option<(&SceneIds, &mut [AovPixel])>,
+        sample_counts: &mut Vec<u32>,
+    ) {
         let inv_nx = 1.0 / params.width as f32;
         let inv_ny = 1.0 / params.height as f32;
-        let inv_ns = 1.0 / params.samples as f32;
 
         let mix_prev = frame_num as f32 / (frame_num + 1) as f32;
         let mix_new = 1.0 - mix_prev;
 
-        // parallel iterate each pixel
-        buffer
-            .par_iter_mut()
-            .enumerate()
-            .for_each(|(i, color_out)| {
-                let y = i as u32 / params.width;
-                let x = i as u32 - (y * params.width);
-                let mut rng = if params.random_seed {
-                    Xoshiro256Plus::seed_from_u64(rand::random())
-                } else {
-                    Xoshiro256Plus::seed_from_u64(
-                        (x as u64 * 1973 + y as u64 * 9277 + frame_num as u64 * 26699) | 1,
-                    )
+        let mut coords = [(0, 0); MAX_PACKET_SIZE];
+        for (coord, &i) in coords.iter_mut().zip(pixels) {
+            let y = i / params.width;
+            *coord = (i - (y * params.width), y);
+        }
+        let mut samplers: Vec<Box<dyn Sampler>> = coords[..pixels.len()]
+            .iter()
+            .map(|&(x, y)| params.sampler.new_sampler(x, y, frame_seed, params.samples))
+            .collect();
+
+        let mut ray_count = 0;
+        let mut path_depth_total = 0;
+        let mut max_path_depth = 0;
+        let mut cols = [Vec3::ZERO; MAX_PACKET_SIZE];
+        let mut aovs = [AovPixel::default(); MAX_PACKET_SIZE];
+        let ids = aovs_out.as_ref().map(|(ids, _)| *ids);
+        let mut variances = [PixelVariance::default(); MAX_PACKET_SIZE];
+        let mut camera_rays = Vec::with_capacity(MAX_PACKET_SIZE);
+        loop {
+            let mut mask = 0;
+            for (lane, variance) in variances.iter().enumerate().take(pixels.len()) {
+                let converged = match params.adaptive {
+                    Some(adaptive) => adaptive.is_converged(variance, params.samples),
+                    None => variance.count() >= params.samples,
                 };
+                if !converged {
+                    mask |= 1 << lane;
+                }
+            }
+            if mask == 0 {
+                break;
+            }
+
+            camera_rays.clear();
+            for lane in lanes(mask) {
+                let sampler = samplers[lane].as_mut();
+                sampler.start_sample(variances[lane].count());
+                let (x, y) = coords[lane];
+                let (jitter_x, jitter_y) = sampler.next_2d();
+                let u = (x as f32 + jitter_x) * inv_nx;
+                let v = (y as f32 + jitter_y) * inv_ny;
+                camera_rays.push((lane, camera.get_ray(u, v, sampler)));
+            }
 
-                let mut ray_count = 0;
-                let mut col = Vec3::ZERO;
-                for _ in 0..params.samples {
-                    let u = (x as f32 + rng.gen::<f32>()) * inv_nx;
-                    let v = (y as f32 + rng.gen::<f32>()) * inv_ny;
-                    let ray = camera.get_ray(u, v, &mut rng);
-                    col += self.ray_trace(&ray, 0, params.max_depth, &mut rng, &mut ray_count);
+            let mut path_depths = [0; MAX_PACKET_SIZE];
+            let samples = if self.packet_size > 1 {
+                let mut packet = RayPacket::new(self.packet_size);
+                for &(lane, ray) in &camera_rays {
+                    packet.set(lane, ray);
                 }
+                self.trace_packet(
+                    &packet,
+                    mask,
+                    params.integrator,
+                    params.min_depth,
+                    &mut samplers,
+                    &mut ray_count,
+                    &mut path_depths,
+                    ids.map(|ids| (ids, &mut aovs[..])),
+                )
+            } else {
+                let mut samples = [Vec3::ZERO; MAX_PACKET_SIZE];
+                for &(lane, ray) in &camera_rays {
+                    samples[lane] = self.trace_path(
+                        &ray,
+                        params.integrator,
+                        params.min_depth,
+                        samplers[lane].as_mut(),
+                        &mut ray_count,
+                        &mut path_depths[lane],
+                        ids.map(|ids| (ids, &mut aovs[lane])),
+                    );
+                }
+                samples
+            };
+            for lane in lanes(mask) {
+                cols[lane] += samples[lane];
+                variances[lane].add(samples[lane]);
+                path_depth_total += path_depths[lane] as usize;
+                max_path_depth = max_path_depth.max(path_depths[lane]);
+            }
+        }
 
-                col *= inv_ns;
-                color_out.0 = color_out.0 * mix_prev + col.x * mix_new;
-                color_out.1 = color_out.1 * mix_prev + col.y * mix_new;
-                color_out.2 = color_out.2 * mix_prev + col.z * mix_new;
+        let mut path_count = 0;
+        for (lane, color_out) in colors_out.iter_mut().enumerate() {
+            let sample_count = variances[lane].count();
+            let col = cols[lane] / sample_count.max(1) as f32;
+            color_out.0 = color_out.0 * mix_prev + col.x * mix_new;
+            color_out.1 = color_out.1 * mix_prev + col.y * mix_new;
+            color_out.2 = color_out.2 * mix_prev + col.z * mix_new;
 
-                self.ray_count.fetch_add(ray_count, Ordering::Relaxed);
-            });
-        self.ray_count.load(Ordering::Relaxed)
+            if let Some((_, aovs_out)) = &mut aovs_out {
+                aovs[lane].average(sample_count);
+                aovs_out[lane].blend(&aovs[lane], mix_prev);
+            }
+
+            path_count += sample_count as usize;
+            sample_counts.push(sample_count);
+        }
+
+        self.ray_count.fetch_add(ray_count, Ordering::Relaxed);
+        self.path_count.fetch_add(path_count, Ordering::Relaxed);
+        self.path_depth_total
+            .fetch_add(path_depth_total, Ordering::Relaxed);
+        self.max_path_depth
+            .fetch_max(max_path_depth, Ordering::Relaxed);
+    }
+}
+
+#[cfg(all(feature = "bench", test))]
+mod bench {
+    use crate::{
+        bench::PARAMS,
+        collision::{PacketHits, RayPacket},
+        params::{Accel, Params},
+        presets,
+        sampler::Sampler,
+        scene::{MAX_T, MIN_T},
+        storage::Storage,
+    };
+    use test::Bencher;
+
+    /// Traces the camera rays through the centre of every pixel of a 1280x720 frame of
+    /// `random_spheres` to their first hit, in packets of `packet_size` consecutive pixels or
+    /// one at a time if `packet_size` is 1.
+    fn random_spheres_720p(b: &mut Bencher, accel: Accel, packet_size: usize) {
+        let params = Params {
+            width: 1280,
+            height: 720,
+            accel,
+            ..PARAMS
+        };
+        let mut rng = params.new_rng();
+        let storage = Storage::new(&mut rng);
+        let (hitables, camera, sky) = presets::random_spheres(&params, &mut rng, &storage);
+        let scene = params.new_scene(&mut rng, &storage, hitables, &camera, sky);
+        let world = scene.world();
+        let mut rays = Vec::with_capacity((params.width * params.height) as usize);
+        for y in 0..params.height {
+            for x in 0..params.width {
+                let u = (x as f32 + 0.5) / params.width as f32;
+                let v = (y as f32 + 0.5) / params.height as f32;
+                rays.push(camera.get_ray(u, v, &mut rng));
+            }
+        }
+        let mut samplers: Vec<Box<dyn Sampler>> = (0..packet_size)
+            .map(|lane| {
+                params
+                    .sampler
+                    .new_sampler(lane as u32, 0, 0, params.samples)
+            })
+            .collect();
+        b.iter(|| {
+            let mut num_hits = 0;
+            if packet_size > 1 {
+                for chunk in rays.chunks(packet_size) {
+                    let mut packet = RayPacket::new(packet_size);
+                    for (lane, ray) in chunk.iter().enumerate() {
+                        packet.set(lane, *ray);
+                    }
+                    let mut hits = PacketHits::new(MAX_T);
+                    let mask = (1 << chunk.len()) - 1;
+                    world.ray_pick_packet(&packet, MIN_T, mask, &mut hits, &mut samplers);
+                    num_hits += hits.hits.iter().filter(|hit| hit.is_some()).count();
+                }
+            } else {
+                for ray in &rays {
+                    if world
+                        .ray_pick(ray, MIN_T, MAX_T, samplers[0].as_mut())
+                        .is_some()
+                    {
+                        num_hits += 1;
+                    }
+                }
+            }
+            num_hits
+        });
+    }
+
+    #[bench]
+    fn random_spheres_720p_soa_single(b: &mut Bencher) {
+        random_spheres_720p(b, Accel::Soa, 1);
+    }
+
+    #[bench]
+    fn random_spheres_720p_soa_packet(b: &mut Bencher) {
+        random_spheres_720p(b, Accel::Soa, RayPacket::detect_size());
+    }
+
+    #[bench]
+    fn random_spheres_720p_bvh_single(b: &mut Bencher) {
+        random_spheres_720p(b, Accel::Bvh, 1);
+    }
+
+    #[bench]
+    fn random_spheres_720p_bvh_packet(b: &mut Bencher) {
+        random_spheres_720p(b, Accel::Bvh, RayPacket::detect_size());
+    }
+
+    #[bench]
+    fn random_spheres_720p_linear_single(b: &mut Bencher) {
+        random_spheres_720p(b, Accel::LinearBvh, 1);
+    }
+
+    #[bench]
+    fn random_spheres_720p_linear_packet(b: &mut Bencher) {
+        random_spheres_720p(b, Accel::LinearBvh, RayPacket::detect_size());
     }
 }
@@ -0,0 +1,1332 @@
+use crate::{
+    camera::{Camera, CameraDesc},
+    collision::{
+        BVHBuilder, BVHNode, ConstantMedium, Cuboid, Hitable, Instance, MovingSphere, Rect, Sphere,
+        Triangle, TriangleMesh,
+    },
+    material::{self, Material},
+    obj::{self, ObjError},
+    params::Params,
+    source::SceneDescription,
+    storage::Storage,
+    texture::{self, RgbImage, Texture},
+};
+use glam::{Affine3A, Quat, Vec2, Vec3};
+use rand_xoshiro::Xoshiro256Plus;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    env, error, fmt, fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SceneFile {
+    pub camera: CameraDesc,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sky: Option<Vec3>,
+    #[serde(default)]
+    pub textures: Vec<TextureDesc>,
+    #[serde(default)]
+    pub materials: Vec<MaterialDesc>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub geometries: Vec<GeometryDesc>,
+    pub objects: Vec<ObjectDesc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TextureDesc {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: TextureKind,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TextureKind {
+    Constant { color: Vec3 },
+    Checker { odd: String, even: String },
+    Noise { scale: f32 },
+    Image { path: PathBuf },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MaterialDesc {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: MaterialKind,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MaterialKind {
+    Lambertian {
+        albedo: String,
+    },
+    Metal {
+        albedo: Vec3,
+        #[serde(default)]
+        fuzz: f32,
+    },
+    Dielectric {
+        ref_idx: f32,
+    },
+    DiffuseLight {
+        emit: String,
+    },
+    Isotropic {
+        albedo: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RectPlane {
+    Xy,
+    Xz,
+    Yz,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RotationDesc {
+    pub axis: Vec3,
+    pub degrees: f32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TransformDesc {
+    #[serde(default)]
+    pub translation: Vec3,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<RotationDesc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<Vec3>,
+}
+
+/// A named object that `ObjectDesc::Geometry` refers to. It is loaded once however many times
+/// it's referred to, so instances of it share the geometry and its acceleration structure.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GeometryDesc {
+    pub name: String,
+    #[serde(flatten)]
+    pub object: ObjectDesc,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ObjectDesc {
+    Sphere {
+        centre: Vec3,
+        radius: f32,
+        material: String,
+    },
+    MovingSphere {
+        centre0: Vec3,
+        centre1: Vec3,
+        time0: f32,
+        time1: f32,
+        radius: f32,
+        material: String,
+    },
+    /// An axis aligned rectangle, `a` and `b` are the two in-plane axes in `plane` order and
+    /// `k` is the position along the remaining axis.
+    Rect {
+        plane: RectPlane,
+        a0: f32,
+        a1: f32,
+        b0: f32,
+        b1: f32,
+        k: f32,
+        #[serde(default)]
+        flip_normals: bool,
+        material: String,
+    },
+    Cuboid {
+        min: Vec3,
+        max: Vec3,
+        material: String,
+    },
+    Triangle {
+        vertices: [Vec3; 3],
+        material: String,
+    },
+    /// An inline indexed triangle mesh, either `material` applies to every face or
+    /// `face_materials` lists one material per face.
+    Mesh {
+        positions: Vec<Vec3>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        normals: Vec<Vec3>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        uvs: Vec<Vec2>,
+        indices: Vec<[u32; 3]>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        material: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        face_materials: Vec<String>,
+    },
+    Obj {
+        path: PathBuf,
+    },
+    Instance {
+        #[serde(default)]
+        transform: TransformDesc,
+        object: Box<ObjectDesc>,
+    },
+    ConstantMedium {
+        boundary: Box<ObjectDesc>,
+        density: f32,
+        albedo: String,
+    },
+    Bvh {
+        objects: Vec<ObjectDesc>,
+    },
+    List {
+        objects: Vec<ObjectDesc>,
+    },
+    /// A reference to an entry of `geometries`, usually as the object of an instance.
+    Geometry {
+        name: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum SceneFileError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// A semantic error in the entry identified by `entry`, e.g. `objects[3].material`.
+    Entry {
+        path: PathBuf,
+        entry: String,
+        message: String,
+    },
+    Obj {
+        path: PathBuf,
+        entry: String,
+        source: Box<ObjError>,
+    },
+}
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneFileError::Io { path, source } => {
+                write!(f, "failed to access '{}': {}", path.display(), source)
+            }
+            SceneFileError::Json { path, source } => write!(f, "{}: {}", path.display(), source),
+            SceneFileError::Entry {
+                path,
+                entry,
+                message,
+            } => write!(f, "{}: {}: {}", path.display(), entry, message),
+            SceneFileError::Obj {
+                path,
+                entry,
+                source,
+            } => write!(f, "{}: {}: {}", path.display(), entry, source),
+        }
+    }
+}
+
+impl error::Error for SceneFileError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SceneFileError::Io { source, .. } => Some(source),
+            SceneFileError::Json { source, .. } => Some(source),
+            SceneFileError::Obj { source, .. } => Some(source.as_ref()),
+            SceneFileError::Entry { .. } => None,
+        }
+    }
+}
+
+/// Deserializes each entry of a top level array separately so errors can name the entry.
+fn parse_entries<T>(
+    path: &Path,
+    root: &mut serde_json::Map<String, Value>,
+    key: &str,
+    required: bool,
+) -> Result<Vec<T>, SceneFileError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let entry_error = |entry: String, message: String| SceneFileError::Entry {
+        path: path.to_path_buf(),
+        entry,
+        message,
+    };
+    let values = match root.remove(key) {
+        Some(Value::Array(values)) => values,
+        Some(_) => {
+            return Err(entry_error(
+                key.to_string(),
+                "expected an array".to_string(),
+            ))
+        }
+        None if required => {
+            return Err(entry_error(
+                key.to_string(),
+                "missing required section".to_string(),
+            ))
+        }
+        None => Vec::new(),
+    };
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| {
+            let entry = match value.get("name").and_then(Value::as_str) {
+                Some(name) => format!("{}[{}] ('{}')", key, index, name),
+                None => format!("{}[{}]", key, index),
+            };
+            serde_json::from_value(value).map_err(|err| entry_error(entry, err.to_string()))
+        })
+        .collect()
+}
+
+impl SceneFile {
+    pub fn open(path: &Path) -> Result<SceneFile, SceneFileError> {
+        let source = fs::read_to_string(path).map_err(|source| SceneFileError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let json_error = |source| SceneFileError::Json {
+            path: path.to_path_buf(),
+            source,
+        };
+        let mut root = match serde_json::from_str(&source).map_err(json_error)? {
+            Value::Object(root) => root,
+            _ => {
+                return Err(SceneFileError::Entry {
+                    path: path.to_path_buf(),
+                    entry: "root".to_string(),
+                    message: "expected an object".to_string(),
+                })
+            }
+        };
+        let entry_error = |entry: &str, err: serde_json::Error| SceneFileError::Entry {
+            path: path.to_path_buf(),
+            entry: entry.to_string(),
+            message: err.to_string(),
+        };
+        let camera = match root.remove("camera") {
+            Some(camera) => {
+                serde_json::from_value(camera).map_err(|err| entry_error("camera", err))?
+            }
+            None => {
+                return Err(SceneFileError::Entry {
+                    path: path.to_path_buf(),
+                    entry: "camera".to_string(),
+                    message: "missing required section".to_string(),
+                })
+            }
+        };
+        let sky = match root.remove("sky") {
+            Some(sky) => serde_json::from_value(sky).map_err(|err| entry_error("sky", err))?,
+            None => None,
+        };
+        let textures = parse_entries(path, &mut root, "textures", false)?;
+        let materials = parse_entries(path, &mut root, "materials", false)?;
+        let geometries = parse_entries(path, &mut root, "geometries", false)?;
+        let objects = parse_entries(path, &mut root, "objects", true)?;
+        if let Some(key) = root.keys().next() {
+            return Err(SceneFileError::Entry {
+                path: path.to_path_buf(),
+                entry: key.clone(),
+                message: "unknown section".to_string(),
+            });
+        }
+        Ok(SceneFile {
+            camera,
+            sky,
+            textures,
+            materials,
+            geometries,
+            objects,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SceneFileError> {
+        let io_error = |source| SceneFileError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+        let json = serde_json::to_string_pretty(self).map_err(|source| SceneFileError::Json {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        fs::write(path, json).map_err(io_error)
+    }
+}
+
+struct Loader<'a, 'p> {
+    path: &'p Path,
+    dir: &'p Path,
+    builder: BVHBuilder,
+    shutter: (f32, f32),
+    storage: &'a Storage<'a>,
+    textures: HashMap<String, &'a Texture<'a>>,
+    materials: HashMap<String, &'a Material<'a>>,
+    geometries: HashMap<String, Hitable<'a>>,
+}
+
+impl<'a, 'p> Loader<'a, 'p> {
+    fn error<T>(&self, entry: &str, message: String) -> Result<T, SceneFileError> {
+        Err(SceneFileError::Entry {
+            path: self.path.to_path_buf(),
+            entry: entry.to_string(),
+            message,
+        })
+    }
+
+    fn texture(&self, entry: &str, name: &str) -> Result<&'a Texture<'a>, SceneFileError> {
+        match self.textures.get(name) {
+            Some(texture) => Ok(*texture),
+            None => self.error(entry, format!("unknown texture '{}'", name)),
+        }
+    }
+
+    fn material(&self, entry: &str, name: &str) -> Result<&'a Material<'a>, SceneFileError> {
+        match self.materials.get(name) {
+            Some(material) => Ok(*material),
+            None => self.error(entry, format!("unknown material '{}'", name)),
+        }
+    }
+
+    fn geometry(&self, entry: &str, name: &str) -> Result<Hitable<'a>, SceneFileError> {
+        match self.geometries.get(name) {
+            Some(geometry) => Ok(*geometry),
+            None => self.error(entry, format!("unknown geometry '{}'", name)),
+        }
+    }
+
+    fn add_texture(&mut self, entry: &str, desc: &TextureDesc) -> Result<(), SceneFileError> {
+        let texture = match &desc.kind {
+            TextureKind::Constant { color } => texture::constant(*color),
+            TextureKind::Checker { odd, even } => {
+                texture::checker(self.texture(entry, odd)?, self.texture(entry, even)?)
+            }
+            TextureKind::Noise { scale } => texture::noise(&self.storage.perlin_noise, *scale),
+            TextureKind::Image { path } => {
+                let path = self.dir.join(path);
+                let image = match RgbImage::open(&path) {
+                    Ok(image) => image,
+                    Err(err) => {
+                        return self.error(
+                            entry,
+                            format!("failed to load image '{}': {}", path.display(), err),
+                        )
+                    }
+                };
+                texture::rgb_image(self.storage.alloc_image(image))
+            }
+        };
+        if self.textures.contains_key(&desc.name) {
+            return self.error(entry, format!("duplicate texture name '{}'", desc.name));
+        }
+        self.textures
+            .insert(desc.name.clone(), self.storage.alloc_texture(texture));
+        Ok(())
+    }
+
+    fn add_material(&mut self, entry: &str, desc: &MaterialDesc) -> Result<(), SceneFileError> {
+        let material = match &desc.kind {
+            MaterialKind::Lambertian { albedo } => {
+                material::lambertian(self.texture(entry, albedo)?)
+            }
+            MaterialKind::Metal { albedo, fuzz } => material::metal(*albedo, *fuzz),
+            MaterialKind::Dielectric { ref_idx } => material::dielectric(*ref_idx),
+            MaterialKind::DiffuseLight { emit } => {
+                material::diffuse_light(self.texture(entry, emit)?)
+            }
+            MaterialKind::Isotropic { albedo } => material::isotropic(self.texture(entry, albedo)?),
+        };
+        if self.materials.contains_key(&desc.name) {
+            return self.error(entry, format!("duplicate material name '{}'", desc.name));
+        }
+        self.materials
+            .insert(desc.name.clone(), self.storage.alloc_material(material));
+        Ok(())
+    }
+
+    fn add_geometry(
+        &mut self,
+        rng: &mut Xoshiro256Plus,
+        entry: &str,
+        desc: &GeometryDesc,
+    ) -> Result<(), SceneFileError> {
+        let mut hitables = Vec::new();
+        self.object(rng, entry, &desc.object, &mut hitables)?;
+        if self.geometries.contains_key(&desc.name) {
+            return self.error(entry, format!("duplicate geometry name '{}'", desc.name));
+        }
+        let geometry = self.single(rng, hitables);
+        self.geometries.insert(desc.name.clone(), geometry);
+        Ok(())
+    }
+
+    /// Collapses multiple hitables into one, objects like meshes and OBJ files can produce many.
+    fn single(&self, rng: &mut Xoshiro256Plus, mut hitables: Vec<Hitable<'a>>) -> Hitable<'a> {
+        if hitables.len() == 1 {
+            hitables[0]
+        } else {
+            Hitable::BVHNode(
+                BVHNode::build(
+                    self.builder,
+                    rng,
+                    &mut hitables,
+                    self.shutter.0,
+                    self.shutter.1,
+                    self.storage,
+                )
+                .unwrap(),
+            )
+        }
+    }
+
+    fn object(
+        &self,
+        rng: &mut Xoshiro256Plus,
+        entry: &str,
+        desc: &ObjectDesc,
+        out: &mut Vec<Hitable<'a>>,
+    ) -> Result<(), SceneFileError> {
+        let storage = self.storage;
+        let material_entry = format!("{}.material", entry);
+        match desc {
+            ObjectDesc::Sphere {
+                centre,
+                radius,
+                material,
+            } => out.push(Hitable::Sphere(
+                storage.alloc_sphere(Sphere::new(*centre, *radius)),
+                self.material(&material_entry, material)?,
+            )),
+            ObjectDesc::MovingSphere {
+                centre0,
+                centre1,
+                time0,
+                time1,
+                radius,
+                material,
+            } => {
+                if time1 <= time0 {
+                    return self.error(entry, "time1 must be greater than time0".to_string());
+                }
+                out.push(Hitable::MovingSphere(
+                    storage.alloc_moving_sphere(MovingSphere::new(
+                        *centre0, *centre1, *time0, *time1, *radius,
+                    )),
+                    self.material(&material_entry, material)?,
+                ))
+            }
+            ObjectDesc::Rect {
+                plane,
+                a0,
+                a1,
+                b0,
+                b1,
+                k,
+                flip_normals,
+                material,
+            } => {
+                if a0 > a1 || b0 > b1 {
+                    return self.error(
+                        entry,
+                        "rect extents must satisfy a0 <= a1 and b0 <= b1".to_string(),
+                    );
+                }
+                let rect = match plane {
+                    RectPlane::Xy => Rect::new_xy(*a0, *a1, *b0, *b1, *k, *flip_normals),
+                    RectPlane::Xz => Rect::new_xz(*a0, *a1, *b0, *b1, *k, *flip_normals),
+                    RectPlane::Yz => Rect::new_yz(*a0, *a1, *b0, *b1, *k, *flip_normals),
+                };
+                out.push(Hitable::Rect(
+                    storage.alloc_rect(rect),
+                    self.material(&material_entry, material)?,
+                ))
+            }
+            ObjectDesc::Cuboid { min, max, material } => {
+                if min.cmpgt(*max).any() {
+                    return self.error(entry, "cuboid min must not exceed max".to_string());
+                }
+                out.push(Hitable::Cuboid(
+                    storage.alloc_cuboid(Cuboid::new(*min, *max)),
+                    self.material(&material_entry, material)?,
+                ))
+            }
+            ObjectDesc::Triangle { vertices, material } => out.push(Hitable::Triangle(
+                storage.alloc_triangle(Triangle::new(vertices[0], vertices[1], vertices[2])),
+                self.material(&material_entry, material)?,
+            )),
+            ObjectDesc::Mesh {
+                positions,
+                normals,
+                uvs,
+                indices,
+                material,
+                face_materials,
+            } => {
+                if !normals.is_empty() && normals.len() != positions.len() {
+                    return self.error(entry, "normals and positions differ in length".to_string());
+                }
+                if !uvs.is_empty() && uvs.len() != positions.len() {
+                    return self.error(entry, "uvs and positions differ in length".to_string());
+                }
+                if let Some(face) = indices
+                    .iter()
+                    .position(|face| face.iter().any(|&index| index as usize >= positions.len()))
+                {
+                    return self.error(
+                        &format!("{}.indices[{}]", entry, face),
+                        format!("index out of range, {} positions defined", positions.len()),
+                    );
+                }
+                let materials = match (material, face_materials.is_empty()) {
+                    (Some(material), true) => {
+                        vec![self.material(&material_entry, material)?; indices.len()]
+                    }
+                    (None, false) if face_materials.len() == indices.len() => face_materials
+                        .iter()
+                        .enumerate()
+                        .map(|(face, name)| {
+                            self.material(&format!("{}.face_materials[{}]", entry, face), name)
+                        })
+                        .collect::<Result<_, _>>()?,
+                    (None, false) => {
+                        return self.error(
+                            entry,
+                            format!(
+                                "{} face_materials for {} faces",
+                                face_materials.len(),
+                                indices.len()
+                            ),
+                        )
+                    }
+                    _ => {
+                        return self.error(
+                            entry,
+                            "exactly one of material or face_materials is required".to_string(),
+                        )
+                    }
+                };
+                let mesh = storage.alloc_triangle_mesh(TriangleMesh::new(
+                    positions.clone(),
+                    normals.clone(),
+                    uvs.clone(),
+                    indices.clone(),
+                    materials,
+                ));
+                out.extend(mesh.faces());
+            }
+            ObjectDesc::Obj { path } => {
+                let (hitables, _) = obj::load(&self.dir.join(path), self.builder, rng, storage)
+                    .map_err(|source| SceneFileError::Obj {
+                        path: self.path.to_path_buf(),
+                        entry: entry.to_string(),
+                        source: Box::new(source),
+                    })?;
+                out.extend(hitables);
+            }
+            ObjectDesc::Instance { transform, object } => {
+                let mut children = Vec::new();
+                self.object(rng, &format!("{}.object", entry), object, &mut children)?;
+                let scale = transform.scale.unwrap_or(Vec3::ONE);
+                if scale.cmpeq(Vec3::ZERO).any() {
+                    return self.error(entry, "transform scale must be non-zero".to_string());
+                }
+                let rotation = match &transform.rotation {
+                    Some(rotation) => {
+                        if rotation.axis.length_squared() == 0.0 {
+                            return self.error(entry, "rotation axis must be non-zero".to_string());
+                        }
+                        Quat::from_axis_angle(
+                            rotation.axis.normalize(),
+                            rotation.degrees.to_radians(),
+                        )
+                    }
+                    None => Quat::IDENTITY,
+                };
+                out.push(Hitable::Instance(storage.alloc_instance(Instance::new(
+                    self.single(rng, children),
+                    Affine3A::from_scale_rotation_translation(
+                        scale,
+                        rotation,
+                        transform.translation,
+                    ),
+                ))));
+            }
+            ObjectDesc::ConstantMedium {
+                boundary,
+                density,
+                albedo,
+            } => {
+                if *density <= 0.0 {
+                    return self.error(entry, "density must be positive".to_string());
+                }
+                let mut children = Vec::new();
+                self.object(rng, &format!("{}.boundary", entry), boundary, &mut children)?;
+                let albedo = self.texture(&format!("{}.albedo", entry), albedo)?;
+                out.push(Hitable::ConstantMedium(storage.alloc_constant_medium(
+                    ConstantMedium::new(self.single(rng, children), *density, albedo),
+                )));
+            }
+            ObjectDesc::Bvh { objects } | ObjectDesc::List { objects } => {
+                let mut children = Vec::new();
+                for (index, object) in objects.iter().enumerate() {
+                    self.object(
+                        rng,
+                        &format!("{}.objects[{}]", entry, index),
+                        object,
+                        &mut children,
+                    )?;
+                }
+                if children.is_empty() {
+                    return self.error(entry, "no objects".to_string());
+                }
+                if let ObjectDesc::Bvh { .. } = desc {
+                    out.push(self.single(rng, children));
+                } else {
+                    out.push(Hitable::List(storage.alloc_hitables(children)));
+                }
+            }
+            ObjectDesc::Geometry { name } => {
+                out.push(self.geometry(&format!("{}.name", entry), name)?)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Loads a JSON scene file into the same shape `presets::from_name` returns.
+pub fn load<'a>(
+    path: &Path,
+    params: &Params,
+    rng: &mut Xoshiro256Plus,
+    storage: &'a Storage<'a>,
+) -> Result<SceneDescription<'a>, SceneFileError> {
+    println!(
+        "loading '{}' at {}x{} with {} samples per pixel",
+        path.display(),
+        params.width,
+        params.height,
+        params.samples
+    );
+
+    let scene_file = SceneFile::open(path)?;
+    let mut loader = Loader {
+        path,
+        dir: path.parent().unwrap_or_else(|| Path::new("")),
+        builder: params.bvh_builder,
+        shutter: (scene_file.camera.time0, scene_file.camera.time1),
+        storage,
+        textures: HashMap::new(),
+        materials: HashMap::new(),
+        geometries: HashMap::new(),
+    };
+    for (index, desc) in scene_file.textures.iter().enumerate() {
+        let entry = format!("textures[{}] ('{}')", index, desc.name);
+        loader.add_texture(&entry, desc)?;
+    }
+    for (index, desc) in scene_file.materials.iter().enumerate() {
+        let entry = format!("materials[{}] ('{}')", index, desc.name);
+        loader.add_material(&entry, desc)?;
+    }
+    for (index, desc) in scene_file.geometries.iter().enumerate() {
+        let entry = format!("geometries[{}] ('{}')", index, desc.name);
+        loader.add_geometry(rng, &entry, desc)?;
+    }
+    let mut hitables = Vec::with_capacity(scene_file.objects.len());
+    for (index, desc) in scene_file.objects.iter().enumerate() {
+        loader.object(rng, &format!("objects[{}]", index), desc, &mut hitables)?;
+    }
+
+    let camera = Camera::from_desc(
+        &scene_file.camera,
+        params.width as f32 / params.height as f32,
+    );
+    Ok((hitables, camera, scene_file.sky))
+}
+
+/// Builds a `SceneFile` from loaded hitables so presets can be exported.
+#[derive(Default)]
+struct Exporter {
+    /// The directory the scene file is saved in, which its paths are relative to.
+    dir: PathBuf,
+    textures: Vec<TextureDesc>,
+    materials: Vec<MaterialDesc>,
+    geometries: Vec<GeometryDesc>,
+    texture_names: HashMap<usize, String>,
+    material_names: HashMap<usize, String>,
+    geometry_names: HashMap<(usize, u32), String>,
+}
+
+impl Exporter {
+    fn texture(&mut self, texture: &Texture) -> String {
+        let key = texture as *const Texture as usize;
+        if let Some(name) = self.texture_names.get(&key) {
+            return name.clone();
+        }
+        let kind = match texture {
+            Texture::Constant { color } => TextureKind::Constant { color: *color },
+            Texture::Checker { odd, even } => TextureKind::Checker {
+                odd: self.texture(odd),
+                even: self.texture(even),
+            },
+            Texture::Noise { scale, .. } => TextureKind::Noise { scale: *scale },
+            Texture::Image { image } => TextureKind::Image {
+                path: relative_path(image.path(), &self.dir),
+            },
+        };
+        let name = format!("texture{}", self.textures.len());
+        self.textures.push(TextureDesc {
+            name: name.clone(),
+            kind,
+        });
+        self.texture_names.insert(key, name.clone());
+        name
+    }
+
+    fn material(&mut self, material: &Material) -> String {
+        let key = material as *const Material as usize;
+        if let Some(name) = self.material_names.get(&key) {
+            return name.clone();
+        }
+        let kind = match material {
+            Material::Lambertian { albedo } => MaterialKind::Lambertian {
+                albedo: self.texture(albedo),
+            },
+            Material::Metal { albedo, fuzz } => MaterialKind::Metal {
+                albedo: *albedo,
+                fuzz: *fuzz,
+            },
+            Material::Dielectric { ref_idx } => MaterialKind::Dielectric { ref_idx: *ref_idx },
+            Material::DiffuseLight { emit } => MaterialKind::DiffuseLight {
+                emit: self.texture(emit),
+            },
+            Material::Isotropic { albedo } => MaterialKind::Isotropic {
+                albedo: self.texture(albedo),
+            },
+        };
+        let name = format!("material{}", self.materials.len());
+        self.materials.push(MaterialDesc {
+            name: name.clone(),
+            kind,
+        });
+        self.material_names.insert(key, name.clone());
+        name
+    }
+
+    /// The object of an instance. Meshes and BVHs may be instanced many times so they are
+    /// emitted once as a geometry which each instance refers to.
+    fn instanced(&mut self, hitable: &Hitable) -> ObjectDesc {
+        match hitable {
+            Hitable::BVHNode(_)
+            | Hitable::LinearBVH(_)
+            | Hitable::WideBVH(_)
+            | Hitable::TriangleMesh(_)
+            | Hitable::List(_) => {
+                let key = hitable.address();
+                let name = match self.geometry_names.get(&key) {
+                    Some(name) => name.clone(),
+                    None => {
+                        let object = self.object(hitable);
+                        let name = format!("geometry{}", self.geometries.len());
+                        self.geometries.push(GeometryDesc {
+                            name: name.clone(),
+                            object,
+                        });
+                        self.geometry_names.insert(key, name.clone());
+                        name
+                    }
+                };
+                ObjectDesc::Geometry { name }
+            }
+            _ => self.object(hitable),
+        }
+    }
+
+    fn mesh(&mut self, mesh: &TriangleMesh) -> ObjectDesc {
+        let materials = mesh.materials();
+        let shared = materials
+            .windows(2)
+            .all(|pair| std::ptr::eq(pair[0], pair[1]));
+        let (material, face_materials) = if shared && !materials.is_empty() {
+            (Some(self.material(materials[0])), Vec::new())
+        } else {
+            (
+                None,
+                materials
+                    .iter()
+                    .map(|material| self.material(material))
+                    .collect(),
+            )
+        };
+        ObjectDesc::Mesh {
+            positions: mesh.positions().to_vec(),
+            normals: mesh.normals().to_vec(),
+            uvs: mesh.uvs().to_vec(),
+            indices: mesh.indices().to_vec(),
+            material,
+            face_materials,
+        }
+    }
+
+    /// Flattens a BVH back into its leaves, mesh faces are gathered back into their mesh and
+    /// multi-primitive leaf lists are expanded.
+    fn bvh_leaves<'a>(
+        hitable: Hitable<'a>,
+        seen_meshes: &mut HashSet<usize>,
+        leaves: &mut Vec<Hitable<'a>>,
+    ) {
+        match hitable {
+            Hitable::BVHNode(node) => {
+                let (lhs, rhs) = node.children();
+                Exporter::bvh_leaves(lhs, seen_meshes, leaves);
+                // a BVH over a single hitable stores it as both children
+                if !lhs.ptr_eq(&rhs) {
+                    Exporter::bvh_leaves(rhs, seen_meshes, leaves);
+                }
+            }
+            Hitable::List(list) => {
+                for &hitable in list.hitables() {
+                    Exporter::bvh_leaves(hitable, seen_meshes, leaves);
+                }
+            }
+            Hitable::Spheres(spheres) => leaves.extend_from_slice(spheres.hitables()),
+            Hitable::LinearBVH(bvh) => {
+                for &hitable in bvh.primitives() {
+                    Exporter::bvh_leaves(hitable, seen_meshes, leaves);
+                }
+            }
+            Hitable::WideBVH(bvh) => {
+                for &hitable in bvh.primitives() {
+                    Exporter::bvh_leaves(hitable, seen_meshes, leaves);
+                }
+            }
+            Hitable::Tlas(tlas) => {
+                for hitable in tlas.hitables() {
+                    Exporter::bvh_leaves(hitable, seen_meshes, leaves);
+                }
+            }
+            Hitable::MeshFace(mesh, _) => {
+                if seen_meshes.insert(mesh as *const TriangleMesh as usize) {
+                    leaves.push(Hitable::TriangleMesh(mesh));
+                }
+            }
+            _ => leaves.push(hitable),
+        }
+    }
+
+    fn object(&mut self, hitable: &Hitable) -> ObjectDesc {
+        match hitable {
+            Hitable::Sphere(sphere, material) => ObjectDesc::Sphere {
+                centre: sphere.centre(),
+                radius: sphere.radius(),
+                material: self.material(material),
+            },
+            Hitable::MovingSphere(sphere, material) => {
+                let (time0, time1) = sphere.time_range();
+                ObjectDesc::MovingSphere {
+                    centre0: sphere.centre(time0),
+                    centre1: sphere.centre(time1),
+                    time0,
+                    time1,
+                    radius: sphere.radius(),
+                    material: self.material(material),
+                }
+            }
+            Hitable::Rect(rect, material) => {
+                let (plane, a0, a1, b0, b1, k, flip_normals) = match **rect {
+                    Rect::XY {
+                        x0,
+                        x1,
+                        y0,
+                        y1,
+                        k,
+                        flip_normals,
+                    } => (RectPlane::Xy, x0, x1, y0, y1, k, flip_normals),
+                    Rect::XZ {
+                        x0,
+                        x1,
+                        z0,
+                        z1,
+                        k,
+                        flip_normals,
+                    } => (RectPlane::Xz, x0, x1, z0, z1, k, flip_normals),
+                    Rect::YZ {
+                        y0,
+                        y1,
+                        z0,
+                        z1,
+                        k,
+                        flip_normals,
+                    } => (RectPlane::Yz, y0, y1, z0, z1, k, flip_normals),
+                };
+                ObjectDesc::Rect {
+                    plane,
+                    a0,
+                    a1,
+                    b0,
+                    b1,
+                    k,
+                    flip_normals,
+                    material: self.material(material),
+                }
+            }
+            Hitable::Cuboid(cuboid, material) => {
+                let aabb = cuboid.bounding_box();
+                ObjectDesc::Cuboid {
+                    min: aabb.min,
+                    max: aabb.max,
+                    material: self.material(material),
+                }
+            }
+            Hitable::Triangle(triangle, material) => ObjectDesc::Triangle {
+                vertices: triangle.vertices(),
+                material: self.material(material),
+            },
+            Hitable::TriangleMesh(mesh) | Hitable::MeshFace(mesh, _) => self.mesh(mesh),
+            Hitable::Instance(instance) => {
+                let (scale, rotation, translation) =
+                    instance.transform().to_scale_rotation_translation();
+                let (axis, angle) = rotation.to_axis_angle();
+                let degrees = (angle.to_degrees() * 1e4).round() / 1e4;
+                ObjectDesc::Instance {
+                    transform: TransformDesc {
+                        translation,
+                        rotation: if degrees != 0.0 {
+                            Some(RotationDesc { axis, degrees })
+                        } else {
+                            None
+                        },
+                        scale: if scale.abs_diff_eq(Vec3::ONE, 1e-6) {
+                            None
+                        } else {
+                            Some(scale)
+                        },
+                    },
+                    object: Box::new(self.instanced(&instance.hitable())),
+                }
+            }
+            Hitable::ConstantMedium(constant_medium) => {
+                let albedo = match constant_medium.phase_function() {
+                    Material::Isotropic { albedo } => self.texture(albedo),
+                    _ => unreachable!("constant medium phase function is always isotropic"),
+                };
+                ObjectDesc::ConstantMedium {
+                    boundary: Box::new(self.object(&constant_medium.hitable())),
+                    density: constant_medium.density(),
+                    albedo,
+                }
+            }
+            Hitable::BVHNode(_)
+            | Hitable::LinearBVH(_)
+            | Hitable::WideBVH(_)
+            | Hitable::Tlas(_) => {
+                let mut leaves = Vec::new();
+                Exporter::bvh_leaves(*hitable, &mut HashSet::new(), &mut leaves);
+                let mut objects: Vec<ObjectDesc> =
+                    leaves.iter().map(|leaf| self.object(leaf)).collect();
+                if objects.len() == 1 {
+                    objects.pop().unwrap()
+                } else {
+                    ObjectDesc::Bvh { objects }
+                }
+            }
+            Hitable::List(list) => {
+                // mesh faces are emitted once as a whole mesh
+                let mut seen_meshes = HashSet::new();
+                ObjectDesc::List {
+                    objects: list
+                        .hitables()
+                        .iter()
+                        .filter(|hitable| match hitable {
+                            Hitable::MeshFace(mesh, _) => {
+                                seen_meshes.insert(*mesh as *const TriangleMesh as usize)
+                            }
+                            _ => true,
+                        })
+                        .map(|hitable| self.object(hitable))
+                        .collect(),
+                }
+            }
+            Hitable::Spheres(spheres) => ObjectDesc::List {
+                objects: spheres
+                    .hitables()
+                    .iter()
+                    .map(|hitable| self.object(hitable))
+                    .collect(),
+            },
+        }
+    }
+}
+
+/// `path` relative to the directory `dir`, or absolute if it can't be.
+fn relative_path(path: &Path, dir: &Path) -> PathBuf {
+    let cwd = env::current_dir().unwrap_or_default();
+    let path = cwd.join(path);
+    let dir = cwd.join(dir);
+    let mut path_components = path.components().peekable();
+    let mut dir_components = dir.components().peekable();
+    while let (Some(a), Some(b)) = (path_components.peek(), dir_components.peek()) {
+        if a != b {
+            break;
+        }
+        path_components.next();
+        dir_components.next();
+    }
+    let mut relative = PathBuf::new();
+    for component in dir_components {
+        match component {
+            Component::CurDir => (),
+            // the path back out of the rest of `dir` isn't known without resolving it
+            Component::Normal(_) => relative.push(".."),
+            _ => return path,
+        }
+    }
+    relative.extend(path_components);
+    relative
+}
+
+/// Converts a loaded scene back into a scene file description that will be saved to `path`,
+/// the paths of the images it uses are written relative to it.
+pub fn export(scene: &SceneDescription, path: &Path) -> SceneFile {
+    let (hitables, camera, sky) = scene;
+    let mut exporter = Exporter {
+        dir: path.parent().unwrap_or_else(|| Path::new("")).to_path_buf(),
+        ..Exporter::default()
+    };
+    let mut seen_meshes = HashSet::new();
+    let mut objects = Vec::with_capacity(hitables.len());
+    for hitable in hitables {
+        // top level mesh faces are emitted once as a whole mesh
+        if let Hitable::MeshFace(mesh, _) = hitable {
+            if !seen_meshes.insert(*mesh as *const TriangleMesh as usize) {
+                continue;
+            }
+        }
+        objects.push(exporter.object(hitable));
+    }
+    SceneFile {
+        camera: *camera.desc(),
+        sky: *sky,
+        textures: exporter.textures,
+        materials: exporter.materials,
+        geometries: exporter.geometries,
+        objects,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, load, relative_path, TransformDesc};
+    use crate::{bench::PARAMS, presets, source::SceneDescription, storage::Storage};
+    use glam::{Affine3A, Quat, Vec3};
+    use serde_json::Value;
+    use std::{
+        env, fs,
+        path::{Path, PathBuf},
+    };
+
+    /// An empty directory for a test's files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("pathtrace-rs-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn transform(value: &Value) -> Affine3A {
+        let desc: TransformDesc = serde_json::from_value(value.clone()).unwrap();
+        let rotation = desc.rotation.map_or(Quat::IDENTITY, |rotation| {
+            Quat::from_axis_angle(rotation.axis.normalize(), rotation.degrees.to_radians())
+        });
+        Affine3A::from_scale_rotation_translation(
+            desc.scale.unwrap_or(Vec3::ONE),
+            rotation,
+            desc.translation,
+        )
+    }
+
+    /// Checks `a` and `b` are the same JSON apart from rounding. Transforms are compared as
+    /// matrices as the axis of a small rotation can change a lot when it's decomposed again.
+    fn assert_similar(a: &Value, b: &Value, at: &str) {
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => {
+                let (a, b) = (a.as_f64().unwrap(), b.as_f64().unwrap());
+                assert!(
+                    (a - b).abs() <= 1e-5 * a.abs().max(1.0),
+                    "{}: {} {}",
+                    at,
+                    a,
+                    b
+                );
+            }
+            (Value::Array(a), Value::Array(b)) => {
+                assert_eq!(a.len(), b.len(), "{}", at);
+                for (index, (a, b)) in a.iter().zip(b).enumerate() {
+                    assert_similar(a, b, &format!("{}[{}]", at, index));
+                }
+            }
+            (Value::Object(a), Value::Object(b)) => {
+                assert_eq!(a.len(), b.len(), "{}", at);
+                for (key, a) in a {
+                    let b = b
+                        .get(key)
+                        .unwrap_or_else(|| panic!("{}.{} missing", at, key));
+                    if key == "transform" {
+                        assert!(
+                            transform(a).abs_diff_eq(transform(b), 1e-4),
+                            "{}.transform: {} {}",
+                            at,
+                            a,
+                            b
+                        );
+                    } else {
+                        assert_similar(a, b, &format!("{}.{}", at, key));
+                    }
+                }
+            }
+            _ => assert_eq!(a, b, "{}", at),
+        }
+    }
+
+    /// Exports `scene` to `path` and checks loading it back and exporting that again gives the
+    /// same scene file.
+    fn round_trip(scene: &SceneDescription, path: &Path) {
+        let scene_file = export(scene, path);
+        scene_file.save(path).unwrap();
+        let mut rng = PARAMS.new_rng();
+        let storage = Storage::new(&mut rng);
+        let loaded =
+            load(path, &PARAMS, &mut rng, &storage).unwrap_or_else(|err| panic!("{}", err));
+        assert_similar(
+            &serde_json::to_value(&scene_file).unwrap(),
+            &serde_json::to_value(export(&loaded, path)).unwrap(),
+            &path.display().to_string(),
+        );
+    }
+
+    fn load_error(dir: &Path, json: &str) -> String {
+        let path = dir.join("scene.json");
+        fs::write(&path, json).unwrap();
+        let mut rng = PARAMS.new_rng();
+        let storage = Storage::new(&mut rng);
+        match load(&path, &PARAMS, &mut rng, &storage) {
+            Ok(_) => panic!("loaded {}", json),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    const CAMERA: &str = r#""camera": {
+        "lookfrom": [0, 0, 5], "lookat": [0, 0, 0], "vup": [0, 1, 0], "vfov": 40,
+        "aperture": 0, "focus_dist": 5, "time0": 0, "time1": 0
+    }"#;
+
+    #[test]
+    fn presets_round_trip() {
+        let dir = test_dir("presets-round-trip");
+        // earth is left out as its image isn't in the repository
+        for name in &[
+            "random",
+            "random_spheres",
+            "small",
+            "smallpt",
+            "cornell",
+            "cornell_smoke",
+            "two_perlin_spheres",
+            "simple_light",
+            "icosphere",
+            "instances",
+            "orbits",
+            "final",
+        ] {
+            let mut rng = PARAMS.new_rng();
+            let storage = Storage::new(&mut rng);
+            let scene = presets::from_name(name, &PARAMS, &mut rng, &storage).unwrap();
+            round_trip(&scene, &dir.join(format!("{}.json", name)));
+        }
+    }
+
+    #[test]
+    fn image_texture_round_trip() {
+        // image paths are relative to the scene file, wherever it's exported to. The scene is
+        // loaded by a relative path too, as it would be from the command line
+        let dir = relative_path(&test_dir("image-round-trip"), Path::new(""));
+        fs::create_dir(dir.join("scene")).unwrap();
+        fs::create_dir(dir.join("export")).unwrap();
+        image::RgbImage::new(2, 2)
+            .save(dir.join("scene").join("texture.png"))
+            .unwrap();
+        let path = dir.join("scene").join("scene.json");
+        fs::write(
+            &path,
+            format!(
+                r#"{{
+                    {},
+                    "textures": [{{ "name": "image", "type": "image", "path": "texture.png" }}],
+                    "materials": [{{ "name": "lambertian", "type": "lambertian", "albedo": "image" }}],
+                    "objects": [
+                        {{ "type": "sphere", "centre": [0, 0, 0], "radius": 1, "material": "lambertian" }}
+                    ]
+                }}"#,
+                CAMERA
+            ),
+        )
+        .unwrap();
+        let mut rng = PARAMS.new_rng();
+        let storage = Storage::new(&mut rng);
+        let scene = load(&path, &PARAMS, &mut rng, &storage).unwrap();
+        round_trip(&scene, &dir.join("scene").join("export.json"));
+        round_trip(&scene, &dir.join("export").join("export.json"));
+        round_trip(&scene, &dir.join("export.json"));
+    }
+
+    #[test]
+    fn entry_errors() {
+        let dir = test_dir("entry-errors");
+        let path = dir.join("scene.json");
+        let error = |json: &str| load_error(&dir, &format!("{{ {}, {} }}", CAMERA, json));
+        assert_eq!(
+            error(
+                r#""materials": [
+                    { "name": "red", "type": "metal", "albedo": [1, 0, 0], "fuzz": 0 },
+                    { "name": "red", "type": "dielectric", "ref_idx": 1.5 }
+                ],
+                "objects": []"#
+            ),
+            format!(
+                "{}: materials[1] ('red'): duplicate material name 'red'",
+                path.display()
+            )
+        );
+        assert_eq!(
+            error(
+                r#""objects": [
+                    { "type": "sphere", "centre": [0, 0, 0], "radius": 1, "material": "blue" }
+                ]"#
+            ),
+            format!(
+                "{}: objects[0].material: unknown material 'blue'",
+                path.display()
+            )
+        );
+        assert_eq!(
+            error(
+                r#""objects": [
+                    { "type": "sphere", "centre": [0, 0, 0], "radius": "big", "material": "blue" }
+                ]"#
+            ),
+            format!(
+                "{}: objects[0]: invalid type: string \"big\", expected f32",
+                path.display()
+            )
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{
+    camera::Camera, collision::Hitable, obj, params::Params, presets, scene_file, storage::Storage,
+};
+use glam::Vec3;
+use rand_xoshiro::Xoshiro256Plus;
+use serde_derive::{Deserialize, Serialize};
+use std::{error::Error, path::PathBuf};
+
+/// The hitables, camera and optional constant sky colour describing a scene.
+pub type SceneDescription<'a> = (Vec<Hitable<'a>>, Camera, Option<Vec3>);
+
+/// Where the scene to render comes from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SceneSource {
+    Preset(String),
+    Obj(PathBuf),
+    File(PathBuf),
+}
+
+impl SceneSource {
+    pub fn load<'a>(
+        &self,
+        params: &Params,
+        rng: &mut Xoshiro256Plus,
+        storage: &'a Storage<'a>,
+    ) -> Result<SceneDescription<'a>, Box<dyn Error>> {
+        match self {
+            SceneSource::Preset(name) => presets::from_name(name, params, rng, storage)
+                .ok_or_else(|| format!("unrecognised preset '{}'", name).into()),
+            SceneSource::Obj(path) => {
+                println!(
+                    "loading '{}' at {}x{} with {} samples per pixel",
+                    path.display(),
+                    params.width,
+                    params.height,
+                    params.samples
+                );
+                let (hitables, bounds) = obj::load(path, params.bvh_builder, rng, storage)?;
+
+                // frame the model's bounding sphere with the camera looking down -z
+                let vfov: f32 = 40.0;
+                let centre = (bounds.min + bounds.max) * 0.5;
+                let radius = (bounds.max - bounds.min).length() * 0.5;
+                let dist_to_focus = 1.1 * radius / (vfov.to_radians() * 0.5).sin();
+                let lookfrom = centre + Vec3::new(0.0, 0.0, dist_to_focus);
+                let camera = Camera::new(
+                    lookfrom,
+                    centre,
+                    Vec3::new(0.0, 1.0, 0.0),
+                    vfov,
+                    params.width as f32 / params.height as f32,
+                    0.0,
+                    dist_to_focus,
+                    0.0,
+                    0.0,
+                );
+                Ok((hitables, camera, None))
+            }
+            SceneSource::File(path) => Ok(scene_file::load(path, params, rng, storage)?),
+        }
+    }
+
+    /// How the scene's instances move over time, if it is an animated preset.
+    pub fn animation(&self) -> Option<presets::Animation> {
+        match self {
+            SceneSource::Preset(name) => presets::animation(name),
+            _ => None,
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{
     collision::{
-        BVHNode, ConstantMedium, Cuboid, Hitable, HitableList, Instance, MovingSphere, Rect, Sphere,
+        BVHNode, ConstantMedium, Cuboid, Hitable, HitableList, Instance, LinearBVH, MovingSphere,
+        Rect, Sphere, SpheresSoA, Triangle, TriangleMesh, WideBVH,
     },
     material::Material,
     perlin::Perlin,
@@ -15,12 +16,17 @@ pub struct Storage<'a> {
     pub material_arena: Arena<Material<'a>>,
     pub image_arena: Arena<RgbImage>,
     pub sphere_arena: Arena<Sphere>,
+    pub spheres_soa_arena: Arena<SpheresSoA<'a>>,
     pub moving_sphere_arena: Arena<MovingSphere>,
     pub rect_arena: Arena<Rect>,
     pub bvhnode_arena: Arena<BVHNode<'a>>,
+    pub linear_bvh_arena: Arena<LinearBVH<'a>>,
+    pub wide_bvh_arena: Arena<WideBVH<'a>>,
     pub hitables_arena: Arena<HitableList<'a>>,
     pub constant_medium_arena: Arena<ConstantMedium<'a>>,
     pub cuboid_arena: Arena<Cuboid>,
+    pub triangle_arena: Arena<Triangle>,
+    pub triangle_mesh_arena: Arena<TriangleMesh<'a>>,
     pub perlin_noise: Perlin,
 }
 
@@ -33,11 +39,16 @@ impl<'a> Storage<'a> {
             image_arena: Arena::new(),
             moving_sphere_arena: Arena::new(),
             sphere_arena: Arena::new(),
+            spheres_soa_arena: Arena::new(),
             rect_arena: Arena::new(),
             bvhnode_arena: Arena::new(),
+            linear_bvh_arena: Arena::new(),
+            wide_bvh_arena: Arena::new(),
             hitables_arena: Arena::new(),
             cuboid_arena: Arena::new(),
             constant_medium_arena: Arena::new(),
+            triangle_arena: Arena::new(),
+            triangle_mesh_arena: Arena::new(),
             perlin_noise: Perlin::new(rng),
         }
     }
@@ -67,6 +78,11 @@ impl<'a> Storage<'a> {
         self.sphere_arena.alloc(sphere)
     }
 
+    #[inline]
+    pub fn alloc_spheres_soa(&self, spheres: SpheresSoA<'a>) -> &mut SpheresSoA<'a> {
+        self.spheres_soa_arena.alloc(spheres)
+    }
+
     #[inline]
     pub fn alloc_moving_sphere(&self, sphere: MovingSphere) -> &mut MovingSphere {
         self.moving_sphere_arena.alloc(sphere)
@@ -82,6 +98,26 @@ impl<'a> Storage<'a> {
         self.cuboid_arena.alloc(cuboid)
     }
 
+    #[inline]
+    pub fn alloc_triangle(&self, triangle: Triangle) -> &mut Triangle {
+        self.triangle_arena.alloc(triangle)
+    }
+
+    #[inline]
+    pub fn alloc_triangle_mesh(&self, mesh: TriangleMesh<'a>) -> &mut TriangleMesh<'a> {
+        self.triangle_mesh_arena.alloc(mesh)
+    }
+
+    #[inline]
+    pub fn alloc_linear_bvh(&self, bvh: LinearBVH<'a>) -> &mut LinearBVH<'a> {
+        self.linear_bvh_arena.alloc(bvh)
+    }
+
+    #[inline]
+    pub fn alloc_wide_bvh(&self, bvh: WideBVH<'a>) -> &mut WideBVH<'a> {
+        self.wide_bvh_arena.alloc(bvh)
+    }
+
     #[inline]
     pub fn alloc_hitables(&self, hitables: Vec<Hitable<'a>>) -> &mut HitableList<'a> {
         self.hitables_arena.alloc(HitableList::new(hitables))
@@ -1,27 +1,34 @@
 #![allow(dead_code)]
 use crate::perlin::Perlin;
 use glam::{vec3, Vec3};
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug)]
 pub struct RgbImage {
+    path: PathBuf,
     width: u32,
     height: u32,
     data: Vec<u8>,
 }
 
 impl RgbImage {
-    // TODO: error handling
-    pub fn open(path: &str) -> RgbImage {
-        let image = image::open(path).unwrap();
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RgbImage, image::ImageError> {
+        let path = path.as_ref().to_path_buf();
+        let image = image::open(&path)?;
         let image = image.to_rgb8();
         let width = image.width();
         let height = image.height();
         let data = image.into_raw();
-        RgbImage {
+        Ok(RgbImage {
+            path,
             data,
             width,
             height,
-        }
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     pub fn value(&self, u: f32, v: f32) -> Vec3 {
@@ -0,0 +1,208 @@
+use crate::aov::AovPixel;
+use serde_derive::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// A rectangle of pixels rendered together, `y` counts up from the bottom row like the frame
+/// buffer.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Tile {
+    /// Iterates the indices of the tile's pixels in an image `image_width` wide, row by row.
+    pub fn pixel_indices(&self, image_width: u32) -> impl Iterator<Item = usize> {
+        let tile = *self;
+        (tile.y..tile.y + tile.height).flat_map(move |y| {
+            let row = (y * image_width) as usize;
+            (row + tile.x as usize)..(row + (tile.x + tile.width) as usize)
+        })
+    }
+
+    /// Copies the tile's pixels out of an image `image_width` wide, row by row.
+    pub fn extract<T: Copy>(&self, image: &[T], image_width: u32) -> Vec<T> {
+        self.pixel_indices(image_width).map(|i| image[i]).collect()
+    }
+
+    /// Copies `pixels`, stored row by row, into the tile's area of an image `image_width` wide.
+    pub fn insert<T: Copy>(&self, pixels: &[T], image: &mut [T], image_width: u32) {
+        for (n, i) in self.pixel_indices(image_width).enumerate() {
+            image[i] = pixels[n];
+        }
+    }
+}
+
+/// Parses a region of a `width` by `height` image given as `x0,y0,x1,y1`, measured from the
+/// top left corner with the far edges excluded. The coordinates are in pixels, or fractions of
+/// the image if any of them has a decimal point.
+pub fn parse_crop(s: &str, width: u32, height: u32) -> Result<Tile, String> {
+    let values: Vec<&str> = s.split(',').map(str::trim).collect();
+    if values.len() != 4 {
+        return Err(format!("expected x0,y0,x1,y1 but found '{}'", s));
+    }
+    let normalized = values.iter().any(|value| value.contains('.'));
+    let mut coords = [0; 4];
+    for (index, value) in values.iter().enumerate() {
+        let size = if index % 2 == 0 { width } else { height };
+        coords[index] = if normalized {
+            let fraction: f32 = value
+                .parse()
+                .map_err(|_| format!("invalid coordinate '{}'", value))?;
+            (fraction * size as f32).round() as u32
+        } else {
+            value
+                .parse()
+                .map_err(|_| format!("invalid coordinate '{}'", value))?
+        };
+    }
+    let [x0, y0, x1, y1] = coords;
+    if x0 >= x1 || y0 >= y1 || x1 > width || y1 > height {
+        return Err(format!(
+            "'{}' isn't a non-empty region of the {}x{} image",
+            s, width, height
+        ));
+    }
+    // rows count up from the bottom of the image
+    Ok(Tile {
+        x: x0,
+        y: height - y1,
+        width: x1 - x0,
+        height: y1 - y0,
+    })
+}
+
+/// The order tiles are started in.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TileOrder {
+    /// Rows of tiles from the top of the image.
+    Scanline,
+    /// Outwards from the centre of the image, so the subject is usually seen first.
+    Spiral,
+    /// Along a Hilbert curve, which keeps consecutive tiles next to each other.
+    Hilbert,
+}
+
+impl TileOrder {
+    pub const NAMES: [&'static str; 3] = ["spiral", "scanline", "hilbert"];
+}
+
+impl FromStr for TileOrder {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scanline" => Ok(TileOrder::Scanline),
+            "spiral" => Ok(TileOrder::Spiral),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            _ => Err(format!("unrecognised tile order '{}'", s)),
+        }
+    }
+}
+
+/// Splits `region` of an image into tiles of `tile_size` pixels square, clipped to the region,
+/// and returns them in `order`.
+pub fn tiles(region: Tile, tile_size: u32, order: TileOrder) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let columns = region.width.div_ceil(tile_size);
+    let rows = region.height.div_ceil(tile_size);
+    let tile = |column: u32, row: u32| {
+        let x = column * tile_size;
+        let y = row * tile_size;
+        Tile {
+            x: region.x + x,
+            y: region.y + y,
+            width: tile_size.min(region.width - x),
+            height: tile_size.min(region.height - y),
+        }
+    };
+
+    let num_tiles = (columns * rows) as usize;
+    let mut tiles = Vec::with_capacity(num_tiles);
+    match order {
+        TileOrder::Scanline => {
+            for row in (0..rows).rev() {
+                for column in 0..columns {
+                    tiles.push(tile(column, row));
+                }
+            }
+        }
+        TileOrder::Spiral => {
+            // walk a square spiral out from the centre, skipping positions outside the region
+            let (mut column, mut row) = ((columns as i32 - 1) / 2, (rows as i32 - 1) / 2);
+            let (mut dx, mut dy) = (1, 0);
+            let mut leg_length = 1;
+            while tiles.len() < num_tiles {
+                for _ in 0..2 {
+                    for _ in 0..leg_length {
+                        if column >= 0 && row >= 0 && column < columns as i32 && row < rows as i32 {
+                            tiles.push(tile(column as u32, row as u32));
+                        }
+                        column += dx;
+                        row += dy;
+                    }
+                    // turn a quarter
+                    let turned = (-dy, dx);
+                    dx = turned.0;
+                    dy = turned.1;
+                }
+                leg_length += 1;
+            }
+        }
+        TileOrder::Hilbert => {
+            // cover the grid with the smallest power of two curve and skip cells outside it
+            let side = columns.max(rows).next_power_of_two();
+            for d in 0..side * side {
+                let (column, row) = hilbert_d2xy(side, d);
+                if column < columns && row < rows {
+                    tiles.push(tile(column, row));
+                }
+            }
+        }
+    }
+    tiles
+}
+
+/// Returns the cell at distance `d` along a Hilbert curve filling a `side` by `side` grid,
+/// where `side` is a power of two.
+fn hilbert_d2xy(side: u32, d: u32) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < side {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+/// Progress through a frame, reported by `Scene::update` from whichever thread rendered the
+/// tile.
+#[derive(Debug)]
+pub enum TileEvent<'a> {
+    Started(Tile),
+    Finished {
+        tile: Tile,
+        /// The tile's pixels blended with the previous frames, row by row.
+        pixels: &'a [(f32, f32, f32)],
+        /// The tile's auxiliary outputs if they are being gathered.
+        aovs: Option<&'a [AovPixel]>,
+        /// The number of samples taken for each of the tile's pixels this frame.
+        sample_counts: &'a [u32],
+        /// How many tiles of the frame are finished, including this one.
+        completed: usize,
+        total: usize,
+    },
+}